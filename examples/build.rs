@@ -0,0 +1,11 @@
+// Shares a value with `comptime_shared!`, as a build script that probes the build would.
+fn main() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let profile = std::env::var("PROFILE").unwrap();
+    std::fs::write(
+        format!("{}/comptime_shared.json", out_dir),
+        format!(r#"{{"profile": "{}", "lanes": [4, 8]}}"#, profile),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,15 @@
+//! A proc-macro crate that uses `comptime!` in its own compilation, which cargo builds with a
+//! different set of args (e.g., `--extern proc_macro` and `-C prefer-dynamic`) than other crates.
+
+use proc_macro::TokenStream;
+
+const SQUARES: [u32; 4] = comptime::comptime! {
+    let squares = (1..=4u32).map(|n| n * n);
+    quote::quote!([#(#squares),*])
+};
+
+/// `squares!()` expands to the sum of the first four squares.
+#[proc_macro]
+pub fn squares(_input: TokenStream) -> TokenStream {
+    SQUARES.iter().sum::<u32>().to_string().parse().unwrap()
+}
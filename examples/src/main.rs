@@ -10,6 +10,28 @@ fn main() {
     // as there is still a function call being made, even if though it
     // just immediately returns an `&'static str`.
     println!("This program was compiled on {}", test());
+    // `quote` is available to comptime programs even though this crate doesn't depend on it.
+    println!("{}", answer());
+    // `comptime!` also works in the compilation of a proc-macro crate.
+    println!("{}", comptime_tests_macro::squares!());
+    // This crate doesn't depend on `phf`, so the map is a `match`.
+    println!("{:?}", weekday("Wed"));
+    // `build.rs` shares these with `comptime_shared!`.
+    const LANES: [usize; 2] = comptime::comptime_shared!("lanes");
+    println!("{} build, {:?} lanes", comptime::comptime_shared!("profile"), LANES);
+}
+
+fn weekday(abbreviation: &str) -> Option<&'static u8> {
+    comptime::comptime_phf!(abbreviation => u8; {
+        ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"].iter().zip(1u8..)
+            .map(|(day, n)| (*day, n))
+            .collect::<Vec<_>>()
+    })
+}
+
+comptime::comptime_items! {
+    let answer = 6 * 7;
+    quote::quote!(fn answer() -> i32 { #answer })
 }
 
 #[comptime::comptime_fn]
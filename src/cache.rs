@@ -0,0 +1,301 @@
+//! Caching the output of comptime programs (`COMPTIME_CACHE=1`), so that rebuilding a crate, as
+//! `cargo watch` does on every save, doesn't compile and run unchanged programs again.
+//!
+//! An entry is keyed by everything that determines the program's output except the files that
+//! the program tracks, which are instead checked to be older than the entry.
+//!
+//! Compiled drivers are also kept, whether or not outputs are cached, so that an unchanged program
+//! is run again without being recompiled. A driver's stamp records the key that it was compiled
+//! with. Drivers that a unit's build no longer uses are removed by its next build.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashSet},
+    hash::{Hash, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Returns the path of the cache entry for `key`.
+pub(crate) fn entry(out_dir: &Path, crate_name: &str, key: u64) -> PathBuf {
+    out_dir
+        .join("comptime-cache")
+        .join(crate_name)
+        .join(format!("{:016x}", key))
+}
+
+/// Returns the driver output stored in `entry`, unless there's none or it's stale.
+pub(crate) fn lookup(entry: &Path, nonce: &str) -> Option<String> {
+    let stored = std::fs::metadata(entry).and_then(|m| m.modified()).ok()?;
+    let output = std::fs::read_to_string(entry).ok()?;
+    let fresh = crate::parse_driver_output(&output, nonce)
+        .ok()?
+        .tracked
+        .iter()
+        .all(|path| {
+            std::fs::metadata(path)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified < stored)
+        });
+    fresh.then_some(output)
+}
+
+/// Stores the driver's `output` in `entry`. The entry is written atomically so that concurrent
+/// builds never read a partial one.
+pub(crate) fn store(entry: &Path, output: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(entry.parent().unwrap())?;
+    let partial = entry.with_extension(format!("{}.partial", std::process::id()));
+    std::fs::write(&partial, output)?;
+    std::fs::rename(&partial, entry)
+}
+
+/// Returns the key of a driver compiled from `driver` with `rustc_args`. It covers the
+/// modification times of the `--extern`s, so that a driver is relinked when they're rebuilt.
+pub(crate) fn driver_key(driver: &str, rustc_args: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (driver, rustc_args).hash(&mut hasher);
+    for pair in rustc_args.windows(2).filter(|pair| pair[0] == "--extern") {
+        let modified = pair[1]
+            .split_once('=')
+            .and_then(|(_, path)| std::fs::metadata(path).and_then(|m| m.modified()).ok());
+        modified.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Returns the key of the entry for the output of the driver with `driver_key` when it's run with
+/// `stdin` and `run_arg`. Like the driver, the entry is stale once an `--extern` is rebuilt, since
+/// a path dependency keeps its path when its source changes.
+pub(crate) fn output_key(driver_key: u64, stdin: Option<&[u8]>, run_arg: Option<&str>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (driver_key, stdin, run_arg).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The answers to rustc's `--print` queries for a crate unit, kept between builds so that a
+/// program whose output is cached is evaluated without running rustc at all. Cargo's
+/// `-C extra-filename` for a unit covers the version of rustc, so they're keyed by it.
+pub(crate) struct RustcQueries<'a> {
+    pub(crate) out_dir: &'a Path,
+    pub(crate) unit: &'a str,
+}
+
+impl RustcQueries<'_> {
+    /// Returns the stored answer to the query of rustc with `args`, or else the one that `query`
+    /// returns, which is stored if it's successful.
+    pub(crate) fn get(
+        &self,
+        args: &[String],
+        query: impl FnOnce() -> Result<String, String>,
+    ) -> Result<String, String> {
+        let mut hasher = DefaultHasher::new();
+        (self.unit, args).hash(&mut hasher);
+        let entry = self
+            .out_dir
+            .join("comptime-cache")
+            .join("rustc")
+            .join(format!("{:016x}", hasher.finish()));
+        if let Ok(answer) = std::fs::read_to_string(&entry) {
+            return Ok(answer);
+        }
+        let answer = query()?;
+        store(&entry, &answer).ok();
+        Ok(answer)
+    }
+}
+
+fn stamp(bin: &Path) -> PathBuf {
+    bin.with_extension("stamp")
+}
+
+/// Returns whether `bin` exists and was compiled with `key`.
+pub(crate) fn is_compiled(bin: &Path, key: u64) -> bool {
+    let stamp = std::fs::read_to_string(stamp(bin));
+    bin.is_file() && stamp.is_ok_and(|stamp| stamp == format!("{:016x}", key))
+}
+
+/// Records that `bin` was compiled with `key`.
+pub(crate) fn record_compiled(bin: &Path, key: u64) -> std::io::Result<()> {
+    std::fs::write(stamp(bin), format!("{:016x}", key))
+}
+
+/// Records that the current build of the unit of the driver `bin`, which is named
+/// `<unit>-<disambiguator>`, uses it. The first time that a rustc process records a driver of a
+/// unit, it removes the unit's drivers, and their stamps, that the unit's previous build didn't
+/// use, e.g. those of programs that were edited since. All of a unit's invocations are expanded by
+/// one rustc process, so each driver that the unit still uses was recorded by its previous build.
+pub(crate) fn record_used(bin: &Path) -> std::io::Result<()> {
+    static COLLECTED: std::sync::Mutex<BTreeSet<PathBuf>> = std::sync::Mutex::new(BTreeSet::new());
+    let dir = bin.parent().unwrap();
+    let stem = bin
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let Some((unit, _)) = stem.rsplit_once('-') else {
+        return Ok(());
+    };
+    let used = dir.join(format!("{}.used", unit));
+    if COLLECTED.lock().unwrap().insert(used.clone()) {
+        if let Ok(previous) = std::fs::read_to_string(&used) {
+            let previous: HashSet<&str> = previous.lines().collect();
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                let (driver, extension) = name.split_once('.').unwrap_or((name, ""));
+                let is_drivers = driver
+                    .strip_prefix(unit)
+                    .and_then(|rest| rest.strip_prefix('-'))
+                    .is_some_and(|d| !d.is_empty() && d.bytes().all(|b| b.is_ascii_digit()));
+                // The source is removed after the driver is built, unless it's kept on purpose.
+                if is_drivers && !previous.contains(driver) && !matches!(extension, "rs" | "d") {
+                    std::fs::remove_file(&path).ok();
+                }
+            }
+        }
+        std::fs::write(&used, "")?;
+    }
+    let mut used = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&used)?;
+    writeln!(used, "{}", stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_cache() {
+        let out_dir = TempDir::new("cache");
+        let tracked = out_dir.join("tracked.rs");
+        std::fs::write(&tracked, "").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let entry = entry(&out_dir, "app", 42);
+        assert_eq!(lookup(&entry, "00ff"), None);
+        let output = format!("00ff\n7\nkind=value\ttrack={}", tracked.display());
+        store(&entry, &output).unwrap();
+        assert_eq!(lookup(&entry, "00ff").as_ref(), Some(&output));
+        assert_eq!(lookup(&entry, "0abc"), None);
+
+        // Changing a tracked file invalidates the entry.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        std::fs::write(&tracked, "fn changed() {}").unwrap();
+        assert_eq!(lookup(&entry, "00ff"), None);
+    }
+
+    #[test]
+    fn test_rustc_queries() {
+        let out_dir = TempDir::new("rustc-queries");
+        let queries = RustcQueries {
+            out_dir: &out_dir,
+            unit: "-0123",
+        };
+        let args = ["--print".to_string(), "cfg".to_string()];
+        assert_eq!(
+            queries.get(&args, || Err("no rustc".into())),
+            Err("no rustc".into())
+        );
+        assert_eq!(queries.get(&args, || Ok("unix".into())), Ok("unix".into()));
+        // The answer is stored, and rustc isn't asked again.
+        assert_eq!(queries.get(&args, || unreachable!()), Ok("unix".into()));
+        let other = RustcQueries {
+            out_dir: &out_dir,
+            unit: "-4567",
+        };
+        assert_eq!(
+            other.get(&args, || Ok("windows".into())),
+            Ok("windows".into())
+        );
+    }
+
+    #[test]
+    fn test_driver_key() {
+        let dir = TempDir::new("driver");
+        let rlib = dir.join("libfoo-1.rlib");
+        std::fs::write(&rlib, "").unwrap();
+        let rustc_args = vec!["--extern".to_string(), format!("foo={}", rlib.display())];
+        let key = driver_key("fn main() {}", &rustc_args);
+        assert_eq!(driver_key("fn main() {}", &rustc_args), key);
+        assert_ne!(driver_key("fn main() { }", &rustc_args), key);
+
+        let bin = dir.join("comptime-app-1");
+        assert!(!is_compiled(&bin, key));
+        std::fs::write(&bin, "").unwrap();
+        record_compiled(&bin, key).unwrap();
+        assert!(is_compiled(&bin, key));
+
+        // Rebuilding an extern invalidates the driver.
+        let rebuilt = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        let rlib_file = std::fs::File::options().write(true).open(&rlib).unwrap();
+        rlib_file.set_modified(rebuilt).unwrap();
+        assert_ne!(driver_key("fn main() {}", &rustc_args), key);
+    }
+
+    #[test]
+    fn test_record_used() {
+        let dir = TempDir::new("used");
+        for name in [
+            "comptime-app-1-10",
+            "comptime-app-1-10.stamp",
+            "comptime-app-1-20",
+            "comptime-app-1-20.stamp",
+            "comptime-app-1-30",
+            "comptime-app-1-30.stamp",
+            "comptime-app-1-30.rs",
+            "comptime-app-2-30",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        // The previous build used only the second driver of the unit `comptime-app-1`.
+        let used = dir.join("comptime-app-1.used");
+        std::fs::write(&used, "comptime-app-1-20\n").unwrap();
+        record_used(&dir.join("comptime-app-1-10")).unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&*dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "comptime-app-1-20",
+                "comptime-app-1-20.stamp",
+                "comptime-app-1-30.rs",
+                "comptime-app-1.used",
+                "comptime-app-2-30",
+            ]
+        );
+        // The rest of the build only records its drivers.
+        std::fs::write(dir.join("comptime-app-1-10"), "").unwrap();
+        record_used(&dir.join("comptime-app-1-20")).unwrap();
+        assert!(dir.join("comptime-app-1-10").exists());
+        assert_eq!(
+            std::fs::read_to_string(&used).unwrap(),
+            "comptime-app-1-10\ncomptime-app-1-20\n"
+        );
+    }
+
+    #[test]
+    fn test_output_key() {
+        let dir = TempDir::new("output-key");
+        let rlib = dir.join("libfoo-1.rlib");
+        std::fs::write(&rlib, "").unwrap();
+        let rustc_args = vec!["--extern".to_string(), format!("foo={}", rlib.display())];
+        let key = || output_key(driver_key("fn main() {}", &rustc_args), None, Some("1"));
+        let unchanged = key();
+        assert_eq!(key(), unchanged);
+        let driver = driver_key("fn main() {}", &rustc_args);
+        assert_ne!(output_key(driver, Some(b"input"), Some("1")), unchanged);
+        assert_ne!(output_key(driver, None, Some("2")), unchanged);
+
+        // Editing a path dependency rebuilds its rlib in place.
+        let rebuilt = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        let rlib_file = std::fs::File::options().write(true).open(&rlib).unwrap();
+        rlib_file.set_modified(rebuilt).unwrap();
+        assert_ne!(key(), unchanged);
+    }
+}
@@ -0,0 +1,494 @@
+//! Building the parent crate's dependencies on demand (`COMPTIME_BUILD_DEPS=1`), for when rustc is
+//! only checking the parent crate and so there are no rlibs to link the comptime program against.
+//!
+//! The dependencies are built by a synthesized helper project whose manifest contains the
+//! parent's dependencies. The helper is cached by the contents of its manifest, so every comptime
+//! invocation that needs the same dependencies shares one build.
+//!
+//! The parent's manifest is also consulted for its dependencies' version requirements, which
+//! decide between multiple versions of a crate in the deps dir.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::TryLockError,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use toml_edit::{value, DocumentMut, Item, Table};
+
+/// How long to wait for another invocation's build of the same dependencies.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Builds the dependencies of the crate at `manifest_dir` in a helper project under `cache_root`
+/// and returns the deps dir containing the built rlibs. If the build fails, the helper's manifest
+/// is removed, unless `keep`, so that it can be inspected.
+pub(crate) fn build_deps(
+    manifest_dir: &Path,
+    cache_root: &Path,
+    keep: bool,
+) -> Result<PathBuf, String> {
+    let parent_manifest_path = manifest_dir.join("Cargo.toml");
+    let parent_manifest = std::fs::read_to_string(&parent_manifest_path)
+        .map_err(|e| format!("could not read `{}`: {}", parent_manifest_path.display(), e))?;
+    let manifest = synthesize_manifest(&parent_manifest, manifest_dir).map_err(|e| {
+        format!(
+            "could not parse `{}`: {}",
+            parent_manifest_path.display(),
+            e
+        )
+    })?;
+
+    let mut hasher = DefaultHasher::new();
+    manifest.hash(&mut hasher);
+    let helper_dir = cache_root.join(format!("{:016x}", hasher.finish()));
+    let target_dir = helper_dir.join("target");
+
+    build_once(&helper_dir, || {
+        std::fs::write(helper_dir.join("Cargo.toml"), &manifest)
+            .and_then(|_| std::fs::write(helper_dir.join("lib.rs"), ""))
+            .map_err(|e| format!("could not write the dependency helper project: {}", e))?;
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let output = Command::new(cargo)
+            .arg("build")
+            .arg("--manifest-path")
+            .arg(helper_dir.join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .env_remove("COMPTIME_BUILD_DEPS")
+            .output()
+            .map_err(|e| format!("could not invoke cargo: {}", e))?;
+        if !output.status.success() {
+            let manifest_path = helper_dir.join("Cargo.toml");
+            if !keep {
+                std::fs::remove_file(&manifest_path).ok();
+            }
+            return Err(build_failure_message(
+                &String::from_utf8_lossy(&output.stderr),
+                &manifest_path,
+                keep,
+            ));
+        }
+        Ok(())
+    })?;
+
+    Ok(target_dir.join("debug").join("deps"))
+}
+
+/// Describes a failed build of the helper project, whose manifest is at `manifest_path`, naming
+/// the dependencies that cargo reported as failing in its `stderr`.
+fn build_failure_message(stderr: &str, manifest_path: &Path, kept: bool) -> String {
+    let mut failed = Vec::new();
+    for pattern in ["could not compile `", "no matching package named `"] {
+        for (i, _) in stderr.match_indices(pattern) {
+            let name = stderr[i + pattern.len()..].split('`').next().unwrap();
+            if !failed.contains(&name) {
+                failed.push(name);
+            }
+        }
+    }
+    let failed = match failed.as_slice() {
+        [] => "the dependencies".to_string(),
+        [name] => format!("dependency `{}`", name),
+        names => format!("dependencies `{}`", names.join("`, `")),
+    };
+    let manifest = match kept {
+        true => format!(
+            "The helper project's manifest is `{}`.",
+            manifest_path.display()
+        ),
+        false => "Run with COMPTIME_KEEP=1 to keep the helper project's manifest.".to_string(),
+    };
+    format!(
+        "could not build {} for COMPTIME_BUILD_DEPS. {}\n\n{}",
+        failed,
+        manifest,
+        stderr.trim_end()
+    )
+}
+
+/// Runs `build` unless it has already succeeded for `dir`. Concurrent callers for the same `dir`
+/// wait for the first one's build instead of building again. The build holds an OS lock on
+/// `dir/lock`, which is released even if the process is killed, so a dead build is redone rather
+/// than waited for.
+pub(crate) fn build_once(
+    dir: &Path,
+    build: impl FnOnce() -> Result<(), String>,
+) -> Result<(), String> {
+    let stamp = dir.join("built");
+    let lock_path = dir.join("lock");
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("could not create `{}`: {}", dir.display(), e))?;
+    let lock = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(|e| format!("could not create `{}`: {}", lock_path.display(), e))?;
+
+    let start = Instant::now();
+    loop {
+        if stamp.exists() {
+            return Ok(());
+        }
+        match lock.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {
+                if start.elapsed() > LOCK_TIMEOUT {
+                    return Err(format!(
+                        "timed out waiting for another build of the dependencies in `{}`",
+                        dir.display()
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("could not lock `{}`: {}", lock_path.display(), e))
+            }
+        }
+    }
+    // The build that held the lock may have just finished.
+    if stamp.exists() {
+        return Ok(());
+    }
+
+    build().and_then(|_| {
+        std::fs::write(&stamp, "")
+            .map_err(|e| format!("could not write `{}`: {}", stamp.display(), e))
+    })
+}
+
+/// Returns the manifest of a helper library that depends on everything the parent manifest
+/// depends on (including dev-dependencies, so that tests work), plus what the comptime program
+/// itself needs. Relative `path` dependencies are made absolute. The helper is its own workspace,
+/// since it's in the parent's target dir, and so often inside the parent's workspace.
+fn synthesize_manifest(parent_manifest: &str, manifest_dir: &Path) -> Result<String, String> {
+    let parent: DocumentMut = parent_manifest.parse().map_err(|e| format!("{}", e))?;
+    let mut dependencies = Table::new();
+    for table in ["dependencies", "dev-dependencies"] {
+        let Some(deps) = parent.get(table).and_then(Item::as_table_like) else {
+            continue;
+        };
+        for (name, dep) in deps.iter() {
+            if dependencies.contains_key(name) {
+                continue;
+            }
+            let mut dep = dep.clone();
+            let path = dep.get("path").and_then(Item::as_str);
+            if let Some(path) = path.map(|path| manifest_dir.join(path)) {
+                dep["path"] = value(path.display().to_string());
+            }
+            dependencies.insert(name, dep);
+        }
+    }
+    for (name, version) in [("quote", "1.0"), ("proc-macro2", "1.0")] {
+        if !dependencies.contains_key(name) {
+            dependencies.insert(name, value(version));
+        }
+    }
+
+    let mut manifest = DocumentMut::new();
+    manifest["package"]["name"] = value("comptime-deps");
+    manifest["package"]["version"] = value("0.0.0");
+    manifest["package"]["edition"] = value("2021");
+    manifest["lib"]["path"] = value("lib.rs");
+    manifest["workspace"] = Item::Table(Table::new());
+    manifest["dependencies"] = Item::Table(dependencies);
+    Ok(manifest.to_string())
+}
+
+/// Returns the version requirements of the dependencies in `manifest`, keyed by the name of each
+/// dependency's library (i.e., the package name with dashes replaced by underscores).
+/// Dependencies without a version requirement, like most `path` dependencies, are omitted.
+pub(crate) fn dependency_requirements(manifest: &str) -> HashMap<String, String> {
+    // (name, package, version) of the dependency being parsed
+    let mut deps: Vec<(String, Option<String>, Option<String>)> = Vec::new();
+    let mut in_dependencies = false;
+    let mut in_table = false;
+    for line in manifest.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_matches(|c| c == '[' || c == ']');
+            (in_dependencies, in_table) = match header.split_once('.') {
+                None => (is_dependencies_table(header), false),
+                Some((table, name)) if is_dependencies_table(table) => {
+                    deps.push((name.to_string(), None, None));
+                    (false, true)
+                }
+                _ => (false, false),
+            };
+            continue;
+        }
+        let Some((key, value)) = trimmed.split_once('=') else {
+            continue;
+        };
+        let key = key.trim();
+        if in_table {
+            let dep = deps.last_mut().unwrap();
+            match key {
+                "version" => dep.2 = quoted(value),
+                "package" => dep.1 = quoted(value),
+                _ => {}
+            }
+        } else if in_dependencies {
+            let name = key.split('.').next().unwrap().trim().to_string();
+            let value = value.trim();
+            let dep = match value.strip_prefix('{') {
+                Some(table) => (
+                    name,
+                    inline_value(table, "package"),
+                    inline_value(table, "version"),
+                ),
+                None => (name, None, quoted(value)),
+            };
+            deps.push(dep);
+        }
+    }
+
+    deps.into_iter()
+        .filter_map(|(name, package, version)| {
+            Some((package.unwrap_or(name).replace('-', "_"), version?))
+        })
+        .collect()
+}
+
+/// Returns the contents of a TOML basic string like `"1.0"`.
+fn quoted(value: &str) -> Option<String> {
+    let value = value.trim().strip_prefix('"')?;
+    Some(value[..value.find('"')?].to_string())
+}
+
+/// Returns the string value of `key` in the inline table whose contents start `table`.
+fn inline_value(table: &str, key: &str) -> Option<String> {
+    table.split(',').find_map(|entry| {
+        let (k, v) = entry.split_once('=')?;
+        if k.trim() == key {
+            quoted(v)
+        } else {
+            None
+        }
+    })
+}
+
+fn is_dependencies_table(table: &str) -> bool {
+    matches!(table, "dependencies" | "dev-dependencies")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_synthesize_manifest() {
+        let parent = r#"
+[package]
+name = "parent"
+
+[dependencies]
+rand = "0.7" # the version that the comptime program needs
+local = { path = "../local" }
+"quote" = "1.0"
+
+[dependencies.serde]
+version = "1"
+features = ["derive"]
+
+[dev-dependencies]
+rand = "0.8"
+chrono = "0.4"
+"#;
+        let manifest = synthesize_manifest(parent, Path::new("/ws/parent")).unwrap();
+        let expected = r#"
+[package]
+name = "comptime-deps"
+version = "0.0.0"
+edition = "2021"
+
+[lib]
+path = "lib.rs"
+
+[workspace]
+
+[dependencies]
+rand = "0.7"
+local = { path = "/ws/parent/../local" }
+quote = "1.0"
+serde = { version = "1", features = ["derive"] }
+chrono = "0.4"
+proc-macro2 = "1.0"
+"#;
+        assert_eq!(
+            manifest.parse::<toml::Table>().unwrap(),
+            expected.parse::<toml::Table>().unwrap()
+        );
+        assert!(synthesize_manifest("[dependencies", Path::new("/ws/parent")).is_err());
+    }
+
+    #[test]
+    fn test_dependency_requirements() {
+        let manifest = r#"
+[dependencies]
+rand = "0.7"
+serde-json = { version = "1.0.1", features = ["std"] }
+local = { path = "../local" }
+old_syn = { package = "syn", version = "1" }
+
+[dependencies.rand-core]
+version = "0.5"
+
+[build-dependencies]
+cc = "1"
+"#;
+        let requirements = dependency_requirements(manifest);
+        assert_eq!(requirements.len(), 4);
+        assert_eq!(requirements["rand"], "0.7");
+        assert_eq!(requirements["serde_json"], "1.0.1");
+        assert_eq!(requirements["syn"], "1");
+        assert_eq!(requirements["rand_core"], "0.5");
+    }
+
+    /// Writes a package `name` in `dir/name` whose library is `lib_rs`.
+    fn write_package(dir: &Path, name: &str, lib_rs: &str) {
+        let package = dir.join(name);
+        std::fs::create_dir_all(package.join("src")).unwrap();
+        let manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"1.0.0\"\nedition = \"2021\"\n",
+            name
+        );
+        std::fs::write(package.join("Cargo.toml"), manifest).unwrap();
+        std::fs::write(package.join("src/lib.rs"), lib_rs).unwrap();
+    }
+
+    /// The dependencies of a parent whose helper builds offline: the ones in `dependencies`, and
+    /// local stand-ins for the `quote` and `proc-macro2` that the helper would otherwise fetch.
+    fn offline_dependencies(dir: &Path, dependencies: &str) -> String {
+        for name in ["quote", "proc-macro2"] {
+            write_package(dir, name, "");
+        }
+        format!(
+            "[dependencies]\n{}\nquote = {{ path = \"{}\" }}\nproc-macro2 = {{ path = \"{}\" }}\n",
+            dependencies,
+            dir.join("quote").display(),
+            dir.join("proc-macro2").display(),
+        )
+    }
+
+    #[test]
+    fn test_build_deps_failure() {
+        let dir = TempDir::new("broken-dep");
+        write_package(&dir, "broken", "pub fn f() -> u32 { \"\" }");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        let dependencies = offline_dependencies(&dir, "broken = { path = \"broken\" }");
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            format!("[package]\nname = \"parent\"\n\n{}", dependencies),
+        )
+        .unwrap();
+
+        let cache_root = dir.join("comptime-deps");
+        let kept = build_deps(&dir, &cache_root, true).unwrap_err();
+        let removed = build_deps(&dir, &cache_root, false).unwrap_err();
+        let manifests: Vec<_> = std::fs::read_dir(&cache_root)
+            .unwrap()
+            .map(|helper| helper.unwrap().path().join("Cargo.toml").exists())
+            .collect();
+        assert!(kept.starts_with(
+            "could not build dependency `broken` for COMPTIME_BUILD_DEPS. The helper project's \
+             manifest is `"
+        ));
+        assert!(kept.contains("mismatched types"));
+        assert!(removed.starts_with(
+            "could not build dependency `broken` for COMPTIME_BUILD_DEPS. Run with \
+             COMPTIME_KEEP=1 to keep the helper project's manifest."
+        ));
+        assert_eq!(manifests, [false]);
+    }
+
+    #[test]
+    fn test_build_deps_in_workspace() {
+        let dir = TempDir::new("workspace");
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n",
+        )
+        .unwrap();
+        write_package(&dir, "broken", "pub fn f() -> u32 { \"\" }");
+        let member = dir.join("member");
+        std::fs::create_dir_all(member.join("src")).unwrap();
+        std::fs::write(member.join("src/lib.rs"), "").unwrap();
+        let dependencies = offline_dependencies(&dir, "broken = { path = \"../broken\" }");
+        std::fs::write(
+            member.join("Cargo.toml"),
+            format!("[package]\nname = \"member\"\n\n{}", dependencies),
+        )
+        .unwrap();
+
+        // The helper is in the workspace's target dir, but cargo gets as far as building `broken`.
+        let cache_root = dir.join("target/debug/comptime-deps");
+        let e = build_deps(&member, &cache_root, false).unwrap_err();
+        assert!(e.starts_with("could not build dependency `broken` for COMPTIME_BUILD_DEPS."));
+    }
+
+    #[test]
+    fn test_build_failure_message() {
+        let stderr = "error: no matching package named `nope` found\n";
+        assert_eq!(
+            build_failure_message(stderr, Path::new("/h/Cargo.toml"), true),
+            "could not build dependency `nope` for COMPTIME_BUILD_DEPS. The helper project's \
+             manifest is `/h/Cargo.toml`.\n\nerror: no matching package named `nope` found"
+        );
+        let stderr = "error: could not compile `a` (lib)\nerror: could not compile `b` (lib)";
+        assert!(
+            build_failure_message(stderr, Path::new("/h/Cargo.toml"), false)
+                .starts_with("could not build dependencies `a`, `b` for COMPTIME_BUILD_DEPS.")
+        );
+    }
+
+    #[test]
+    fn test_build_once() {
+        let dir = TempDir::new("build-once");
+        let builds = Arc::new(AtomicUsize::new(0));
+        let invocations: Vec<_> = (0..2)
+            .map(|_| {
+                let dir = dir.to_path_buf();
+                let builds = Arc::clone(&builds);
+                std::thread::spawn(move || {
+                    build_once(&dir, || {
+                        builds.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(300));
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+        for invocation in invocations {
+            invocation.join().unwrap().unwrap();
+        }
+        assert_eq!(builds.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_build_once_after_killed_build() {
+        // A killed build leaves its lock file, but not the OS lock on it.
+        let dir = TempDir::new("killed-build");
+        std::fs::write(dir.join("lock"), "").unwrap();
+        let start = Instant::now();
+        let mut built = false;
+        build_once(&dir, || {
+            built = true;
+            Ok(())
+        })
+        .unwrap();
+        assert!(built);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
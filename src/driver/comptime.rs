@@ -0,0 +1,262 @@
+// The items of the `comptime` module available to the comptime program, which is appended to its
+// source along with any optional items that the program uses.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+static TRACKED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// An empty result, which `comptime_items!` and `comptime!` splice as nothing.
+pub fn nothing() -> proc_macro2::TokenStream {
+    proc_macro2::TokenStream::new()
+}
+
+/// The root of the crate that invoked `comptime!`, which relative paths are resolved against.
+pub fn manifest_dir() -> PathBuf {
+    PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is set"))
+}
+
+/// A seed derived from the comptime program's source, for random generation that's reproducible.
+pub fn seed() -> u64 {
+    std::env::var("COMPTIME_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .expect("COMPTIME_SEED is set")
+}
+
+/// Runs `main` on a thread with a stack of `COMPTIME_STACK_MB` megabytes, and exits like a
+/// panicking `main` if it panics.
+pub(crate) fn with_stack(main: impl FnOnce() + Send + 'static) {
+    let mb: usize = std::env::var("COMPTIME_STACK_MB")
+        .ok()
+        .and_then(|mb| mb.parse().ok())
+        .expect("COMPTIME_STACK_MB is set");
+    let thread = std::thread::Builder::new()
+        .name("comptime".to_string())
+        .stack_size(mb << 20)
+        .spawn(main)
+        .expect("could not spawn the comptime thread");
+    if thread.join().is_err() {
+        std::process::exit(101);
+    }
+}
+
+/// Fails a program run with `@try` whose `?` returned `error`.
+pub(crate) fn exit_with_error(error: Box<dyn std::error::Error>) -> ! {
+    eprintln!("error: {}", error);
+    std::process::exit(1)
+}
+
+/// The argument that `comptime_map!` runs the program with, parsed as a `T`.
+pub fn arg<T: std::str::FromStr>() -> T {
+    let arg = std::env::args()
+        .nth(1)
+        .expect("the program is run by `comptime_map!`");
+    match arg.parse() {
+        Ok(arg) => arg,
+        Err(_) => panic!(
+            "could not parse `comptime_map!` argument `{}` as a `{}`",
+            arg,
+            std::any::type_name::<T>()
+        ),
+    }
+}
+
+/// Registers the file at `path` so that the invoking crate is rebuilt when it changes.
+pub fn track(path: impl AsRef<Path>) {
+    TRACKED.lock().unwrap().push(manifest_dir().join(path));
+}
+
+pub(crate) fn tracked() -> Vec<PathBuf> {
+    TRACKED.lock().unwrap().clone()
+}
+
+/// Converts a value computed by the comptime program into the tokens of the equivalent value in
+/// the invoking crate. Anything `ToTokens` is `Emit`, and `comptime::emit_struct!` implements it
+/// for structs.
+pub trait Emit {
+    fn emit(&self) -> proc_macro2::TokenStream;
+}
+
+impl<T: quote::ToTokens> Emit for T {
+    fn emit(&self) -> proc_macro2::TokenStream {
+        self.to_token_stream()
+    }
+}
+
+/// `emit_struct!(Point { x, y })` implements `Emit` for `Point` as a struct expression of its
+/// emitted fields.
+macro_rules! emit_struct {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        impl comptime::Emit for $ty {
+            fn emit(&self) -> proc_macro2::TokenStream {
+                $(let $field = comptime::Emit::emit(&self.$field);)*
+                quote::quote!($ty { $($field: #$field),* })
+            }
+        }
+    };
+}
+pub(crate) use emit_struct;
+
+/// Emits `include_str!` of the file at `path`, relative to the crate's root, rather than its
+/// contents, so that large files aren't copied into tokens, and cargo rebuilds the crate when the
+/// file changes.
+pub struct IncludeStr<P: AsRef<Path>>(pub P);
+
+/// Emits `include_bytes!` of the file at `path`, like `IncludeStr`.
+pub struct IncludeBytes<P: AsRef<Path>>(pub P);
+
+// A relative path is resolved by `CARGO_MANIFEST_DIR` in the invoking crate, so that the emitted
+// tokens aren't specific to this machine.
+fn include_path(path: &Path) -> proc_macro2::TokenStream {
+    let path_str = path.to_string_lossy();
+    match path.is_absolute() {
+        true => quote::quote!(#path_str),
+        false => quote::quote!(concat!(env!("CARGO_MANIFEST_DIR"), "/", #path_str)),
+    }
+}
+
+impl<P: AsRef<Path>> quote::ToTokens for IncludeStr<P> {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let path = include_path(self.0.as_ref());
+        tokens.extend(quote::quote!(include_str!(#path)));
+    }
+}
+
+impl<P: AsRef<Path>> quote::ToTokens for IncludeBytes<P> {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let path = include_path(self.0.as_ref());
+        tokens.extend(quote::quote!(include_bytes!(#path)));
+    }
+}
+
+/// Emits `rows` as an array literal, which is what `comptime_table!` programs return.
+pub fn table<T: Emit>(rows: impl IntoIterator<Item = T>) -> proc_macro2::TokenStream {
+    let rows = rows.into_iter().map(|row| row.emit());
+    quote::quote!([#(#rows),*])
+}
+
+/// Emits `values` as a tuple, which is what `comptime_bundle!` programs return.
+pub fn bundle(values: &[&dyn Emit]) -> proc_macro2::TokenStream {
+    let values = values.iter().map(|value| value.emit());
+    quote::quote!((#(#values,)*))
+}
+
+/// Emits `arms` as an array of `(start, end, value)` tuples, which is what `comptime_range_match!`
+/// programs return. Empty ranges are dropped, since they can't be matched anyway.
+pub fn range_match<T: Emit>(
+    arms: impl IntoIterator<Item = (std::ops::RangeInclusive<i64>, T)>,
+) -> proc_macro2::TokenStream {
+    let arms = arms
+        .into_iter()
+        .filter(|(range, _)| !range.is_empty())
+        .map(|(range, value)| {
+            let start = proc_macro2::Literal::i64_unsuffixed(*range.start());
+            let end = proc_macro2::Literal::i64_unsuffixed(*range.end());
+            let value = value.emit();
+            quote::quote!((#start, #end, #value))
+        });
+    quote::quote!([#(#arms),*])
+}
+
+/// Runs `program` with `args` in the crate's root, which is what `comptime_cmd!` programs return:
+/// `(true, stdout)` with the trimmed stdout or, if it fails, `(false, error)`.
+pub fn cmd(program: &str, args: &[&str]) -> proc_macro2::TokenStream {
+    let display = std::iter::once(program)
+        .chain(args.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let program = Path::new(program);
+    let program = match program.components().count() > 1 {
+        true => manifest_dir().join(program), // a relative path, e.g. `scripts/version.sh`
+        false => program.to_path_buf(),
+    };
+    let (ok, text) = match std::process::Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => (
+            true,
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ),
+        Ok(output) => (
+            false,
+            format!(
+                "`{}` failed with {}:\n{}",
+                display,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ),
+        ),
+        Err(e) => (false, format!("could not run `{}`: {}", display, e)),
+    };
+    quote::quote!((#ok, #text))
+}
+
+/// Parses the config file at `path`, relative to the crate's root, with `parse` and emits the
+/// result, which is what `comptime_toml!` and `comptime_yaml!` programs return. The file is
+/// tracked, and an error reading or parsing it is emitted as a `compile_error!` that names it.
+pub fn config<T: Emit, E: std::fmt::Display>(
+    path: &str,
+    parse: impl FnOnce(&str) -> Result<T, E>,
+) -> proc_macro2::TokenStream {
+    let text = match std::fs::read_to_string(manifest_dir().join(path)) {
+        Ok(text) => text,
+        Err(e) => {
+            let message = format!("could not read config file `{}`: {}", path, e);
+            return quote::quote!(compile_error!(#message));
+        }
+    };
+    track(path);
+    match parse(&text) {
+        Ok(value) => value.emit(),
+        Err(e) => {
+            let message = format!("could not parse config file `{}`: {}", path, e);
+            quote::quote!(compile_error!(#message))
+        }
+    }
+}
+
+/// Emits `entries` as an expression that constructs a `std::collections::HashMap` of them, for
+/// maps that are computed at compile time but modified at runtime.
+pub fn hash_map<K: Emit, V: Emit>(
+    entries: impl IntoIterator<Item = (K, V)>,
+) -> proc_macro2::TokenStream {
+    let entries = entries.into_iter().map(|(key, value)| {
+        let (key, value) = (key.emit(), value.emit());
+        quote::quote!((#key, #value))
+    });
+    quote::quote!(::std::collections::HashMap::from([#(#entries),*]))
+}
+
+/// Emits `entries` for `comptime_phf!`: as the `phf::Map` expression made by `build`, if given
+/// (from each key and its emitted value), or else as an array of `(key, value)` tuples. A
+/// duplicate key is emitted as a `compile_error!`.
+pub fn map<K: Into<String>, T: Emit>(
+    entries: impl IntoIterator<Item = (K, T)>,
+    build: Option<&dyn Fn(&[(String, String)]) -> String>,
+) -> proc_macro2::TokenStream {
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(key, value)| (key.into(), value.emit()))
+        .collect();
+    let mut keys = std::collections::HashSet::new();
+    if let Some((key, _)) = entries.iter().find(|(key, _)| !keys.insert(key)) {
+        let message = format!("comptime_phf! key `{}` is duplicated", key);
+        return quote::quote!(compile_error!(#message));
+    }
+    match build {
+        Some(build) => {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_string()))
+                .collect();
+            build(&entries).parse().unwrap()
+        }
+        None => {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| quote::quote!((#key, #value)));
+            quote::quote!([#(#entries),*])
+        }
+    }
+}
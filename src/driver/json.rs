@@ -0,0 +1,140 @@
+// Appended to the `comptime` module when the comptime program uses `comptime::json`, which
+// requires the `json` feature of comptime and a dependency on `serde_json`.
+
+/// Results that are sent to the macro as JSON and rendered as literals there, rather than as Rust
+/// source that's parsed back, which is robust to deeply nested data.
+pub mod json {
+    use std::collections::BTreeMap;
+
+    /// A literal, which `emit` sends to the macro. Integers and floats are unsuffixed, so that
+    /// their types are inferred where the literal is used.
+    pub enum Value {
+        Int(i64),
+        UInt(u64),
+        Float(f64),
+        Bool(bool),
+        Char(char),
+        Str(String),
+        Array(Vec<Value>),
+        Tuple(Vec<Value>),
+        /// Rendered as an array of `(key, value)` tuples.
+        Map(Vec<(Value, Value)>),
+    }
+
+    /// The result of `emit`, which the driver prints as JSON.
+    pub struct Json(pub(crate) serde_json::Value);
+
+    /// Emits `value` as a literal, e.g. a `BTreeMap<String, Vec<(u8, bool)>>` as
+    /// `[("key", [(1, true)])]`.
+    pub fn emit(value: impl Into<Value>) -> Json {
+        Json(value.into().to_json())
+    }
+
+    impl Value {
+        // Each value is tagged with its variant, like `{"Int":1}`. Floats are sent as their
+        // `Debug` text, since JSON can't represent every `f64`.
+        fn to_json(&self) -> serde_json::Value {
+            let list = |values: &[Value]| values.iter().map(Value::to_json).collect::<Vec<_>>();
+            let (tag, value) = match self {
+                Self::Int(n) => ("Int", (*n).into()),
+                Self::UInt(n) => ("UInt", (*n).into()),
+                Self::Float(f) => ("Float", format!("{:?}", f).into()),
+                Self::Bool(b) => ("Bool", (*b).into()),
+                Self::Char(c) => ("Char", c.to_string().into()),
+                Self::Str(s) => ("Str", s.as_str().into()),
+                Self::Array(values) => ("Array", list(values).into()),
+                Self::Tuple(values) => ("Tuple", list(values).into()),
+                Self::Map(entries) => (
+                    "Map",
+                    entries
+                        .iter()
+                        .map(|(key, value)| serde_json::json!([key.to_json(), value.to_json()]))
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+            };
+            let mut tagged = serde_json::Map::new();
+            tagged.insert(tag.to_string(), value);
+            tagged.into()
+        }
+    }
+
+    macro_rules! from_primitive {
+        ($variant:ident($as:ty): $($ty:ty),*) => { $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    Self::$variant(value as $as)
+                }
+            }
+        )* };
+    }
+    from_primitive!(Int(i64): i8, i16, i32, i64, isize);
+    from_primitive!(UInt(u64): u8, u16, u32, u64, usize);
+    from_primitive!(Float(f64): f32, f64);
+
+    impl From<bool> for Value {
+        fn from(value: bool) -> Self {
+            Self::Bool(value)
+        }
+    }
+
+    impl From<char> for Value {
+        fn from(value: char) -> Self {
+            Self::Char(value)
+        }
+    }
+
+    impl From<String> for Value {
+        fn from(value: String) -> Self {
+            Self::Str(value)
+        }
+    }
+
+    impl From<&str> for Value {
+        fn from(value: &str) -> Self {
+            Self::Str(value.to_string())
+        }
+    }
+
+    impl<T: Into<Value>> From<Vec<T>> for Value {
+        fn from(values: Vec<T>) -> Self {
+            Self::Array(values.into_iter().map(Into::into).collect())
+        }
+    }
+
+    impl<T: Into<Value>, const N: usize> From<[T; N]> for Value {
+        fn from(values: [T; N]) -> Self {
+            Self::Array(values.into_iter().map(Into::into).collect())
+        }
+    }
+
+    // A `HashMap` isn't `Into<Value>`, since its order, and hence the result, would vary.
+    impl<K: Into<Value>, V: Into<Value>> From<BTreeMap<K, V>> for Value {
+        fn from(entries: BTreeMap<K, V>) -> Self {
+            Self::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.into(), value.into()))
+                    .collect(),
+            )
+        }
+    }
+
+    macro_rules! from_tuple {
+        ($(($($ty:ident),*)),*) => { $(
+            #[allow(non_snake_case)]
+            impl<$($ty: Into<Value>),*> From<($($ty,)*)> for Value {
+                fn from(($($ty,)*): ($($ty,)*)) -> Self {
+                    Self::Tuple(vec![$($ty.into()),*])
+                }
+            }
+        )* };
+    }
+    from_tuple!((A), (A, B), (A, B, C), (A, B, C, D), (A, B, C, D, E), (A, B, C, D, E, F));
+}
+
+impl super::ComptimeExact for super::ComptimeKind<&json::Json> {
+    fn comptime_emit(&self) -> (String, &'static str) {
+        (self.0 .0.to_string(), "json")
+    }
+}
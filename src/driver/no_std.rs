@@ -0,0 +1,57 @@
+// The source of an `@no_std` comptime program, which only has `core` and prints its result with
+// `Display` using libc's `write`. The placeholders are replaced by the program's statements, by
+// the nonce that `parse_driver_output` expects, by the result's format, and by `no_std_alloc.rs`
+// for `@no_std(alloc)`.
+
+#![no_std]
+#![no_main]
+
+{{comptime_alloc}}
+
+#[link(name = "c")]
+unsafe extern "C" {
+    fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+    fn abort() -> !;
+}
+
+struct Stdout;
+
+impl core::fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut s = s.as_bytes();
+        while !s.is_empty() {
+            let written = unsafe { write(1, s.as_ptr(), s.len()) };
+            if written <= 0 {
+                return Err(core::fmt::Error);
+            }
+            s = &s[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    unsafe { abort() }
+}
+
+// The precompiled `core` refers to this even though panics abort.
+#[unsafe(no_mangle)]
+extern "C" fn rust_eh_personality() {}
+
+#[unsafe(no_mangle)]
+extern "C" fn main(_argc: i32, _argv: *const *const u8) -> i32 {
+    let comptime_output = {
+        {{comptime_program}}
+    };
+    match core::fmt::Write::write_fmt(
+        &mut Stdout,
+        format_args!(
+            "{}\n{{comptime_format}}\nkind=value",
+            "{{comptime_nonce}}", comptime_output
+        ),
+    ) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
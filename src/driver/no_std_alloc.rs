@@ -0,0 +1,47 @@
+// Spliced into `no_std.rs` for `@no_std(alloc)`: links `alloc` with libc's allocator as the global
+// allocator, and imports the collections and macros that std's prelude would.
+
+extern crate alloc;
+
+#[allow(unused_imports)]
+use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+#[link(name = "c")]
+unsafe extern "C" {
+    fn posix_memalign(memptr: *mut *mut u8, alignment: usize, size: usize) -> i32;
+    fn free(ptr: *mut u8);
+}
+
+struct Malloc;
+
+unsafe impl core::alloc::GlobalAlloc for Malloc {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        // `posix_memalign` requires the alignment to be a multiple of the pointer size.
+        let align = layout.align().max(core::mem::size_of::<usize>());
+        let mut ptr = core::ptr::null_mut();
+        match unsafe { posix_memalign(&mut ptr, align, layout.size()) } {
+            0 => ptr,
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _: core::alloc::Layout) {
+        unsafe { free(ptr) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Malloc = Malloc;
+
+// Like `rust_eh_personality`, the precompiled `alloc` refers to this even though panics abort.
+#[unsafe(no_mangle)]
+extern "C" fn _Unwind_Resume() -> ! {
+    unsafe { abort() }
+}
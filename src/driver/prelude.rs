@@ -0,0 +1,52 @@
+// Appended to the comptime program's source to support printing its result.
+
+// Results are dispatched on their type using autoref specialization: types with exact impls of
+// `ComptimeExact` are picked before the blanket `ComptimeValue` impl.
+struct ComptimeKind<T>(T);
+
+trait ComptimeExact {
+    fn comptime_emit(&self) -> (String, &'static str);
+}
+
+impl ComptimeExact for ComptimeKind<&proc_macro2::TokenStream> {
+    fn comptime_emit(&self) -> (String, &'static str) {
+        (self.0.to_string(), "tokens")
+    }
+}
+
+// `()` isn't `ToTokens`, but a program that only conditionally generates code may return it.
+impl ComptimeExact for ComptimeKind<&()> {
+    fn comptime_emit(&self) -> (String, &'static str) {
+        ("()".to_string(), "value")
+    }
+}
+
+macro_rules! comptime_float {
+    ($($float:ident),*) => { $(
+        impl ComptimeExact for ComptimeKind<&$float> {
+            fn comptime_emit(&self) -> (String, &'static str) {
+                let tokens = match *self.0 {
+                    f if f.is_nan() => format!("{}::NAN", stringify!($float)),
+                    f if f == $float::INFINITY => format!("{}::INFINITY", stringify!($float)),
+                    f if f == $float::NEG_INFINITY => {
+                        format!("{}::NEG_INFINITY", stringify!($float))
+                    }
+                    f => quote::quote!(#f).to_string(),
+                };
+                (tokens, "value")
+            }
+        }
+    )* };
+}
+comptime_float!(f32, f64);
+
+trait ComptimeValue {
+    fn comptime_emit(&self) -> (String, &'static str);
+}
+
+impl<T: quote::ToTokens> ComptimeValue for &ComptimeKind<&T> {
+    fn comptime_emit(&self) -> (String, &'static str) {
+        let value = self.0;
+        (quote::quote!(#value).to_string(), "value")
+    }
+}
@@ -0,0 +1,12 @@
+// Appended to the `comptime` module when the comptime program reflects over the crate's source.
+
+/// Reads and parses the Rust file at `path`, and tracks it so that the invoking crate is rebuilt
+/// when it changes.
+pub fn parse_file(path: impl AsRef<Path>) -> syn::File {
+    let path = manifest_dir().join(path);
+    let source = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("could not read `{}`: {}", path.display(), e));
+    track(&path);
+    syn::parse_file(&source)
+        .unwrap_or_else(|e| panic!("could not parse `{}`: {}", path.display(), e))
+}
@@ -0,0 +1,13 @@
+// The default template of the comptime program's source. The placeholders are replaced by the
+// program's statements and by the code that prints the result bound to `comptime_output`. They
+// run on a thread with a stack of `COMPTIME_STACK_MB`, since deeply recursive programs can
+// overflow the main thread's.
+
+fn main() {
+    comptime::with_stack(|| {
+        let comptime_output = {
+            {{comptime_program}}
+        };
+        {{comptime_print}}
+    });
+}
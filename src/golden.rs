@@ -0,0 +1,140 @@
+//! Golden files of the results of comptime programs (`COMPTIME_GOLDEN_DIR`), for snapshot testing
+//! the code that's generated by comptime-powered libraries.
+//!
+//! A golden file is keyed by a hash of the program's text, and holds its formatted result. Unlike
+//! the program's disambiguator, the hash function is FNV-1a, which is stable across toolchains,
+//! rather than std's `DefaultHasher`, which isn't. The text is rendered by rustc's `TokenStream`,
+//! though, whose spacing can still change between compiler versions, so a Rust upgrade may rename
+//! golden files. `COMPTIME_UPDATE_GOLDEN=1` writes the golden files rather than checking them.
+
+use std::path::{Path, PathBuf};
+
+/// The most changed lines of each side that a diff shows.
+const DIFF_LINES: usize = 20;
+
+/// Returns the path of the golden file for `program`, which covers what sets it apart from the
+/// crate's other programs.
+pub(crate) fn path(golden_dir: &Path, crate_name: &str, program: &str) -> PathBuf {
+    golden_dir
+        .join(crate_name)
+        .join(format!("{:016x}.rs", fnv1a(program.as_bytes())))
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Checks that the golden file at `path` holds `result`, or writes it there if `update`.
+pub(crate) fn check(path: &Path, result: &str, update: bool) -> Result<(), String> {
+    if update {
+        return std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(path, result))
+            .map_err(|e| format!("could not write golden file `{}`: {}", path.display(), e));
+    }
+    let golden = match std::fs::read_to_string(path) {
+        Ok(golden) => golden,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!(
+                "comptime expr has no golden file `{}`. Run with COMPTIME_UPDATE_GOLDEN=1 to write \
+                 it.",
+                path.display()
+            ))
+        }
+        Err(e) => {
+            return Err(format!(
+                "could not read golden file `{}`: {}",
+                path.display(),
+                e
+            ))
+        }
+    };
+    match golden == result {
+        true => Ok(()),
+        false => Err(format!(
+            "comptime expr result differs from golden file `{}`. Run with \
+             COMPTIME_UPDATE_GOLDEN=1 to update it.\n\n{}",
+            path.display(),
+            diff(&golden, result)
+        )),
+    }
+}
+
+/// Shows the lines that differ between `golden` and `result`, which are those between their
+/// common leading and trailing lines, as `-` and `+` lines respectively.
+fn diff(golden: &str, result: &str) -> String {
+    let (golden, result): (Vec<_>, Vec<_>) = (golden.lines().collect(), result.lines().collect());
+    let prefix = golden
+        .iter()
+        .zip(&result)
+        .take_while(|(golden, result)| golden == result)
+        .count();
+    let suffix = golden[prefix..]
+        .iter()
+        .rev()
+        .zip(result[prefix..].iter().rev())
+        .take_while(|(golden, result)| golden == result)
+        .count();
+    let mut diff = format!("@@ line {} @@\n", prefix + 1);
+    for (sign, lines) in [
+        ('-', &golden[prefix..golden.len() - suffix]),
+        ('+', &result[prefix..result.len() - suffix]),
+    ] {
+        for line in lines.iter().take(DIFF_LINES) {
+            diff.push_str(&format!("{}{}\n", sign, line));
+        }
+        if lines.len() > DIFF_LINES {
+            diff.push_str(&format!(
+                "{}... {} more lines\n",
+                sign,
+                lines.len() - DIFF_LINES
+            ));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_golden() {
+        let golden_dir = TempDir::new("golden");
+        let path = path(&golden_dir, "app", "[1, 1 + 1]");
+        let missing = check(&path, "[1, 2]\n", false).unwrap_err();
+        assert!(missing.contains("has no golden file"));
+
+        check(&path, "[1, 2]\n", true).unwrap();
+        assert_eq!(check(&path, "[1, 2]\n", false), Ok(()));
+        assert!(check(&path, "[1, 3]\n", false)
+            .unwrap_err()
+            .ends_with("to update it.\n\n@@ line 1 @@\n-[1, 2]\n+[1, 3]\n"));
+    }
+
+    #[test]
+    fn test_path() {
+        // The hash function is the same with every toolchain.
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(
+            path(Path::new("golden"), "app", "a"),
+            Path::new("golden/app/af63dc4c8601ec8c.rs")
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            diff("a\nb\nc\nd\n", "a\nB\nc\nd\n"),
+            "@@ line 2 @@\n-b\n+B\n"
+        );
+        assert_eq!(diff("a\n", "a\nb\n"), "@@ line 2 @@\n+b\n");
+        let long: String = (0..30).map(|i| format!("{}\n", i)).collect();
+        let diff = diff("", &long);
+        assert!(diff.ends_with("+19\n+... 10 more lines\n"));
+    }
+}
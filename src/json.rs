@@ -0,0 +1,297 @@
+//! Rendering the JSON results of `comptime::json::emit`, with the `json` feature.
+//!
+//! The driver sends each value tagged with its kind, e.g. `{"Tuple":[{"Int":1},{"Str":"a"}]}`,
+//! and it's rendered as tokens directly rather than as Rust source that's parsed back.
+//!
+//! The driver's compile errors are also rendered from JSON, with `COMPTIME_JSON_DIAGNOSTICS=1`,
+//! as are the values that a build script shares with `comptime_shared!`, which are plain JSON.
+
+use proc_macro2::{Literal, TokenStream};
+use quote::{quote, ToTokens};
+use serde_json::Value;
+
+/// The source of `comptime::json`, which is only added to programs that use it.
+pub(crate) const DRIVER_JSON: &str = include_str!("driver/json.rs");
+
+/// Renders the JSON printed by the driver as a literal.
+pub(crate) fn render(json: &str) -> Result<TokenStream, String> {
+    let value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    render_value(&value)
+}
+
+fn render_value(value: &Value) -> Result<TokenStream, String> {
+    let invalid = || format!("invalid value `{}`", value);
+    let (tag, value) = match value.as_object() {
+        Some(tagged) if tagged.len() == 1 => tagged.iter().next().unwrap(),
+        _ => return Err(invalid()),
+    };
+    let list = |value: &Value| -> Result<Vec<TokenStream>, String> {
+        let values = value.as_array().ok_or_else(invalid)?;
+        values.iter().map(render_value).collect()
+    };
+    Ok(match tag.as_str() {
+        "Int" => {
+            let n = value.as_i64().ok_or_else(invalid)?;
+            let abs = Literal::u64_unsuffixed(n.unsigned_abs());
+            match n < 0 {
+                true => quote!(-#abs),
+                false => quote!(#abs),
+            }
+        }
+        "UInt" => Literal::u64_unsuffixed(value.as_u64().ok_or_else(invalid)?).to_token_stream(),
+        "Float" => {
+            let f: f64 = value
+                .as_str()
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)?;
+            match f {
+                f if f.is_nan() => quote!(f64::NAN),
+                f if f == f64::INFINITY => quote!(f64::INFINITY),
+                f if f == f64::NEG_INFINITY => quote!(f64::NEG_INFINITY),
+                f => {
+                    let abs = Literal::f64_unsuffixed(f.abs());
+                    match f.is_sign_negative() {
+                        true => quote!(-#abs),
+                        false => quote!(#abs),
+                    }
+                }
+            }
+        }
+        "Bool" => {
+            let b = value.as_bool().ok_or_else(invalid)?;
+            quote!(#b)
+        }
+        "Char" => {
+            let mut chars = value.as_str().ok_or_else(invalid)?.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => quote!(#c),
+                _ => return Err(invalid()),
+            }
+        }
+        "Str" => {
+            let s = value.as_str().ok_or_else(invalid)?;
+            quote!(#s)
+        }
+        "Array" => {
+            let values = list(value)?;
+            quote!([#(#values),*])
+        }
+        "Tuple" => match list(value)?.as_slice() {
+            [value] => quote!((#value,)),
+            values => quote!((#(#values),*)),
+        },
+        "Map" => {
+            let entries = value
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|entry| match entry.as_array().map(Vec::as_slice) {
+                    Some([key, value]) => {
+                        let (key, value) = (render_value(key)?, render_value(value)?);
+                        Ok(quote!((#key, #value)))
+                    }
+                    _ => Err(invalid()),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            quote!([#(#entries),*])
+        }
+        _ => return Err(invalid()),
+    })
+}
+
+/// Renders the value of `key` in the JSON object `shared`, which a build script wrote, as a
+/// literal.
+pub(crate) fn render_shared(shared: &str, key: &str) -> Result<TokenStream, String> {
+    let shared: Value = serde_json::from_str(shared).map_err(|e| e.to_string())?;
+    let Some(shared) = shared.as_object() else {
+        return Err("it is not a JSON object".to_string());
+    };
+    match shared.get(key) {
+        Some(value) => render_plain(value),
+        None => Err(format!("it has no key `{}`", key)),
+    }
+}
+
+/// Renders an untagged JSON value: objects as arrays of `(key, value)` tuples, ordered by key.
+fn render_plain(value: &Value) -> Result<TokenStream, String> {
+    Ok(match value {
+        Value::Null => return Err("`null` has no literal".to_string()),
+        Value::Bool(b) => quote!(#b),
+        Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(n), _, _) => Literal::u64_unsuffixed(n).to_token_stream(),
+            (_, Some(n), _) => {
+                let abs = Literal::u64_unsuffixed(n.unsigned_abs());
+                quote!(-#abs)
+            }
+            (_, _, Some(f)) => {
+                let abs = Literal::f64_unsuffixed(f.abs());
+                match f.is_sign_negative() {
+                    true => quote!(-#abs),
+                    false => quote!(#abs),
+                }
+            }
+            _ => return Err(format!("invalid number `{}`", n)),
+        },
+        Value::String(s) => quote!(#s),
+        Value::Array(values) => {
+            let values = values
+                .iter()
+                .map(render_plain)
+                .collect::<Result<Vec<_>, _>>()?;
+            quote!([#(#values),*])
+        }
+        Value::Object(entries) => {
+            let entries = entries
+                .iter()
+                .map(|(key, value)| {
+                    let value = render_plain(value)?;
+                    Ok(quote!((#key, #value)))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            quote!([#(#entries),*])
+        }
+    })
+}
+
+/// Renders the errors among rustc's JSON `diagnostics` concisely: each error's message, and the
+/// source line and label of each of its spans. Lines that aren't JSON are kept as they are.
+pub(crate) fn render_diagnostics(diagnostics: &str) -> String {
+    let mut rendered = String::new();
+    for line in diagnostics.lines() {
+        let Ok(diagnostic) = serde_json::from_str::<Value>(line) else {
+            rendered.push_str(line);
+            rendered.push('\n');
+            continue;
+        };
+        let message = diagnostic["message"].as_str().unwrap_or_default();
+        // rustc's summary of the errors says nothing that they don't.
+        if diagnostic["$message_type"] != "diagnostic"
+            || diagnostic["level"] != "error"
+            || message.starts_with("aborting due to")
+        {
+            continue;
+        }
+        match diagnostic["code"]["code"].as_str() {
+            Some(code) => rendered.push_str(&format!("error[{}]: {}\n", code, message)),
+            None => rendered.push_str(&format!("error: {}\n", message)),
+        }
+        let spans = diagnostic["spans"].as_array().map(Vec::as_slice);
+        for span in spans.unwrap_or_default() {
+            rendered.push_str(&render_span(span));
+        }
+        let children = diagnostic["children"].as_array().map(Vec::as_slice);
+        for child in children.unwrap_or_default() {
+            rendered.push_str(&format!(
+                "  = {}: {}\n",
+                child["level"].as_str().unwrap_or("note"),
+                child["message"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+    rendered
+}
+
+/// Renders the location of `span`, and its first line of source with the span underlined and
+/// labeled.
+fn render_span(span: &Value) -> String {
+    let mut rendered = format!(
+        "  --> {}:{}:{}\n",
+        span["file_name"].as_str().unwrap_or_default(),
+        span["line_start"],
+        span["column_start"]
+    );
+    if let Some(text) = span["text"].get(0) {
+        let start = text["highlight_start"].as_u64().unwrap_or(1).max(1) as usize;
+        let end = text["highlight_end"]
+            .as_u64()
+            .unwrap_or(1)
+            .max(start as u64 + 1) as usize;
+        let underline = match span["is_primary"].as_bool() {
+            Some(true) => "^",
+            _ => "-",
+        };
+        rendered.push_str(&format!(
+            "   | {}\n   | {}{} {}\n",
+            text["text"].as_str().unwrap_or_default(),
+            " ".repeat(start - 1),
+            underline.repeat(end - start),
+            span["label"].as_str().unwrap_or_default()
+        ));
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let json = r#"{"Map":[
+            [{"Str":"a"},{"Array":[{"Tuple":[{"Int":-1},{"Bool":true}]}]}],
+            [{"Str":"b"},{"Array":[]}]
+        ]}"#;
+        assert_eq!(
+            render(json).unwrap().to_string(),
+            quote!([("a", [(-1, true)]), ("b", [])]).to_string()
+        );
+        let json = r#"{"Tuple":[{"UInt":18446744073709551615},{"Float":"-0.5"},{"Char":"x"}]}"#;
+        assert_eq!(
+            render(json).unwrap().to_string(),
+            quote!((18446744073709551615, -0.5, 'x')).to_string()
+        );
+        assert_eq!(
+            render(r#"{"Tuple":[{"Float":"NaN"}]}"#)
+                .unwrap()
+                .to_string(),
+            quote!((f64::NAN,)).to_string()
+        );
+        assert_eq!(
+            render(r#"{"Int":"1"}"#).unwrap_err(),
+            r#"invalid value `{"Int":"1"}`"#
+        );
+    }
+
+    #[test]
+    fn test_render_shared() {
+        let shared = r#"{"version":[1,-2,0.5],"git":{"hash":"abc","dirty":false},"none":null}"#;
+        assert_eq!(
+            render_shared(shared, "version").unwrap().to_string(),
+            quote!([1, -2, 0.5]).to_string()
+        );
+        assert_eq!(
+            render_shared(shared, "git").unwrap().to_string(),
+            quote!([("dirty", false), ("hash", "abc")]).to_string()
+        );
+        assert_eq!(
+            render_shared(shared, "none").unwrap_err(),
+            "`null` has no literal"
+        );
+        assert_eq!(
+            render_shared(shared, "missing").unwrap_err(),
+            "it has no key `missing`"
+        );
+        assert_eq!(
+            render_shared("[1]", "a").unwrap_err(),
+            "it is not a JSON object"
+        );
+    }
+
+    #[test]
+    fn test_render_diagnostics() {
+        let diagnostics = [
+            r#"{"$message_type":"artifact","artifact":"comptime-app-1.d","emit":"dep-info"}"#,
+            r#"{"$message_type":"diagnostic","message":"mismatched types","code":{"code":"E0308"},"level":"error","spans":[{"file_name":"comptime-app-1.rs","line_start":3,"column_start":18,"is_primary":true,"label":"expected `u32`, found `&str`","text":[{"text":"    let x: u32 = \"a\";","highlight_start":18,"highlight_end":21}]}],"children":[{"message":"try `.len()`","level":"help","spans":[]}]}"#,
+            r#"{"$message_type":"diagnostic","message":"aborting due to 1 previous error","code":null,"level":"error","spans":[],"children":[]}"#,
+            r#"{"$message_type":"diagnostic","message":"For more information about this error, try `rustc --explain E0308`.","code":null,"level":"failure-note","spans":[],"children":[]}"#,
+        ];
+        assert_eq!(
+            render_diagnostics(&diagnostics.join("\n")),
+            "error[E0308]: mismatched types\n  \
+             --> comptime-app-1.rs:3:18\n   \
+             |     let x: u32 = \"a\";\n   \
+             |                  ^^^ expected `u32`, found `&str`\n  \
+             = help: try `.len()`\n"
+        );
+    }
+}
@@ -25,34 +25,696 @@
 //! interpolate static values using `quote!`.
 //!
 //! Also, `comptime!` requires you to run `cargo build` at least once before `cargo (clippy|check)`
-//! will work since `comptime!` does not compile dependencies.
+//! will work since `comptime!` does not compile dependencies, unless `COMPTIME_BUILD_DEPS=1`.
+//!
+//! The program can use the crate's dependencies, as well as `quote` and `proc-macro2` 1.x, which
+//! are available even if the crate doesn't depend on them.
+//!
+//! The comptime program runs on the host, so when cross-compiling, things like
+//! `std::mem::size_of::<usize>()`, `#[cfg(target_os = ..)]`, and `std::env::consts` describe the
+//! host and not the target. `cfg!`s that only ask about the target, like
+//! `cfg!(target_pointer_width = "32")`, are answered for the target, from `rustc --print cfg`
+//! with the crate's `-C target-cpu` and `-C target-feature`.
+//! `@target_layout` provides the target's layout, and in the crates that cargo was asked to build,
+//! a warning is printed when a layout query is made for a target whose pointer width differs from
+//! the host's.
+//!
+//! Crates are linked from the deps dir. The crate's own dependencies are linked as the rlibs that
+//! cargo passes to rustc, even under other names. When the deps dir holds several versions of
+//! another crate, the highest one compatible with the crate's own `Cargo.toml` requirement is
+//! used, or else the highest one, which might not be the version that a transitive dependency
+//! expects.
+//!
+//! A result can be a const generic argument, but rustc parses a macro invocation in generic
+//! argument position as a type, so the invocation must be braced, as in
+//! `Buf::<{ comptime!(..) }>`. Integer results have the type computed by the program (e.g., `4i32`
+//! for `2 + 2`), so the program must compute a `usize` for a `const N: usize`.
+//!
+//! The same goes for a const parameter's default: rustc rejects `struct S<const N: usize =
+//! comptime!(..)>` while parsing, before any macro is expanded, so no macro can be used there
+//! unbraced. Either brace it, as in `struct S<const N: usize = { comptime!(..) }>`, or compute a
+//! `const` item and name it, as in `struct S<const N: usize = N_DEFAULT>`.
+//!
+//! Each invocation's result is its own literal, but rustc stores identical literals in a crate
+//! only once, so many invocations that produce the same large string don't bloat the binary.
+//!
+//! The program can't refer to `$crate`, so a `macro_rules!` macro that invokes `comptime!` must
+//! name crates explicitly:
+//!
+//! ```ignore
+//! macro_rules! answer {
+//!     () => { comptime::comptime!($crate::answer()) };
+//! }
+//! fn answer() -> u32 { 42 }
+//! let _ = answer!();
+//! ```
 //!
 //! Finally, using this macro in doctests may fail with strange errors for no good reason. This is
 //! because output directory detection is imperfect and sometimes breaks. You have been warned.
+//!
+//! ### Result types
+//!
+//! A result that doesn't parse as an expression is silently turned into a string literal. To
+//! instead require a particular kind of literal, give the result's type as
+//! `comptime!(as u32 { .. })` or `comptime_as!(u32, { .. })`. The type may be a primitive integer
+//! or float, `bool`, `char`, or `&str` (for which the program may return a `String`), and a
+//! result of any other kind fails compilation:
+//!
+//! ```ignore
+//! let four = comptime::comptime!(as u32 { 2 + 2 }); // `4u32`
+//! let name = comptime::comptime!(as &str { 4 }); // error: `4i32` is not a string literal
+//! ```
+//!
+//! A result that calls unsafe functions, like `std::mem::transmute`, can either be an `unsafe`
+//! block itself or be wrapped in one by `comptime_unsafe!`, which is otherwise `comptime!`:
+//!
+//! ```ignore
+//! let one: f32 = comptime::comptime_unsafe!(quote::quote!(std::mem::transmute(0x3f80_0000u32)));
+//! ```
+//!
+//! ### C strings
+//!
+//! `comptime_cstr!(..)` is `comptime!(as &str ..)` emitted as a `&'static CStr`, for FFI. It's a
+//! `c".."` literal in edition 2021 and later, or else built from a byte string with a trailing
+//! NUL. A result with an interior NUL fails to compile:
+//!
+//! ```ignore
+//! const GREETING: &std::ffi::CStr = comptime::comptime_cstr!(format!("hi from {}", host()));
+//! unsafe { puts(GREETING.as_ptr()) };
+//! ```
+//!
+//! ### JSON results
+//!
+//! With the `json` feature, and if the crate depends on `serde_json`, a program can return
+//! `comptime::json::emit(value)`, where `value` is made of integers, floats, `bool`s, `char`s,
+//! strings, `Vec`s, arrays, tuples, and `BTreeMap`s (as arrays of `(key, value)` tuples). The
+//! value is sent to the macro as JSON and rendered as a literal there, which is more robust than
+//! the text of deeply nested data. Numbers are unsuffixed, so that their types are inferred:
+//!
+//! ```ignore
+//! let scores: [(&str, [(u8, f32); 1]); 1] = comptime!({
+//!     comptime::json::emit(std::collections::BTreeMap::from([("a", vec![(3u8, 2.5f32)])]))
+//! });
+//! ```
+//!
+//! ### Embedded files
+//!
+//! A program that returns `comptime::IncludeStr(path)` or `comptime::IncludeBytes(path)` emits an
+//! `include_str!` or `include_bytes!` of the file at `path`, relative to the crate's root, rather
+//! than the file's contents. This is cheaper for large files, and cargo rebuilds the crate when
+//! the file changes, as for any other `include_str!`:
+//!
+//! ```ignore
+//! const SCHEMA: &str = comptime!(comptime::IncludeStr(format!("schemas/{}.sql", version())));
+//! ```
+//!
+//! ### Config files
+//!
+//! `comptime_toml!(Type, "path", { .. })` parses a TOML file, relative to the crate's root, as a
+//! `Type` with the crate's `toml` dependency, and emits it with `comptime::Emit`, e.g. as a struct
+//! literal. `comptime_yaml!` does the same for YAML with `serde_yaml`. The program can't see the
+//! crate's types, so the braced items define a `Deserialize` twin of `Type` for the program, whose
+//! strings may be `String`s where the crate's are `&'static str`s. The crate is rebuilt when the
+//! file changes, and a file that can't be read or parsed fails compilation with its path and the
+//! parser's error:
+//!
+//! ```ignore
+//! struct Config { name: &'static str, port: u16 }
+//!
+//! const CONFIG: Config = comptime::comptime_toml!(Config, "config.toml", {
+//!     #[derive(serde::Deserialize)]
+//!     struct Config { name: String, port: u16 }
+//!     comptime::emit_struct!(Config { name, port });
+//! });
+//! ```
+//!
+//! ### Build scripts
+//!
+//! With the `json` feature, a build script can share values with the crate by writing a JSON
+//! object to `$OUT_DIR/comptime_shared.json`, and `comptime_shared!("key")` emits the value of
+//! `key` as a literal, like a JSON result, with objects as arrays of `(key, value)` tuples. The
+//! crate is rebuilt when the file changes:
+//!
+//! ```ignore
+//! // build.rs
+//! let out_dir = std::env::var("OUT_DIR").unwrap();
+//! std::fs::write(format!("{}/comptime_shared.json", out_dir), r#"{"lanes": 8}"#).unwrap();
+//!
+//! // src/lib.rs
+//! const LANES: usize = comptime::comptime_shared!("lanes");
+//! ```
+//!
+//! ### Statics
+//!
+//! `comptime_static!` emits a `static` whose initializer is the result of the comptime program:
+//!
+//! ```ignore
+//! comptime::comptime_static!(pub REVERSED: [u8; 256] = {
+//!     let bytes = (0..=255u8).map(u8::reverse_bits);
+//!     quote::quote!([#(#bytes),*])
+//! });
+//! ```
+//!
+//! A result that can't be built in a `const`, like a `HashMap`, can initialize a lazy `static`
+//! instead. `comptime_lazy!(Type, { .. })` emits a `once_cell::sync::Lazy<Type>` if the crate
+//! depends on `once_cell`, or else a `std::sync::LazyLock<Type>`, which computes the result the
+//! first time that it's used:
+//!
+//! ```ignore
+//! static SCORES: LazyLock<HashMap<&str, u32>> = comptime::comptime_lazy!(HashMap<&str, u32>, {
+//!     comptime::hash_map(vec![("a", 1u32), ("b", 2)])
+//! });
+//! ```
+//!
+//! ### Bundles
+//!
+//! `comptime_bundle! { .. }` emits several `const`s whose initializers are comptime programs,
+//! which are compiled and run as one program to save the cost of compiling each. A program can use
+//! the values that the others compute by name, since each is bound to a variable of its const's
+//! name, and they run in the order of those references, so that a const can precede the ones it
+//! uses. The values are emitted with `comptime::Emit`, so their types in the programs may differ
+//! from the consts', e.g. a `String` for a `&str`:
+//!
+//! ```ignore
+//! comptime::comptime_bundle! {
+//!     pub const BANNER: &str = format!("{} primes below {}", PRIMES, LIMIT);
+//!     const LIMIT: u32 = 100;
+//!     const PRIMES: usize = (2..LIMIT).filter(|n| (2..*n).all(|d| n % d != 0)).count();
+//! }
+//! ```
+//!
+//! A name is taken to be a reference wherever it appears in another program, even if it's shadowed
+//! there, and consts that refer to each other fail to compile. The variables are bound by value,
+//! and still emitted afterwards, so a program that needs another's value that isn't `Copy`, like a
+//! `String` or a `Vec`, by value must clone it rather than move it.
+//!
+//! ### Items
+//!
+//! `comptime_items!`, or `comptime_item!`, emits items in item position. A program that returns
+//! `()` or `comptime::nothing()` emits nothing, which makes conditional generation straightforward
+//! (in expression position, `comptime!` evaluates to `()` instead):
+//!
+//! ```ignore
+//! comptime::comptime_items! {
+//!     if cfg!(feature = "serde") {
+//!         quote::quote!(mod serde_impls;)
+//!     } else {
+//!         comptime::nothing()
+//!     }
+//! }
+//! ```
+//!
+//! The items are spliced as they are, so a program can emit any number of them, including `use`s
+//! and private helpers that the public items share.
+//!
+//! ### Docs
+//!
+//! `#[comptime_doc { .. }]` documents an item with the string that the comptime program returns,
+//! e.g. a summary generated from a changelog. A result that isn't a string fails to compile. For
+//! a result that tracks no files, `#[doc = comptime!(..)]` does the same:
+//!
+//! ```ignore
+//! #[comptime::comptime_doc {
+//!     let changelog = std::fs::read_to_string("CHANGELOG.md").unwrap();
+//!     comptime::track("CHANGELOG.md");
+//!     changelog.split("\n## ").nth(1).unwrap_or_default().to_string()
+//! }]
+//! pub struct Release;
+//! ```
+//!
+//! ### Modules
+//!
+//! `#[comptime_fn(module = "generated")]` writes the items that the function's body returns to a
+//! formatted file in the out dir and expands to a `mod generated;` of it, with the function's
+//! attributes and visibility. rustc tracks the file as a module source, and it's only rewritten
+//! when its contents change. A nested path like `module = "gen::tables"` declares `mod tables`
+//! and keeps its file apart from those of other `tables` modules:
+//!
+//! ```ignore
+//! #[comptime::comptime_fn(module = "generated")]
+//! pub fn generated() {
+//!     quote::quote! {
+//!         pub const PRIMES: [u32; 3] = [2, 3, 5];
+//!         pub fn nth_prime(n: usize) -> u32 { PRIMES[n] }
+//!     }
+//! }
+//!
+//! use generated::nth_prime;
+//! ```
+//!
+//! ### Tables
+//!
+//! `comptime_table!(Type; { .. })` emits an array literal of the values returned by the comptime
+//! program. The program can't use the crate's own `Type`, so it defines its own version of it,
+//! and `comptime::emit_struct!` converts that to `Type`'s struct expression. Fields can be
+//! anything that's `quote::ToTokens` or `comptime::Emit`:
+//!
+//! ```ignore
+//! struct Point { x: i32, y: i32 }
+//!
+//! const POINTS: [Point; 3] = comptime::comptime_table!(Point; {
+//!     struct Point { x: i32, y: i32 }
+//!     comptime::emit_struct!(Point { x, y });
+//!     (1..=3).map(|x| Point { x, y: x * x }).collect::<Vec<_>>()
+//! });
+//! ```
+//!
+//! Large tables are emitted as an `include!` of a file in the out dir, like `@include`.
+//!
+//! A table of `u8`s is a byte array, which, unlike a byte string, can be owned by a `const`:
+//!
+//! ```ignore
+//! const KEY: [u8; 32] = comptime::comptime_table!(u8; {
+//!     let seed = std::fs::read("key.seed").unwrap();
+//!     (0..32).map(|i| seed[i % seed.len()] ^ i as u8).collect::<Vec<_>>()
+//! });
+//! ```
+//!
+//! `comptime_const_fn!(fn name(index: usize) -> Type { .. })` emits a table along with a
+//! `const fn` that looks up its elements, which can be used in `const`s. If `Type` is an `Option`,
+//! an index that's out of bounds is `None`, and otherwise it panics:
+//!
+//! ```ignore
+//! comptime::comptime_const_fn!(pub fn crc8(byte: u8) -> u8 {
+//!     (0..=255u8).map(|byte| crc8_of(byte)).collect::<Vec<_>>()
+//! });
+//! const CHECK: u8 = crc8(0x42);
+//! ```
+//!
+//! ### Argument sweeps
+//!
+//! `comptime_map!(name: Type in args { .. })` runs the comptime program once for each of `args`,
+//! which are an array of literals or a range of integers, with `name` bound to the argument, and
+//! evaluates to an array of the results. The program is compiled only once, however many times
+//! it's run, which is much faster than a `comptime!` per argument:
+//!
+//! ```ignore
+//! const FACTORIALS: [u64; 8] = comptime::comptime_map!(n: u64 in 0..8 {
+//!     (1..=n).product::<u64>()
+//! });
+//! ```
+//!
+//! ### Unique ids
+//!
+//! `comptime_unique_id!()` is an integer literal that's different from that of every other
+//! invocation in the build, including in other crates, e.g. for discriminants or type ids:
+//!
+//! ```ignore
+//! const WIDGET_ID: u32 = comptime::comptime_unique_id!();
+//! ```
+//!
+//! Ids are claimed from a counter file in the out dir, in order, as each invocation is expanded.
+//! They're stable within a build, but not across builds, since a crate that's rebuilt claims new
+//! ones (until `cargo clean`), and the order in which crates are built varies.
+//!
+//! ### Timestamps
+//!
+//! `comptime_timestamp!()` is the time of the build as an RFC 3339 string in UTC, like
+//! `"2019-08-30T12:00:00Z"`. A timestamp makes the build unreproducible, so it's opt-in, and if
+//! `SOURCE_DATE_EPOCH` is set, as reproducible builds do, it's that time instead. The crate is
+//! rebuilt when `SOURCE_DATE_EPOCH` changes:
+//!
+//! ```ignore
+//! const BUILT_AT: &str = comptime::comptime_timestamp!();
+//! ```
+//!
+//! ### Range matches
+//!
+//! `comptime_range_match!(input; { .. })` emits a `match` of the integer `input` over the
+//! `(RangeInclusive<i64>, value)` pairs returned by the comptime program, which evaluates to
+//! `Some(value)` for the range containing `input`, and `None` if there's none. This suits
+//! generated classification tables, like Unicode category lookups. Values can be anything that's
+//! `quote::ToTokens` or `comptime::Emit`, and overlapping ranges fail to compile:
+//!
+//! ```ignore
+//! fn is_digit(c: u8) -> bool {
+//!     comptime::comptime_range_match!(c; {
+//!         vec![(0x30..=0x39, true), (0x3a..=0x40, false)]
+//!     })
+//!     .unwrap_or(false)
+//! }
+//! ```
+//!
+//! ### Enums
+//!
+//! `comptime_enum!(Name { .. })` emits `enum Name` with a unit variant named by each string that
+//! the comptime program returns, e.g. from an external list of country codes, along with
+//! `Name::as_str`, and a `FromStr` impl whose error is a `String`. Attributes and a visibility may
+//! precede the name, and a name that isn't a valid identifier fails to compile:
+//!
+//! ```ignore
+//! comptime::comptime_enum!(#[derive(Debug)] pub Country {
+//!     vec!["US", "FR", "0A"] // error: `0A` is not a valid identifier
+//! });
+//! ```
+//!
+//! ### Maps
+//!
+//! `comptime_phf!(key => Type; { .. })` looks up the `&str` `key` in a static map of the
+//! `(key, value)` pairs returned by the comptime program, and evaluates to an
+//! `Option<&'static Type>`. If the crate depends on `phf`, and `phf_codegen` has been built (e.g.,
+//! as a build-dependency), the map is a perfect-hash `phf::Map`, and otherwise it's a `match`.
+//! Duplicate keys fail to compile:
+//!
+//! ```ignore
+//! fn http_status(reason: &str) -> Option<u16> {
+//!     comptime::comptime_phf!(reason => u16; {
+//!         vec![("OK", 200), ("Not Found", 404), ("OK", 204)]
+//!     })
+//!     .copied()
+//! }
+//! ```
+//!
+//! For a `HashMap` that can be modified at runtime instead, a `comptime!` program can return
+//! `comptime::hash_map(entries)`, which emits a `HashMap::from([..])` of the `(key, value)`
+//! entries:
+//!
+//! ```ignore
+//! let mut counts: HashMap<&str, u32> = comptime!(comptime::hash_map(vec![("a", 1u32)]));
+//! ```
+//!
+//! ### Assertions
+//!
+//! `comptime_assert!` and `comptime_assert_eq!` check invariants over arbitrary computation,
+//! and not just what's const-evaluable. They expand to an item, so they can be used wherever an
+//! item can, including at a module's top level:
+//!
+//! ```ignore
+//! comptime::comptime_assert!(std::mem::size_of::<u64>() == 8);
+//! comptime::comptime_assert_eq!(
+//!     "abc".repeat(2),
+//!     "abcabcabc",
+//!     "should fail to compile"
+//! ); // error: should fail to compile
+//! ```
+//!
+//! `comptime_regex!` checks that a regex pattern is valid, using the crate's `regex` dependency,
+//! and expands to the pattern, so constructing the regex at runtime can't fail:
+//!
+//! ```ignore
+//! let word = regex::Regex::new(comptime::comptime_regex!(r"\w+")).unwrap();
+//! let unclosed = regex::Regex::new(comptime::comptime_regex!(r"(\w+")).unwrap(); // error
+//! ```
+//!
+//! ### Reflection
+//!
+//! The comptime program runs in the crate's root, with `CARGO_MANIFEST_DIR` set to it, so a
+//! relative path like `"data/table.csv"` names the same file in every module of the crate, unlike
+//! in `include_str!`, whose paths are relative to the file that invokes it. The program also
+//! inherits the environment that cargo sets for rustc, like `CARGO_PKG_VERSION` and, for a crate
+//! with a build script, `OUT_DIR`. Without cargo, e.g. when rustc is invoked directly, it runs in
+//! rustc's working directory.
+//!
+//! `comptime::parse_file(path)` parses the crate's own source with `syn`, which makes it possible
+//! to emit a registry of the crate's items. Parsed files are tracked so that the crate is rebuilt
+//! when they change:
+//!
+//! ```ignore
+//! const HANDLERS: &[&str] = &comptime! {
+//!     let file = comptime::parse_file("src/handlers.rs");
+//!     let names = file.items.iter().filter_map(|item| match item {
+//!         syn::Item::Fn(f) => Some(f.sig.ident.to_string()),
+//!         _ => None,
+//!     });
+//!     quote::quote!([#(#names),*])
+//! };
+//! ```
+//!
+//! The source is read as written, so items generated by macros (including other `comptime!`s)
+//! aren't visible. A tracked result is wrapped in a block, so it can't be used where a literal is
+//! required, such as in `concat!`.
+//!
+//! ### Randomness
+//!
+//! The comptime program is run with `COMPTIME_SEED` set to a seed derived from its source (and
+//! its `@env_key`s), which `comptime::seed()` returns. A generator seeded with it is random, yet
+//! produces the same output on every build of the same source with the same toolchain:
+//!
+//! ```ignore
+//! const SALTS: [u64; 4] = comptime! {
+//!     use rand::{Rng, SeedableRng};
+//!     let mut rng = rand::rngs::StdRng::seed_from_u64(comptime::seed());
+//!     let salts = (0..4).map(|_| rng.gen::<u64>());
+//!     quote::quote!([#(#salts),*])
+//! };
+//! ```
+//!
+//! ### Commands
+//!
+//! `comptime_cmd!("program", ["arg", ..])` runs a command in the crate's root and expands to its
+//! trimmed stdout as a string literal, and a command that fails fails compilation with its
+//! stderr. A relative path to the program, like `scripts/version.sh`, is relative to the crate's
+//! root. The command isn't tracked, so it's only rerun when the crate is rebuilt for some other
+//! reason (and not at all under `COMPTIME_CACHE`):
+//!
+//! ```ignore
+//! const REVISION: &str = comptime::comptime_cmd!("git", ["rev-parse", "HEAD"]);
+//! const MISSING: &str = comptime::comptime_cmd!("git", ["no-such-command"]); // error
+//! ```
+//!
+//! ### Directives
+//!
+//! The comptime program may be preceded by `@directive`s, in which case the program itself must
+//! be wrapped in braces:
+//!
+//! * `@include` writes the result to a rustfmt-formatted file in the out dir and expands to an
+//!   `include!` of it, which keeps large generated code readable.
+//!
+//! * `@no_std` builds the program with only `core`, for when linking std into it is a problem.
+//!   This is severely limited: the program can't use `std`, `alloc`, `quote`, or crates that
+//!   depend on them, its result is printed with `core::fmt::Display` (so only results like
+//!   integers and `bool`s, whose `Display` is valid Rust, make sense), a panic aborts without a
+//!   message, and it's only supported on Unix hosts, where libc provides the entry point and
+//!   output. `COMPTIME_DRIVER_TEMPLATE` doesn't apply.
+//!
+//! * `@no_std(alloc)` also links `alloc`, with libc's `malloc` as the global allocator, and
+//!   imports `Vec`, `String`, `Box`, `vec!`, and `format!` as std's prelude would. Its result is
+//!   printed with `Debug` instead, which is valid Rust for collections of literals, e.g. a
+//!   `Vec<u8>` is emitted as `[0, 1, 4, 9]`.
+//!
+//! * `@stdin` or `@stdin("path")` feeds the file at `path` (or `COMPTIME_STDIN`) to the comptime
+//!   program's stdin. If neither is given, stdin is empty.
+//!
+//! * `@edition(2024)` compiles the program with the given edition, which may be any edition that
+//!   the installed rustc supports, rather than with the crate's edition.
+//!   `#[comptime_fn(edition = "2024")]` does the same for a `comptime_fn`.
+//!
+//! * `@env_key(VAR, ..)` declares that the result depends on the environment variables `VAR, ..`,
+//!   so that the crate is rebuilt, and the program re-evaluated, when they change. For example,
+//!   `@env_key(BUILD_ENV)` lets a program generate different code for `BUILD_ENV=prod` and
+//!   `BUILD_ENV=dev`. `#[comptime_fn(env_key(VAR, ..))]` does the same for a `comptime_fn`.
+//!
+//! * `@cfg("feature=\"x\"", ..)` compiles the program with the given `--cfg`s in place of the
+//!   crate's cfgs of the same names, so `cfg!(feature = "x")` is true in the program, and other
+//!   features are off, however the crate is built. This lets a program generate data for every
+//!   feature regardless of which are enabled.
+//!
+//! * `@suffix(false)` strips the type suffixes of the result's numeric literals, e.g. `5u8` to
+//!   `5`, so that their types are inferred where the result is used. By default, literals keep
+//!   the suffixes of the program's types. A number that's too large for its inferred type fails
+//!   to compile, with an error that points at the invocation:
+//!
+//!   ```ignore
+//!   const LIMIT: u8 = comptime::comptime!(@suffix(false) { 100 * 3 }); // error: out of range
+//!   ```
+//!
+//! * `@try` lets the program use `?`, as in a `try` block: its value is the result, and an error
+//!   from `?`, of any type that converts to a `Box<dyn std::error::Error>`, fails the build with
+//!   the error's message rather than a panic. The program is the body of a closure, so `return`
+//!   returns a `Result` from it: `return Ok(value)` makes `value` the result early.
+//!   `#[comptime_fn(try)]` does the same for a `comptime_fn`:
+//!
+//!   ```ignore
+//!   const VERSION: &str = comptime::comptime!(@try {
+//!       let manifest: toml::Table = std::fs::read_to_string("Cargo.toml")?.parse()?;
+//!       manifest["package"]["version"].as_str().ok_or("there's no version")?.to_string()
+//!   });
+//!   ```
+//!
+//! * `@cast(Type)` converts a numeric result to the primitive integer or float `Type`, e.g. an
+//!   `i64` that a program computes to the `usize` that a const needs. An integer that fits in
+//!   `Type` is emitted as a literal of `Type`. Anything else is emitted as `(result) as Type`,
+//!   with a warning if `as` truncates it, in the crates that cargo was asked to build:
+//!
+//!   ```ignore
+//!   const LANES: usize = comptime::comptime!(@cast(usize) { lanes_i64() });
+//!   ```
+//!
+//! * `@span(token)` reports errors in the result at `token`, rather than at the invocation, for
+//!   macros that expand to `comptime!` invocations of their input. For example, a macro can
+//!   report a type error in a program's result at the name that it was given:
+//!
+//!   ```ignore
+//!   macro_rules! lookup_table {
+//!       ($name:ident = $program:block) => {
+//!           const $name: [u8; 256] = comptime::comptime!(@span($name) $program);
+//!       };
+//!   }
+//!   ```
+//!
+//! * `@timeout(120)` lets the program run for 120 seconds, in place of `COMPTIME_TIMEOUT`, so
+//!   that a slow generator needn't loosen the timeout of every other program.
+//!   `#[comptime_fn(timeout_secs = 120)]` does the same for a `comptime_fn`.
+//!
+//! * `@target_layout` provides the compilation target's `POINTER_WIDTH`, `ENDIAN`, `ARCH`, and
+//!   `OS` to the program as constants in `comptime::target`.
+//!
+//! ```ignore
+//! const SQUARES: [u32; 4] = comptime!(@include { [0, 1, 4, 9] });
+//! ```
+//!
+//! ### Configuration
+//!
+//! `comptime!` reads a few environment variables at build time:
+//!
+//! * `COMPTIME_BUILD_DEPS=1` builds the crate's dependencies on demand when rustc is only
+//!   checking the crate (as in `cargo check` and `cargo clippy`), so that `cargo build` needn't be
+//!   run first. The dependencies are built once, by a helper project in
+//!   `target/<profile>/comptime-deps`, and shared by all comptime invocations that need them.
+//!   Dependencies inherited from a workspace aren't supported. A failed build names the
+//!   dependencies that failed, and `COMPTIME_KEEP=1` keeps the helper's manifest for inspection.
+//! * `COMPTIME_CACHE=1` caches the output of each comptime program in the out dir, and reuses it
+//!   while the program, its dependencies, its `@stdin`, its `@env_key`s, and the files that it
+//!   tracks (e.g., with `comptime::parse_file`) are unchanged. This skips compiling and running
+//!   the program, and rustc's answers about the target and `@edition`s are cached too, so that
+//!   rustc isn't run for it at all. This makes `cargo watch` loops fast, since rustc re-expands
+//!   every `comptime!` in a crate whenever any of its files change. Programs whose output depends
+//!   on anything else, like the current time, are stale when cached. Even without it, a compiled
+//!   program is kept in the out dir and run again, without being recompiled, while its source, its
+//!   rustc arguments, and the crates that it links are unchanged. It's removed by the crate's next
+//!   build after one that didn't use it.
+//! * `COMPTIME_COMMITTABLE=1` ensures that files written from results, with `@include` or
+//!   `COMPTIME_KEEP`, are the same on every machine, so that they can be committed. A result that
+//!   contains the crate's path, the out dir, or a home directory fails the build, as does a result
+//!   that rustfmt can't format.
+//! * `COMPTIME_DEBUG_ALL=1` turns on every diagnostic at once, for debugging a failing build, e.g.
+//!   in CI: it implies `COMPTIME_KEEP=1` and `COMPTIME_LOG=summary,commands,stderr`, and it
+//!   disables `COMPTIME_CACHE` and the reuse of compiled programs, so that every program is
+//!   compiled and run.
+//! * `COMPTIME_DRIVER_TEMPLATE=path` replaces the `fn main` wrapper of the comptime program with
+//!   the file at `path`. The template must contain a `{{comptime_program}}` placeholder, where the
+//!   program's statements go, and a `{{comptime_print}}` placeholder, where the result, bound to
+//!   `comptime_output`, is printed. The default template is `src/driver/template.rs`.
+//! * `COMPTIME_DRIVER_TARGET_FEATURES=features` compiles the comptime program, and not the crate,
+//!   with `-C target-feature=features`, e.g. `+avx2,+fma`, so that it can use SIMD intrinsics
+//!   to compute tables faster. The program runs on the build host, which must support the
+//!   features, or else it dies of an illegal instruction, which fails the build.
+//! * `COMPTIME_EXTERN_STRATEGY=strategy` chooses how the crates that the comptime program can
+//!   use are found, in case the default picks the wrong ones for a build:
+//!   * `args` links only the crate's direct dependencies, as passed to rustc by cargo. This is
+//!     exact, but other crates, like build-dependencies, can't be used.
+//!   * `depinfo` also links the rlibs in the deps dir of the crate's dependencies, as declared in
+//!     its manifest, whose versions in the dep-info written beside them are compatible. This
+//!     skips stale versions, but dependencies without a version requirement, like `path`
+//!     dependencies, are only found if they're passed to rustc.
+//!   * `scan` links the highest version of every crate in the deps dir, which finds crates that are
+//!     only built for other crates in the workspace, but may be of the wrong version.
+//!   * `auto`, the default, links what `depinfo` does, and then what `scan` does of the other
+//!     crates. So a crate of a compatible version is preferred, but a crate without one, or
+//!     without a version requirement, is still found.
+//!
+//!   The crates that comptime itself needs, `quote` and `proc-macro2`, are always linked.
+//! * `COMPTIME_GOLDEN_DIR=dir` checks the formatted result of each comptime program against its
+//!   golden file in `dir`, relative to the crate's root, and fails the build with a diff if they
+//!   differ. This lets libraries that generate code with comptime snapshot test it. Golden files
+//!   are named `<crate name>/<hash>.rs`, by a stable hash of the program's text, so changing the
+//!   program requires a new golden file, as may a compiler upgrade that renders its tokens
+//!   differently. They're written, rather than checked, with `COMPTIME_UPDATE_GOLDEN=1`.
+//! * `COMPTIME_JSON_DIAGNOSTICS=1`, with the `json` feature, renders the errors from compiling the
+//!   comptime program from rustc's JSON diagnostics, as just each error's message, labels, and the
+//!   lines of source that they point at, for tools that surface the `compile_error!`. Otherwise,
+//!   they're rustc's own rendering.
+//! * `COMPTIME_KEEP=1` keeps the comptime program's source in the out dir, even if it fails to
+//!   compile, and prints its path. It also writes the formatted result of each invocation to
+//!   `<out dir>/comptime-results/<crate name>/<source file>/<line>-<column>.rs`, where the line
+//!   (1-based) and column (0-based) are those of the invocation. Editor tooling, which can't run
+//!   comptime programs itself, can read these files to show the last-known result.
+//! * `COMPTIME_LINKER=path` links the comptime program with the linker at `path`. Otherwise, the
+//!   crate's `-C linker` is used, unless it's being cross-compiled, in which case the crate's
+//!   linker is for the target and the default one is used.
+//! * `COMPTIME_LOG=kinds` prints the comma-separated kinds of diagnostics to rustc's stderr,
+//!   which cargo shows:
+//!   * `summary` prints how many comptime invocations there were in each crate, and how long
+//!     they took in total, once rustc is done with the crate.
+//!   * `commands` prints each rustc command that compiles a comptime program, and each command
+//!     that runs one.
+//!   * `stderr` prints the stderr of comptime programs that succeed, as a warning per line, and
+//!     comptime's own warnings, even in dependencies. In the crates that cargo was asked to
+//!     build, they're always printed, and the stderr of programs that fail is always shown, in
+//!     the error.
+//! * `COMPTIME_MAX_OUTPUT=bytes` fails the build if the comptime program prints more than `bytes`
+//!   of output, which guards against runaway generators. The program's stdout is closed once the
+//!   cap is exceeded.
+//! * `COMPTIME_MEM_LIMIT_MB=mb` limits the address space of the comptime program to `mb`
+//!   megabytes, so that a runaway generator fails the build rather than exhausting the machine's
+//!   memory. Since this limits address space rather than resident memory, it should be generous.
+//!   This is a no-op on platforms other than Linux.
+//! * `COMPTIME_PURE=1` checks that comptime programs have no side effects, so that their results
+//!   are safe to cache, by running them under `COMPTIME_SANDBOX`, which is required. A program
+//!   that fails under the sandbox fails the build. How much is enforced depends entirely on the
+//!   sandbox.
+//! * `COMPTIME_RLIB_WAIT=secs` is how long the crate's build waits for a dependency's rlib, 30
+//!   seconds by default. Cargo starts building the crate once its dependencies' metadata is
+//!   written, but comptime programs link to their rlibs, which are written later, e.g. after a
+//!   slow codegen. The build fails if an rlib isn't written in time.
+//! * `COMPTIME_SANDBOX=command` runs comptime programs under `command`, split on whitespace, with
+//!   the program's path appended, in the same way as cargo's `target.<triple>.runner`. For
+//!   example, `unshare --map-root-user --net` denies programs network access on Linux, and a
+//!   sandbox like bubblewrap can also restrict which files they can access.
+//! * `COMPTIME_STACK_MB=mb` runs comptime programs on a thread with a stack of `mb` megabytes, 64
+//!   by default, which is far more than a main thread's, so that deeply recursive programs don't
+//!   overflow it. The stack counts towards `COMPTIME_MEM_LIMIT_MB`. A `COMPTIME_DRIVER_TEMPLATE`
+//!   gets this by calling `comptime::with_stack(|| { .. })` in its `main`, as the default one
+//!   does, and `@no_std` programs run on the main thread.
+//! * `COMPTIME_STDIN=path` is the file read by `@stdin` when no path is given.
+//! * `COMPTIME_STRICT_PERMS=1` refuses to build or run the comptime program if the out dir or
+//!   deps dir is writable by others. This is a no-op on platforms without Unix permissions.
+//! * `COMPTIME_TIMEOUT=secs` kills a comptime program that runs for longer than `secs` seconds,
+//!   60 by default, which fails the build rather than hanging it on an accidental infinite loop.
+//!   `COMPTIME_TIMEOUT=0` lets programs run for as long as they like. A program's `@timeout` takes
+//!   precedence.
+//! * `RUSTC=path` and `RUSTFMT=path` are the rustc and rustfmt that comptime invokes, e.g. a
+//!   toolchain shim, instead of those on the `PATH`.
+//!
+//! Comptime programs are built in rustc's out dir. If it can't be written, as in some sandboxes
+//! with read-only outputs, they're built in the system's temp dir instead, along with their
+//! other artifacts, but their dependencies are still found in the out dir. That dir is private to
+//! the user, and one that someone else created first is refused rather than used.
 
 extern crate proc_macro;
 
+mod cache;
+mod deps;
+mod golden;
+#[cfg(feature = "json")]
+mod json;
+mod run;
+mod summary;
+#[cfg(test)]
+mod testing;
+
 use std::{
-    collections::{
-        hash_map::{DefaultHasher, Entry},
-        HashMap,
-    },
+    collections::{btree_map::Entry, hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
     hash::{Hash, Hasher},
-    path::Path,
-    process::Command,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
 };
 
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens, TokenStreamExt};
 use syn::{
     parse::{Parse, ParseStream},
+    spanned::Spanned,
     ItemFn,
 };
 
+/// Returns a `compile_error!` with the formatted message from the current `Result`-returning fn.
 macro_rules! err {
     ($fstr:literal$(,)? $( $arg:expr ),*) => {{
         let compile_error = format!($fstr, $($arg),*);
-        return TokenStream::from(quote!(compile_error!(#compile_error)));
+        return Err(quote!(compile_error!(#compile_error)));
     }};
 }
 
@@ -62,10 +724,37 @@ struct BlockInner {
 
 impl Parse for BlockInner {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        Ok(Self {
-            stmts: syn::Block::parse_within(input)?,
-        })
+        check_metavariables(input.fork().parse()?)?;
+        let stmts = syn::Block::parse_within(input).map_err(|e| {
+            syn::Error::new(
+                e.span(),
+                format!(
+                    "comptime program is not a valid sequence of statements: {}",
+                    e
+                ),
+            )
+        })?;
+        Ok(Self { stmts })
+    }
+}
+
+/// Rejects `$crate`, which appears when `comptime!` is invoked by a `macro_rules!` macro. The
+/// comptime program is compiled as its own crate, so `$crate` can't mean anything in it.
+fn check_metavariables(tokens: proc_macro2::TokenStream) -> syn::Result<()> {
+    for token in tokens {
+        match token {
+            proc_macro2::TokenTree::Ident(ident) if ident == "$crate" => {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    "unsupported macro metavariable `$crate` in comptime program: the program is \
+                     compiled as its own crate, so refer to crates by name instead",
+                ))
+            }
+            proc_macro2::TokenTree::Group(group) => check_metavariables(group.stream())?,
+            _ => {}
+        }
     }
+    Ok(())
 }
 
 impl ToTokens for BlockInner {
@@ -74,8 +763,373 @@ impl ToTokens for BlockInner {
     }
 }
 
+/// Options set by `@directive`s preceding the comptime program, e.g. `comptime!(@include { .. })`.
+#[derive(Default)]
+struct Directives {
+    /// Emit the result as an `include!` of a formatted file rather than inline tokens.
+    include: bool,
+    /// Connect the comptime program's stdin to a file rather than inheriting it.
+    stdin: bool,
+    /// The file given as `@stdin("path")`, which takes precedence over `COMPTIME_STDIN`.
+    stdin_path: Option<String>,
+    /// Provide the compilation target's layout to the program as `comptime::target`.
+    target_layout: bool,
+    /// Environment variables that the program's output depends on, set by `@env_key(VAR, ..)`.
+    env_keys: Vec<String>,
+    /// The edition to compile the program with instead of the crate's, set by `@edition(2024)`.
+    edition: Option<String>,
+    /// Strip the type suffixes of the result's literals, set by `@suffix(false)`.
+    strip_suffixes: bool,
+    /// How many seconds the program may run, set by `@timeout(120)`, overriding `COMPTIME_TIMEOUT`.
+    timeout_secs: Option<u64>,
+    /// `--cfg`s that replace the crate's cfgs of the same names, set by `@cfg("feature=\"x\"")`.
+    cfgs: Vec<String>,
+    /// Run the program as the body of a `try` block, so that it can use `?`, set by `@try`.
+    fallible: bool,
+    /// The primitive numeric type to convert the result to, set by `@cast(usize)`.
+    cast: Option<syn::Ident>,
+    /// Where errors in the result are reported, set by `@span(token)` to the span of `token`.
+    span: Option<proc_macro2::Span>,
+    /// Run the program once with each of these arguments, as `comptime::arg()`, and emit an array
+    /// of the results, set by `comptime_map!`.
+    run_args: Option<Vec<String>>,
+    /// Emit the result as an `include!` if it's longer than this many bytes.
+    include_above: Option<usize>,
+    /// Build the program with only `core`, using `DRIVER_NO_STD`.
+    no_std: bool,
+    /// With `no_std`, also link `alloc`, with libc's allocator, set by `@no_std(alloc)`.
+    no_std_alloc: bool,
+    /// The type of the result, set by `comptime!(as u32 { .. })`.
+    result_type: Option<ResultType>,
+    /// Write the result to a file in the out dir and emit a `mod` of it, set by
+    /// `#[comptime_fn(module = "generated")]`.
+    module: Option<Module>,
+}
+
+/// The module that `#[comptime_fn(module = "a::b")]` writes its result to: `mod b`, whose file is
+/// `<out dir>/comptime-modules/<crate name>/a/b.rs`.
+struct Module {
+    path: Vec<syn::Ident>,
+    /// The attributes and visibility of the `mod`, which are the function's.
+    attrs: Vec<syn::Attribute>,
+    vis: syn::Visibility,
+}
+
+/// A result type given to `comptime!(as Type { .. })` or `comptime_as!`, which must be that of
+/// some kind of literal.
+struct ResultType {
+    ty: syn::Type,
+    kind: LitKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LitKind {
+    Int,
+    Float,
+    Bool,
+    Char,
+    Str,
+}
+
+impl LitKind {
+    fn of(lit: &syn::Lit) -> Option<Self> {
+        Some(match lit {
+            syn::Lit::Int(_) => Self::Int,
+            syn::Lit::Float(_) => Self::Float,
+            syn::Lit::Bool(_) => Self::Bool,
+            syn::Lit::Char(_) => Self::Char,
+            syn::Lit::Str(_) => Self::Str,
+            _ => return None,
+        })
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Int => "an integer",
+            Self::Float => "a float",
+            Self::Bool => "a `bool`",
+            Self::Char => "a `char`",
+            Self::Str => "a string",
+        }
+    }
+}
+
+impl Parse for ResultType {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ty: syn::Type = input.parse()?;
+        let kind = match &ty {
+            syn::Type::Reference(reference) if reference.mutability.is_none() => {
+                match &*reference.elem {
+                    syn::Type::Path(path) if path.path.is_ident("str") => Some(LitKind::Str),
+                    _ => None,
+                }
+            }
+            syn::Type::Path(path) => path.path.get_ident().and_then(|ident| {
+                Some(match ident.to_string().as_str() {
+                    "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32"
+                    | "u64" | "u128" | "usize" => LitKind::Int,
+                    "f32" | "f64" => LitKind::Float,
+                    "bool" => LitKind::Bool,
+                    "char" => LitKind::Char,
+                    _ => return None,
+                })
+            }),
+            _ => None,
+        };
+        match kind {
+            Some(kind) => Ok(Self { ty, kind }),
+            None => Err(syn::Error::new_spanned(
+                ty,
+                "comptime result type must be a primitive integer or float, `bool`, `char`, or \
+                 `&str`",
+            )),
+        }
+    }
+}
+
+/// Returns the kind of literal, possibly negated, that a result is, if it's one. A path is taken
+/// for a non-finite float, e.g. `f64::NAN`, if a float is `expected`.
+fn result_lit_kind(result: &proc_macro2::TokenStream, expected: LitKind) -> Option<LitKind> {
+    match syn::parse2(result.clone()) {
+        Ok(syn::Expr::Lit(lit)) => LitKind::of(&lit.lit),
+        Ok(syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        })) => match *expr {
+            syn::Expr::Lit(lit) => LitKind::of(&lit.lit),
+            _ => None,
+        },
+        Ok(syn::Expr::Path(_)) if expected == LitKind::Float => Some(LitKind::Float),
+        _ => None,
+    }
+}
+
+/// Parses the `(VAR, ..)` of `@env_key` and `#[comptime_fn(env_key)]`.
+fn parse_env_keys(input: ParseStream) -> syn::Result<Vec<String>> {
+    let keys;
+    syn::parenthesized!(keys in input);
+    let keys = keys.parse_terminated::<_, syn::Token![,]>(syn::Ident::parse)?;
+    Ok(keys.iter().map(ToString::to_string).collect())
+}
+
+/// Parses the edition of `@edition(2024)` and `#[comptime_fn(edition = "2024")]`, which may be a
+/// year or a string.
+fn parse_edition(input: ParseStream) -> syn::Result<String> {
+    match input.parse()? {
+        syn::Lit::Int(year) => Ok(year.base10_digits().to_string()),
+        syn::Lit::Str(name) => Ok(name.value()),
+        edition => Err(syn::Error::new(edition.span(), "expected an edition")),
+    }
+}
+
+/// Parses the seconds of `@timeout(120)` and `#[comptime_fn(timeout_secs = 120)]`.
+fn parse_timeout(input: ParseStream) -> syn::Result<u64> {
+    let secs: syn::LitInt = input.parse()?;
+    match secs.base10_parse() {
+        Ok(0) | Err(_) => Err(syn::Error::new(
+            secs.span(),
+            "comptime timeout must be a positive number of seconds",
+        )),
+        Ok(secs) => Ok(secs),
+    }
+}
+
+/// Parses the `("name=\"value\"", ..)` of `@cfg`, which must be valid `--cfg` specs.
+fn parse_cfgs(input: ParseStream) -> syn::Result<Vec<String>> {
+    let cfgs;
+    syn::parenthesized!(cfgs in input);
+    let cfgs = cfgs.parse_terminated::<_, syn::Token![,]>(<syn::LitStr as Parse>::parse)?;
+    cfgs.iter()
+        .map(|cfg| match cfg_name(&cfg.value()) {
+            Some(_) => Ok(cfg.value()),
+            None => Err(syn::Error::new(
+                cfg.span(),
+                "expected a cfg like `name` or `name=\"value\"`",
+            )),
+        })
+        .collect()
+}
+
+struct ComptimeInput {
+    directives: Directives,
+    program: BlockInner,
+}
+
+impl Parse for ComptimeInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut directives = Directives::default();
+        if input.peek(syn::Token![as]) {
+            input.parse::<syn::Token![as]>()?;
+            directives.result_type = Some(input.parse()?);
+        } else if !input.peek(syn::Token![@]) {
+            return Ok(Self {
+                directives,
+                program: input.parse()?,
+            });
+        }
+
+        while input.peek(syn::Token![@]) {
+            input.parse::<syn::Token![@]>()?;
+            // `try` is a keyword, and so not an identifier.
+            if input.parse::<Option<syn::Token![try]>>()?.is_some() {
+                directives.fallible = true;
+                continue;
+            }
+            let name: syn::Ident = input.parse()?;
+            match name.to_string().as_str() {
+                "include" => directives.include = true,
+                "target_layout" => directives.target_layout = true,
+                "no_std" => {
+                    directives.no_std = true;
+                    if input.peek(syn::token::Paren) {
+                        let krate;
+                        syn::parenthesized!(krate in input);
+                        let krate: syn::Ident = krate.parse()?;
+                        if krate != "alloc" {
+                            return Err(syn::Error::new(krate.span(), "expected `alloc`"));
+                        }
+                        directives.no_std_alloc = true;
+                    }
+                }
+                "env_key" => directives.env_keys.extend(parse_env_keys(input)?),
+                "edition" => {
+                    let edition;
+                    syn::parenthesized!(edition in input);
+                    directives.edition = Some(parse_edition(&edition)?);
+                }
+                "cfg" => directives.cfgs.extend(parse_cfgs(input)?),
+                "suffix" => {
+                    let suffix;
+                    syn::parenthesized!(suffix in input);
+                    directives.strip_suffixes = !suffix.parse::<syn::LitBool>()?.value;
+                }
+                "cast" => {
+                    let ty;
+                    syn::parenthesized!(ty in input);
+                    let ty: syn::Ident = ty.parse()?;
+                    if int_bits(&ty.to_string(), 64).is_none() && ty != "f32" && ty != "f64" {
+                        return Err(syn::Error::new(
+                            ty.span(),
+                            "`@cast` takes a primitive integer or float type",
+                        ));
+                    }
+                    directives.cast = Some(ty);
+                }
+                "span" => {
+                    let token;
+                    syn::parenthesized!(token in input);
+                    directives.span = Some(token.parse::<proc_macro2::TokenTree>()?.span());
+                }
+                "timeout" => {
+                    let secs;
+                    syn::parenthesized!(secs in input);
+                    directives.timeout_secs = Some(parse_timeout(&secs)?);
+                }
+                "stdin" => {
+                    directives.stdin = true;
+                    if input.peek(syn::token::Paren) {
+                        let path;
+                        syn::parenthesized!(path in input);
+                        directives.stdin_path = Some(path.parse::<syn::LitStr>()?.value());
+                    }
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("unknown comptime directive `@{}`", name),
+                    ))
+                }
+            }
+        }
+
+        let program;
+        syn::braced!(program in input);
+        let program = program.parse()?;
+        if !input.is_empty() {
+            return Err(input.error("unexpected tokens after the braced comptime program"));
+        }
+        Ok(Self {
+            directives,
+            program,
+        })
+    }
+}
+
+/// The arguments of `#[comptime_fn(..)]`, which are directives without the `@`.
+struct ComptimeFnArgs(Directives);
+
+impl Parse for ComptimeFnArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut directives = Directives::default();
+        while !input.is_empty() {
+            if input.parse::<Option<syn::Token![try]>>()?.is_some() {
+                directives.fallible = true;
+                if !input.is_empty() {
+                    input.parse::<syn::Token![,]>()?;
+                }
+                continue;
+            }
+            let name: syn::Ident = input.parse()?;
+            match name.to_string().as_str() {
+                "env_key" => directives.env_keys.extend(parse_env_keys(input)?),
+                "timeout_secs" => {
+                    input.parse::<syn::Token![=]>()?;
+                    directives.timeout_secs = Some(parse_timeout(input)?);
+                }
+                "edition" => {
+                    input.parse::<syn::Token![=]>()?;
+                    directives.edition = Some(parse_edition(input)?);
+                }
+                "module" => {
+                    input.parse::<syn::Token![=]>()?;
+                    let path: syn::LitStr = input.parse()?;
+                    directives.module = Some(Module {
+                        path: parse_module_path(&path)?,
+                        attrs: Vec::new(),
+                        vis: syn::Visibility::Inherited,
+                    });
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        format!("unknown comptime_fn argument `{}`", name),
+                    ))
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(Self(directives))
+    }
+}
+
+/// Parses the module path of `#[comptime_fn(module = "a::b")]`, which is a path of identifiers.
+fn parse_module_path(path: &syn::LitStr) -> syn::Result<Vec<syn::Ident>> {
+    let invalid = || {
+        syn::Error::new(
+            path.span(),
+            "expected a module path like `generated::tables`",
+        )
+    };
+    let parsed: syn::Path = path.parse().map_err(|_| invalid())?;
+    if parsed.leading_colon.is_some() {
+        return Err(invalid());
+    }
+    parsed
+        .segments
+        .into_iter()
+        .map(|segment| match segment.arguments {
+            syn::PathArguments::None => Ok(segment.ident),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
 #[proc_macro_attribute]
-pub fn comptime_fn(_args: TokenStream, item: TokenStream) -> TokenStream {
+pub fn comptime_fn(args: TokenStream, item: TokenStream) -> TokenStream {
+    let ComptimeFnArgs(mut directives) = syn::parse_macro_input!(args as ComptimeFnArgs);
     let input = syn::parse_macro_input!(item as ItemFn);
 
     let ItemFn {
@@ -88,7 +1142,18 @@ pub fn comptime_fn(_args: TokenStream, item: TokenStream) -> TokenStream {
         // Other attributes applied to this function
         attrs,
     } = input;
-    let result: proc_macro2::TokenStream = comptime(block.to_token_stream().into()).into();
+    if let Err(e) = check_metavariables(block.to_token_stream()) {
+        return e.to_compile_error().into();
+    }
+    let program = BlockInner { stmts: block.stmts };
+    if let Some(module) = &mut directives.module {
+        module.attrs = attrs;
+        module.vis = vis;
+        return evaluate(&program, &directives)
+            .unwrap_or_else(|compile_error| quote!(#compile_error;))
+            .into();
+    }
+    let result = evaluate(&program, &directives).unwrap_or_else(|compile_error| compile_error);
     quote::quote!(
         #(#attrs)*
         #vis #sig {
@@ -98,176 +1163,5263 @@ pub fn comptime_fn(_args: TokenStream, item: TokenStream) -> TokenStream {
     .into()
 }
 
+/// `#[comptime_doc { .. }]` documents the item that it's applied to with the result of the
+/// comptime program, which must be a string, as `#[doc = ".."]`.
+#[proc_macro_attribute]
+pub fn comptime_doc(args: TokenStream, item: TokenStream) -> TokenStream {
+    let ComptimeInput {
+        directives,
+        program,
+    } = syn::parse_macro_input!(args as ComptimeInput);
+    let item = proc_macro2::TokenStream::from(item);
+    let doc = match evaluate(&program, &directives) {
+        Ok(doc) => doc,
+        Err(compile_error) => return quote!(#compile_error; #item).into(),
+    };
+    match syn::parse2(doc.clone()).map(untrack) {
+        Ok((
+            tracking,
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(doc),
+                ..
+            }),
+        )) => quote!(#(#tracking)* #[doc = #doc] #item),
+        _ => {
+            let message = format!("comptime_doc result is not a string literal: `{}`", doc);
+            quote!(compile_error!(#message); #item)
+        }
+    }
+    .into()
+}
+
+/// `comptime! { .. }` runs the program, which is a sequence of statements ending in an
+/// expression, at compile time and expands to its result. A malformed program is rejected before
+/// it's run:
+///
+/// ```ignore
+/// let sum = comptime::comptime!(let x = ; x + 1);
+/// ```
 #[proc_macro]
 pub fn comptime(input: TokenStream) -> TokenStream {
-    let args: Vec<_> = std::env::args().collect();
-    let get_arg = |arg| {
-        args.iter()
-            .position(|a| a == arg)
-            .and_then(|p| args.get(p + 1))
-    };
+    let ComptimeInput {
+        directives,
+        program,
+    } = syn::parse_macro_input!(input as ComptimeInput);
+    evaluate(&program, &directives)
+        .unwrap_or_else(|compile_error| compile_error)
+        .into()
+}
 
-    let comptime_program = syn::parse_macro_input!(input as BlockInner);
+/// `comptime_as!(Type, { .. })` is `comptime!(as Type { .. })`.
+#[proc_macro]
+pub fn comptime_as(input: TokenStream) -> TokenStream {
+    let AsInput { ty, input } = syn::parse_macro_input!(input as AsInput);
+    let ComptimeInput {
+        mut directives,
+        program,
+    } = input;
+    directives.result_type = Some(ty);
+    evaluate(&program, &directives)
+        .unwrap_or_else(|compile_error| compile_error)
+        .into()
+}
 
-    let out_dir = match get_arg("--out-dir") {
-        Some(out_dir) => Path::new(out_dir),
-        None => {
-            err!("comptime failed: could not determine rustc out dir.");
-        }
-    };
+/// `Type, <comptime program>`
+struct AsInput {
+    ty: ResultType,
+    input: ComptimeInput,
+}
 
-    let comptime_program_str = comptime_program.to_token_stream().to_string();
-    let mut hasher = DefaultHasher::new();
-    comptime_program_str.hash(&mut hasher);
-    let comptime_disambiguator = hasher.finish();
+impl Parse for AsInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ty = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        Ok(Self {
+            ty,
+            input: input.parse()?,
+        })
+    }
+}
 
-    let comptime_rs = out_dir.join(format!("comptime-{}.rs", comptime_disambiguator));
-    std::fs::write(
-        &comptime_rs,
-        format!(
-            r#"fn main() {{
-                    let comptime_output = {{ {} }};
-                    print!("{{}}", quote::quote!(#comptime_output));
-                }}"#,
-            comptime_program_str
-        ),
-    )
-    .expect("could not write comptime.rs");
-    Command::new("rustfmt").arg(&comptime_rs).output().ok();
+/// `comptime_unsafe!(..)` is `comptime!(..)` wrapped in an `unsafe` block, for results that
+/// call unsafe functions, e.g. `std::mem::transmute`.
+#[proc_macro]
+pub fn comptime_unsafe(input: TokenStream) -> TokenStream {
+    let ComptimeInput {
+        directives,
+        program,
+    } = syn::parse_macro_input!(input as ComptimeInput);
+    match evaluate(&program, &directives) {
+        Ok(tokens) => quote!(unsafe { #tokens }),
+        Err(compile_error) => compile_error,
+    }
+    .into()
+}
 
-    let mut rustc_args = filter_rustc_args(&args);
-    rustc_args.push("--crate-name".to_string());
-    rustc_args.push("comptime_bin".to_string());
-    rustc_args.push("--crate-type".to_string());
-    rustc_args.push("bin".to_string());
-    rustc_args.push("--emit=dep-info,link".to_string());
-    rustc_args.append(&mut merge_externs(out_dir, &args));
-    rustc_args.push(comptime_rs.to_str().unwrap().to_string());
+/// `comptime_cstr!(..)` is `comptime!(as &str ..)` emitted as a `&'static CStr`, which fails to
+/// compile if the string has an interior NUL.
+#[proc_macro]
+pub fn comptime_cstr(input: TokenStream) -> TokenStream {
+    let ComptimeInput {
+        mut directives,
+        program,
+    } = syn::parse_macro_input!(input as ComptimeInput);
+    directives.result_type = Some(syn::parse_quote!(&str));
+    let result = match evaluate(&program, &directives) {
+        Ok(result) => result,
+        Err(compile_error) => return compile_error.into(),
+    };
+    let args = expand_argfiles(std::env::args()).unwrap_or_default();
+    let cstr = match syn::parse2(result.clone()).map(untrack) {
+        Ok((
+            tracking,
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(lit),
+                ..
+            }),
+        )) => cstr_literal(&lit, parent_edition(&args)).map(|cstr| quote!({ #(#tracking)* #cstr })),
+        _ => Err(format!(
+            "comptime_cstr result is not a string literal: `{}`",
+            result
+        )),
+    };
+    match cstr {
+        Ok(cstr) => cstr,
+        Err(message) => quote!(compile_error!(#message)),
+    }
+    .into()
+}
 
-    let compile_output = Command::new("rustc")
-        .args(&rustc_args)
-        .output()
-        .expect("could not invoke rustc");
-    if !compile_output.status.success() {
-        err!(
-            "could not compile comptime expr:\n\n{}\n",
-            String::from_utf8(compile_output.stderr).unwrap()
-        );
+/// Returns `lit` as a `&'static CStr`: a `c".."` literal in `edition` 2021 and later, or else a
+/// `CStr` of a byte string with a trailing NUL.
+fn cstr_literal(lit: &syn::LitStr, edition: &str) -> Result<proc_macro2::TokenStream, String> {
+    let value = lit.value();
+    if let Some(nul) = value.find('\0') {
+        return Err(format!(
+            "comptime_cstr result has an interior NUL at byte {}: {:?}",
+            nul, value
+        ));
     }
+    let value = std::ffi::CString::new(value).unwrap();
+    if edition >= "2021" {
+        let mut cstr = proc_macro2::Literal::c_string(&value);
+        cstr.set_span(lit.span());
+        return Ok(cstr.into_token_stream());
+    }
+    let bytes = syn::LitByteStr::new(value.as_bytes_with_nul(), lit.span());
+    Ok(quote! {
+        match ::core::ffi::CStr::from_bytes_with_nul(#bytes) {
+            Ok(cstr) => cstr,
+            Err(_) => panic!(),
+        }
+    })
+}
 
-    let extra_filename = args
-        .iter()
-        .find(|a| a.starts_with("extra-filename="))
-        .map(|ef| ef.split('=').nth(1).unwrap())
-        .unwrap_or_default();
-    let comptime_bin = out_dir.join(format!("comptime_bin{}", extra_filename));
+/// Returns the edition of the crate that's being compiled with `args`.
+fn parent_edition(args: &[String]) -> &str {
+    args.iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.strip_prefix("--edition") {
+            Some("") => args.get(i + 1).map(String::as_str),
+            Some(edition) => edition.strip_prefix('='),
+            None => None,
+        })
+        .unwrap_or("2015")
+}
 
-    let comptime_output = Command::new(&comptime_bin)
-        .output()
-        .expect("could not invoke comptime_bin");
+/// `comptime_lazy!(Type, { .. })` is a `once_cell::sync::Lazy<Type>`, if the crate depends on
+/// `once_cell`, or else a `std::sync::LazyLock<Type>`, that's initialized with the result of the
+/// comptime program, for statics of types that can't be built in a `const`.
+#[proc_macro]
+pub fn comptime_lazy(input: TokenStream) -> TokenStream {
+    let LazyInput {
+        ty,
+        input: ComptimeInput {
+            directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as LazyInput);
+    let tokens = match evaluate(&program, &directives) {
+        Ok(tokens) => tokens,
+        Err(compile_error) => return compile_error.into(),
+    };
+    let args = expand_argfiles(std::env::args()).unwrap_or_default();
+    match has_extern(&args, "once_cell") {
+        true => quote!(::once_cell::sync::Lazy::<#ty>::new(|| #tokens)),
+        false => quote!(::std::sync::LazyLock::<#ty>::new(|| #tokens)),
+    }
+    .into()
+}
 
-    if !comptime_output.status.success() {
-        err!(
-            "could not run comptime expr:\n\n{}\n",
-            String::from_utf8(comptime_output.stderr).unwrap()
-        );
+/// `Type, <comptime program>`
+struct LazyInput {
+    ty: syn::Type,
+    input: ComptimeInput,
+}
+
+impl Parse for LazyInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ty = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        Ok(Self {
+            ty,
+            input: input.parse()?,
+        })
     }
+}
 
-    let comptime_expr_str = match String::from_utf8(comptime_output.stdout) {
-        Ok(output) => output,
-        Err(_) => err!("comptime expr output was not utf8"),
+/// `comptime_toml!(Type, "path", { .. })` parses the TOML file at `path`, relative to the crate's
+/// root, as a `Type` with the crate's `toml` dependency and emits it with `comptime::Emit`. The
+/// braced items define `Type` for the comptime program, and the crate is rebuilt when the file
+/// changes.
+#[proc_macro]
+pub fn comptime_toml(input: TokenStream) -> TokenStream {
+    comptime_config(input, "comptime_toml!", "toml")
+}
+
+/// `comptime_yaml!(Type, "path", { .. })` is `comptime_toml!` for a YAML file, which is parsed
+/// with the crate's `serde_yaml` dependency.
+#[proc_macro]
+pub fn comptime_yaml(input: TokenStream) -> TokenStream {
+    comptime_config(input, "comptime_yaml!", "serde_yaml")
+}
+
+/// `comptime_shared!("key")` expands to the value of `key` in the JSON object that the crate's
+/// build script wrote to `$OUT_DIR/comptime_shared.json`, as a literal. It requires the `json`
+/// feature.
+#[proc_macro]
+pub fn comptime_shared(input: TokenStream) -> TokenStream {
+    let key = syn::parse_macro_input!(input as syn::LitStr);
+    match shared_value(&key.value()) {
+        Ok(value) => value,
+        Err(e) => {
+            let message = format!("comptime_shared!({:?}) failed: {}", key.value(), e);
+            quote!(compile_error!(#message))
+        }
+    }
+    .into()
+}
+
+/// Returns the value of `key` in `$OUT_DIR/comptime_shared.json`, and tracks the file.
+fn shared_value(key: &str) -> Result<proc_macro2::TokenStream, String> {
+    #[cfg(not(feature = "json"))]
+    return Err(format!(
+        "reading `{}` requires the `json` feature of comptime",
+        key
+    ));
+    #[cfg(feature = "json")]
+    {
+        let Some(out_dir) = std::env::var_os("OUT_DIR") else {
+            return Err("OUT_DIR is not set, since the crate has no build script".to_string());
+        };
+        let path = Path::new(&out_dir).join("comptime_shared.json");
+        let shared = std::fs::read_to_string(&path)
+            .map_err(|e| format!("could not read `{}`: {}", path.display(), e))?;
+        let value = json::render_shared(&shared, key)
+            .map_err(|e| format!("`{}`: {}", path.display(), e))?;
+        let path = path.display().to_string();
+        Ok(quote!({
+            const _: &[u8] = include_bytes!(#path);
+            #value
+        }))
+    }
+}
+
+/// Expands the config macro `name`, which parses the file with `krate::from_str`.
+fn comptime_config(input: TokenStream, name: &str, krate: &str) -> TokenStream {
+    let ConfigInput { ty, path, items } = syn::parse_macro_input!(input as ConfigInput);
+    let args = expand_argfiles(std::env::args()).unwrap_or_default();
+    if !has_extern(&args, krate) {
+        let message = format!("{} requires the crate to depend on `{}`", name, krate);
+        return quote!(compile_error!(#message)).into();
+    }
+    let krate = syn::Ident::new(krate, proc_macro2::Span::call_site());
+    let program = quote!(
+        #items
+        comptime::config::<#ty, _>(#path, |text| ::#krate::from_str(text))
+    );
+    let program = match syn::parse2(program) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
     };
-    let comptime_expr: syn::Expr = match syn::parse_str(&comptime_expr_str) {
-        Ok(expr) => expr,
-        Err(_) => syn::ExprLit {
-            attrs: Vec::new(),
-            lit: syn::LitStr::new(&comptime_expr_str, proc_macro2::Span::call_site()).into(),
+    evaluate(&program, &Directives::default())
+        .unwrap_or_else(|compile_error| compile_error)
+        .into()
+}
+
+/// `Type, "path", { items }`
+struct ConfigInput {
+    ty: syn::Type,
+    path: syn::LitStr,
+    items: proc_macro2::TokenStream,
+}
+
+impl Parse for ConfigInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ty = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let path = input.parse()?;
+        let mut items = proc_macro2::TokenStream::new();
+        if !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+            let braced;
+            syn::braced!(braced in input);
+            items = braced.parse()?;
+            input.parse::<Option<syn::Token![,]>>()?;
         }
-        .into(),
+        Ok(Self { ty, path, items })
+    }
+}
+
+/// `comptime_map!(name: Type in args { .. })` runs the comptime program once for each of `args`,
+/// an array of literals or a range of at most 4096 integers, with `name` bound to it, and
+/// evaluates to an array of the results. The program is compiled only once.
+#[proc_macro]
+pub fn comptime_map(input: TokenStream) -> TokenStream {
+    let MapInput {
+        name,
+        ty,
+        args,
+        input: ComptimeInput {
+            mut directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as MapInput);
+    let program = match syn::parse2(quote!(let #name: #ty = comptime::arg(); { #program })) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
     };
+    directives.run_args = Some(args);
+    evaluate(&program, &directives)
+        .unwrap_or_else(|compile_error| compile_error)
+        .into()
+}
 
-    std::fs::remove_file(comptime_rs).ok();
-    std::fs::remove_file(comptime_bin).ok();
+/// `name: Type in args <comptime program>`
+struct MapInput {
+    name: syn::Ident,
+    ty: syn::Type,
+    args: Vec<String>,
+    input: ComptimeInput,
+}
 
-    TokenStream::from(comptime_expr.to_token_stream())
+impl Parse for MapInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<syn::Token![:]>()?;
+        let ty = input.parse()?;
+        input.parse::<syn::Token![in]>()?;
+        let args = syn::Expr::parse_without_eager_brace(input)?;
+        Ok(Self {
+            name,
+            ty,
+            args: parse_map_args(&args)?,
+            input: input.parse()?,
+        })
+    }
 }
 
-/// Returns the rustc args needed to build the comptime executable.
-fn filter_rustc_args(args: &[String]) -> Vec<String> {
-    let mut rustc_args = Vec::with_capacity(args.len());
-    let mut skip = true; // skip the invoked program
-    for arg in args {
-        if skip {
-            skip = false;
-            continue;
+/// The most arguments that a range passed to `comptime_map!` may have.
+const MAX_MAP_ARGS: usize = 4096;
+
+/// Parses the arguments of `comptime_map!`, which are passed to the program as text: an array of
+/// literals, like `["a", "b"]`, or a range of integers, like `0..8`.
+fn parse_map_args(args: &syn::Expr) -> syn::Result<Vec<String>> {
+    use syn::{Expr, Lit, UnOp};
+    fn literal(expr: &Expr) -> syn::Result<String> {
+        let (negative, lit) = match expr {
+            Expr::Lit(lit) => (false, &lit.lit),
+            Expr::Unary(unary) if matches!(unary.op, UnOp::Neg(_)) => match &*unary.expr {
+                Expr::Lit(lit) => (true, &lit.lit),
+                _ => return Err(syn::Error::new_spanned(expr, "expected a literal")),
+            },
+            _ => return Err(syn::Error::new_spanned(expr, "expected a literal")),
+        };
+        let text = match lit {
+            Lit::Int(int) => int.base10_digits().to_string(),
+            Lit::Float(float) => float.base10_digits().to_string(),
+            Lit::Str(s) if !negative => s.value(),
+            Lit::Char(c) if !negative => c.value().to_string(),
+            Lit::Bool(b) if !negative => b.value.to_string(),
+            _ => return Err(syn::Error::new_spanned(expr, "expected a literal")),
+        };
+        Ok(match negative {
+            true => format!("-{}", text),
+            false => text,
+        })
+    }
+    match args {
+        Expr::Array(array) => array.elems.iter().map(literal).collect(),
+        Expr::Range(range) => {
+            let bound = |bound: Option<&Expr>| -> syn::Result<i128> {
+                let bound = bound.ok_or_else(|| {
+                    syn::Error::new_spanned(range, "expected a range with both bounds")
+                })?;
+                literal(bound)?
+                    .parse()
+                    .map_err(|_| syn::Error::new_spanned(bound, "expected an integer"))
+            };
+            let start = bound(range.from.as_deref())?;
+            let end = match range.limits {
+                syn::RangeLimits::HalfOpen(_) => bound(range.to.as_deref())?,
+                syn::RangeLimits::Closed(_) => bound(range.to.as_deref())?.saturating_add(1),
+            };
+            // The range is checked before it's collected, since e.g. `0..u64::MAX` would exhaust
+            // memory, and the program is run once for each argument anyway.
+            let len = end.checked_sub(start);
+            if len.is_none_or(|len| len > MAX_MAP_ARGS as i128) {
+                let message = format!("expected a range of at most {} integers", MAX_MAP_ARGS);
+                return Err(syn::Error::new_spanned(range, message));
+            }
+            Ok((start..end).map(|i| i.to_string()).collect())
         }
-        if arg == "--crate-type" || arg == "--crate-name" || arg == "--extern" {
-            skip = true;
-        } else if arg.ends_with(".rs")
-            || arg == "--test"
-            || arg == "rustc"
-            || arg.starts_with("--emit")
-        {
-            continue;
-        } else {
-            rustc_args.push(arg.clone());
+        _ => Err(syn::Error::new_spanned(
+            args,
+            "expected an array of literals or a range of integers",
+        )),
+    }
+}
+
+/// `comptime_unique_id!()` expands to an unsuffixed integer literal that's different from that of
+/// every other invocation in the build, across crates, which is unique but not stable.
+#[proc_macro]
+pub fn comptime_unique_id(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        let message = "comptime_unique_id! takes no arguments";
+        return quote!(compile_error!(#message)).into();
+    }
+    let args = expand_argfiles(std::env::args()).unwrap_or_default();
+    let Some((out_dir, _)) = rustc_out_dir(&args) else {
+        let message = "comptime failed: could not determine rustc out dir.";
+        return quote!(compile_error!(#message)).into();
+    };
+    let counter = out_dir.join("comptime-unique-id");
+    match next_unique_id(&counter) {
+        Ok(id) => proc_macro2::Literal::u64_unsuffixed(id)
+            .into_token_stream()
+            .into(),
+        Err(e) => {
+            let message = format!(
+                "comptime could not claim a unique id in `{}`: {}",
+                counter.display(),
+                e
+            );
+            quote!(compile_error!(#message)).into()
         }
     }
-    rustc_args
 }
 
-fn merge_externs(deps_dir: &Path, args: &[String]) -> Vec<String> {
-    let mut cargo_rlibs = HashMap::new(); // libfoo -> /path/to/libfoo-12345.rlib
-    let mut next_is_extern = false;
-    for arg in args {
-        if next_is_extern {
-            let mut libname_path = arg.split('=');
-            let lib_name = libname_path.next().unwrap(); // libfoo
-            let path = Path::new(libname_path.next().unwrap());
-            if path.extension().unwrap() == "rlib" {
-                cargo_rlibs.insert(lib_name.to_string(), path.to_path_buf());
+/// `comptime_timestamp!()` expands to the time of the build, or of `SOURCE_DATE_EPOCH` if it's
+/// set, as an RFC 3339 string in UTC.
+#[proc_macro]
+pub fn comptime_timestamp(input: TokenStream) -> TokenStream {
+    if !input.is_empty() {
+        let message = "comptime_timestamp! takes no arguments";
+        return quote!(compile_error!(#message)).into();
+    }
+    let source_date_epoch = std::env::var("SOURCE_DATE_EPOCH").ok();
+    match build_timestamp(source_date_epoch.as_deref()) {
+        // `option_env!` makes cargo rebuild the crate when `SOURCE_DATE_EPOCH` changes.
+        Ok(timestamp) => quote!({
+            const _: Option<&str> = option_env!("SOURCE_DATE_EPOCH");
+            #timestamp
+        }),
+        Err(message) => quote!(compile_error!(#message)),
+    }
+    .into()
+}
+
+/// Returns the RFC 3339 timestamp of `source_date_epoch`, a number of seconds since the Unix
+/// epoch, or else of now.
+fn build_timestamp(source_date_epoch: Option<&str>) -> Result<String, String> {
+    let secs = match source_date_epoch {
+        Some(secs) => secs.trim().parse::<i64>().map_err(|_| {
+            format!(
+                "SOURCE_DATE_EPOCH must be a number of seconds since the Unix epoch, not `{}`",
+                secs
+            )
+        })?,
+        None => match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(before_epoch) => -(before_epoch.duration().as_secs() as i64),
+        },
+    };
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Howard Hinnant's `civil_from_days`, for the proleptic Gregorian calendar.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    Ok(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    ))
+}
+
+/// `comptime_items! { .. }` expands to the items returned by the comptime program, for use in
+/// item position. A program that returns `()` or `comptime::nothing()` emits no items.
+#[proc_macro]
+pub fn comptime_items(input: TokenStream) -> TokenStream {
+    let ComptimeInput {
+        directives,
+        program,
+    } = syn::parse_macro_input!(input as ComptimeInput);
+    let items = match evaluate(&program, &directives) {
+        Ok(items) => items,
+        Err(compile_error) => return quote!(#compile_error;).into(),
+    };
+    if let Ok((tracking, syn::Expr::Tuple(unit))) = syn::parse2(items.clone()).map(untrack) {
+        if unit.elems.is_empty() {
+            return quote!(#(#tracking)*).into();
+        }
+    }
+    if syn::parse2::<syn::File>(items.clone()).is_err() {
+        let message = format!("comptime_items! result is not a list of items: `{}`", items);
+        return quote!(compile_error!(#message);).into();
+    }
+    items.into()
+}
+
+/// `comptime_item! { .. }` is another name for `comptime_items!`.
+#[proc_macro]
+pub fn comptime_item(input: TokenStream) -> TokenStream {
+    comptime_items(input)
+}
+
+/// Tables longer than this many bytes of tokens are emitted as an `include!` to keep rustc's
+/// diagnostics and `cargo expand` output manageable.
+const TABLE_INCLUDE_THRESHOLD: usize = 64 * 1024;
+
+/// `comptime_table!(Type; { .. })` expands to an array literal of the `Type`s in the `Vec`
+/// (or other iterable) returned by the comptime program.
+#[proc_macro]
+pub fn comptime_table(input: TokenStream) -> TokenStream {
+    let TableInput {
+        ty,
+        input: ComptimeInput {
+            mut directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as TableInput);
+    let program = match syn::parse2(quote!(comptime::table({ #program }))) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // A limit that the directives already set is kept.
+    directives
+        .include_above
+        .get_or_insert(TABLE_INCLUDE_THRESHOLD);
+    let table = match evaluate(&program, &directives) {
+        Ok(table) => table,
+        Err(compile_error) => return compile_error.into(),
+    };
+    // An empty array's element type can't be inferred from the array itself.
+    match syn::parse2(table.clone()).map(untrack) {
+        Ok((tracking, syn::Expr::Array(array))) if array.elems.is_empty() => {
+            quote!({ #(#tracking)* let table: [#ty; 0] = []; table }).into()
+        }
+        _ => table.into(),
+    }
+}
+
+/// `Type; <comptime program>`
+struct TableInput {
+    ty: syn::Type,
+    input: ComptimeInput,
+}
+
+impl Parse for TableInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ty = input.parse()?;
+        input.parse::<syn::Token![;]>()?;
+        Ok(Self {
+            ty,
+            input: input.parse()?,
+        })
+    }
+}
+
+/// `comptime_const_fn!(fn name(index: usize) -> Type { .. })` expands to a `const fn` that
+/// returns the element at `index` of a table of the values returned by the comptime program, as
+/// in `comptime_table!`. If `Type` is an `Option`, the function returns `None` for an `index`
+/// that's out of bounds, and otherwise it panics.
+#[proc_macro]
+pub fn comptime_const_fn(input: TokenStream) -> TokenStream {
+    let ConstFnInput {
+        attrs,
+        vis,
+        name,
+        index,
+        index_ty,
+        ret,
+        input: ComptimeInput {
+            mut directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as ConstFnInput);
+    let program = match syn::parse2(quote!(comptime::table({ #program }))) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // A limit that the directives already set is kept.
+    directives
+        .include_above
+        .get_or_insert(TABLE_INCLUDE_THRESHOLD);
+    let table = match evaluate(&program, &directives) {
+        Ok(table) => table,
+        Err(compile_error) => return compile_error.into(),
+    };
+    let (ty, lookup) = match option_element(&ret) {
+        Some(ty) => (
+            ty,
+            quote!(match (#index as usize) < TABLE.len() {
+                true => Some(TABLE[#index as usize]),
+                false => None,
+            }),
+        ),
+        None => (&ret, quote!(TABLE[#index as usize])),
+    };
+    quote!(
+        #(#attrs)*
+        #vis const fn #name(#index: #index_ty) -> #ret {
+            const TABLE: &[#ty] = &#table;
+            #lookup
+        }
+    )
+    .into()
+}
+
+/// Returns `T` if `ty` is `Option<T>`.
+fn option_element(ty: &syn::Type) -> Option<&syn::Type> {
+    let syn::Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last().filter(|s| s.ident == "Option")?;
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `#[attr] pub fn name(index: usize) -> Type <comptime program>`
+struct ConstFnInput {
+    attrs: Vec<syn::Attribute>,
+    vis: syn::Visibility,
+    name: syn::Ident,
+    index: syn::Ident,
+    index_ty: syn::Type,
+    ret: syn::Type,
+    input: ComptimeInput,
+}
+
+impl Parse for ConstFnInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(syn::Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        input.parse::<Option<syn::Token![const]>>()?;
+        input.parse::<syn::Token![fn]>()?;
+        let name = input.parse()?;
+        let params;
+        syn::parenthesized!(params in input);
+        let index = params.parse()?;
+        params.parse::<syn::Token![:]>()?;
+        let index_ty = params.parse()?;
+        input.parse::<syn::Token![->]>()?;
+        Ok(Self {
+            attrs,
+            vis,
+            name,
+            index,
+            index_ty,
+            ret: input.parse()?,
+            input: input.parse()?,
+        })
+    }
+}
+
+/// `comptime_range_match!(input; { .. })` expands to a `match` of `input` that evaluates to
+/// `Some(value)` for the range containing it, among the `(RangeInclusive<i64>, value)` pairs
+/// returned by the comptime program, and `None` otherwise. Overlapping ranges are an error.
+#[proc_macro]
+pub fn comptime_range_match(input: TokenStream) -> TokenStream {
+    let RangeMatchInput {
+        scrutinee,
+        input: ComptimeInput {
+            directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as RangeMatchInput);
+    let program = match syn::parse2(quote!(comptime::range_match({ #program }))) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let arms = match evaluate(&program, &directives) {
+        Ok(arms) => arms,
+        Err(compile_error) => return compile_error.into(),
+    };
+    let (tracking, arms) = match syn::parse2(arms.clone()).map(untrack) {
+        Ok((tracking, syn::Expr::Array(array))) => (tracking, array.elems),
+        _ => {
+            let message = format!(
+                "comptime_range_match! result is not a list of arms: `{}`",
+                arms
+            );
+            return quote!(compile_error!(#message)).into();
+        }
+    };
+
+    let mut ranges = Vec::with_capacity(arms.len());
+    for arm in arms {
+        let syn::Expr::Tuple(tuple) = arm else {
+            unreachable!("`comptime::range_match` emits tuples");
+        };
+        let mut elems = tuple.elems.into_iter();
+        let (Some(start), Some(end), Some(value)) = (elems.next(), elems.next(), elems.next())
+        else {
+            unreachable!("`comptime::range_match` emits `(start, end, value)`");
+        };
+        ranges.push((int_value(&start), int_value(&end), start, end, value));
+    }
+    ranges.sort_by_key(|(start, ..)| *start);
+    let bounds: Vec<_> = ranges
+        .iter()
+        .map(|(start, end, ..)| (*start, *end))
+        .collect();
+    if let Some(message) = range_overlap(&bounds) {
+        return quote!(compile_error!(#message)).into();
+    }
+
+    let arms = ranges.iter().map(
+        |(_, _, start, end, value)| quote!(#start..=#end => ::core::option::Option::Some(#value),),
+    );
+    quote!({
+        #(#tracking)*
+        match #scrutinee {
+            #(#arms)*
+            _ => ::core::option::Option::None,
+        }
+    })
+    .into()
+}
+
+/// Returns an error about the first of the inclusive `ranges`, sorted by their starts, that
+/// overlaps the next, if any does.
+fn range_overlap(ranges: &[(i128, i128)]) -> Option<String> {
+    ranges.windows(2).find_map(|pair| {
+        let [(start, end), (next_start, next_end)] = pair else {
+            unreachable!();
+        };
+        (next_start <= end).then(|| {
+            format!(
+                "comptime_range_match! ranges `{}..={}` and `{}..={}` overlap",
+                start, end, next_start, next_end
+            )
+        })
+    })
+}
+
+/// `input; <comptime program>`
+struct RangeMatchInput {
+    scrutinee: syn::Expr,
+    input: ComptimeInput,
+}
+
+impl Parse for RangeMatchInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let scrutinee = input.parse()?;
+        input.parse::<syn::Token![;]>()?;
+        Ok(Self {
+            scrutinee,
+            input: input.parse()?,
+        })
+    }
+}
+
+/// Returns the value of an integer literal emitted by `comptime::range_match`, e.g. `-5`.
+fn int_value(expr: &syn::Expr) -> i128 {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(int),
+            ..
+        }) => int.base10_parse().unwrap(),
+        syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => -int_value(expr),
+        _ => unreachable!("`comptime::range_match` emits integer literals"),
+    }
+}
+
+/// `comptime_enum!(#[attr] pub Name { .. })` emits `enum Name` with a unit variant named by each
+/// of the strings returned by the comptime program, along with `Name::as_str` and a `FromStr` impl
+/// that map the variants to and from their names.
+#[proc_macro]
+pub fn comptime_enum(input: TokenStream) -> TokenStream {
+    let EnumInput {
+        attrs,
+        vis,
+        name,
+        input: ComptimeInput {
+            directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as EnumInput);
+    let program = match syn::parse2(quote!(comptime::table({ #program }))) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let names = match evaluate(&program, &directives) {
+        Ok(names) => names,
+        Err(compile_error) => return quote!(#compile_error;).into(),
+    };
+    let (tracking, names) = match syn::parse2(names.clone()).map(untrack) {
+        Ok((tracking, syn::Expr::Array(array))) => (tracking, array.elems),
+        _ => {
+            let message = format!("comptime_enum! result is not a list of names: `{}`", names);
+            return quote!(compile_error!(#message);).into();
+        }
+    };
+    let mut strings = Vec::with_capacity(names.len());
+    for name in names {
+        match name {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(name),
+                ..
+            }) => strings.push(name.value()),
+            name => {
+                let message = format!(
+                    "comptime_enum! variant names must be strings, not `{}`",
+                    name.to_token_stream()
+                );
+                return quote!(compile_error!(#message);).into();
             }
         }
-        next_is_extern = arg == "--extern";
     }
+    let variants = match enum_variants(strings) {
+        Ok(variants) => variants,
+        Err(message) => return quote!(compile_error!(#message);).into(),
+    };
+    let (names, idents): (Vec<_>, Vec<_>) = variants.into_iter().unzip();
+    let unknown = format!("unknown `{}` variant `{{}}`", name);
+    quote!(
+        #(#tracking)*
+        #(#attrs)*
+        #vis enum #name {
+            #(#idents),*
+        }
 
-    let mut dep_dirents: Vec<_> = std::fs::read_dir(deps_dir)
-        .unwrap()
-        .filter_map(|de| {
-            let de = de.unwrap();
-            let p = de.path();
-            let fname = p.file_name().unwrap().to_str().unwrap();
-            if fname.starts_with("lib") && fname.ends_with(".rlib") {
-                Some(de)
-            } else {
-                None
+        impl #name {
+            /// The name of the variant.
+            #vis fn as_str(&self) -> &'static str {
+                match *self {
+                    #(Self::#idents => #names),*
+                }
+            }
+        }
+
+        impl ::core::str::FromStr for #name {
+            type Err = ::std::string::String;
+
+            fn from_str(name: &str) -> ::core::result::Result<Self, Self::Err> {
+                match name {
+                    #(#names => ::core::result::Result::Ok(Self::#idents),)*
+                    _ => ::core::result::Result::Err(::std::format!(#unknown, name)),
+                }
+            }
+        }
+    )
+    .into()
+}
+
+/// Returns the identifiers of the `comptime_enum!` variants `names`, or an error about the first
+/// that's duplicated or isn't a valid identifier.
+fn enum_variants(names: Vec<String>) -> Result<Vec<(String, syn::Ident)>, String> {
+    let mut variants: Vec<(String, syn::Ident)> = Vec::with_capacity(names.len());
+    for name in names {
+        if variants.iter().any(|(variant, _)| *variant == name) {
+            return Err(format!("comptime_enum! variant `{}` is duplicated", name));
+        }
+        match syn::parse_str::<syn::Ident>(&name) {
+            Ok(ident) => variants.push((name, ident)),
+            Err(_) => {
+                return Err(format!(
+                    "comptime_enum! variant name `{}` is not a valid identifier",
+                    name
+                ))
             }
+        }
+    }
+    Ok(variants)
+}
+
+/// `#[attr] pub Name <comptime program>`
+struct EnumInput {
+    attrs: Vec<syn::Attribute>,
+    vis: syn::Visibility,
+    name: syn::Ident,
+    input: ComptimeInput,
+}
+
+impl Parse for EnumInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        Ok(Self {
+            attrs: input.call(syn::Attribute::parse_outer)?,
+            vis: input.parse()?,
+            name: input.parse()?,
+            input: input.parse()?,
         })
-        .collect();
-    dep_dirents.sort_by_key(|de| std::cmp::Reverse(de.metadata().and_then(|m| m.created()).ok()));
+    }
+}
 
-    for dirent in dep_dirents {
-        let path = dirent.path();
-        let fname = path.file_name().unwrap().to_str().unwrap();
-        if !fname.ends_with(".rlib") {
-            continue;
+/// `comptime_phf!(key => Type; { .. })` looks up the `&str` `key` in a map of the `(key, value)`
+/// pairs returned by the comptime program, and evaluates to an `Option<&'static Type>`. The map is
+/// a `phf::Map` if the crate depends on `phf` and `phf_codegen` has been built, or else a `match`.
+#[proc_macro]
+pub fn comptime_phf(input: TokenStream) -> TokenStream {
+    let PhfInput {
+        key,
+        ty,
+        input: ComptimeInput {
+            directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as PhfInput);
+    let args = expand_argfiles(std::env::args()).unwrap_or_default();
+    let build = if has_extern(&args, "phf") && has_dependency_rlib(&args, "phf_codegen") {
+        quote!(Some(&|entries: &[(String, String)]| {
+            let mut map = phf_codegen::Map::new();
+            for (key, value) in entries {
+                map.entry(key.as_str(), value);
+            }
+            map.build().to_string()
+        }))
+    } else {
+        quote!(None)
+    };
+    let program = match syn::parse2(quote!(comptime::map({ #program }, #build))) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let map = match evaluate(&program, &directives) {
+        Ok(map) => map,
+        Err(compile_error) => return compile_error.into(),
+    };
+    match syn::parse2(map.clone()).map(untrack) {
+        // A duplicate key.
+        Ok((_, syn::Expr::Macro(_))) => map.into(),
+        Ok((tracking, syn::Expr::Array(array))) => {
+            let mut keys = Vec::with_capacity(array.elems.len());
+            let mut values = Vec::with_capacity(array.elems.len());
+            for entry in array.elems {
+                let syn::Expr::Tuple(tuple) = entry else {
+                    unreachable!("`comptime::map` emits tuples");
+                };
+                let mut elems = tuple.elems.into_iter();
+                keys.extend(elems.next());
+                values.extend(elems.next());
+            }
+            let len = values.len();
+            let indices = 0..len;
+            quote!({
+                #(#tracking)*
+                static VALUES: [#ty; #len] = [#(#values),*];
+                match #key {
+                    #(#keys => ::core::option::Option::Some(&VALUES[#indices]),)*
+                    _ => ::core::option::Option::None,
+                }
+            })
+            .into()
         }
-        let lib_name = fname.rsplit_once('-').unwrap().0.to_string();
-        // ^ reverse "libfoo-disambiguator" then split off the disambiguator
-        if let Entry::Vacant(ve) = cargo_rlibs.entry(lib_name) {
-            ve.insert(path);
+        Ok((tracking, phf_map)) => quote!({
+            #(#tracking)*
+            static MAP: ::phf::Map<&'static str, #ty> = #phf_map;
+            MAP.get(#key)
+        })
+        .into(),
+        Err(_) => {
+            let message = format!("comptime_phf! result is not a map: `{}`", map);
+            quote!(compile_error!(#message)).into()
         }
     }
+}
 
-    let mut merged_externs = Vec::with_capacity(cargo_rlibs.len() * 2);
-    for (lib_name, path) in cargo_rlibs.iter() {
-        merged_externs.push("--extern".to_string());
-        merged_externs.push(format!(
-            "{}={}",
-            &lib_name.strip_prefix("lib").unwrap_or(lib_name),
-            path.display()
-        ));
+/// `key => Type; <comptime program>`
+struct PhfInput {
+    key: syn::Expr,
+    ty: syn::Type,
+    input: ComptimeInput,
+}
+
+impl Parse for PhfInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<syn::Token![=>]>()?;
+        let ty = input.parse()?;
+        input.parse::<syn::Token![;]>()?;
+        Ok(Self {
+            key,
+            ty,
+            input: input.parse()?,
+        })
     }
+}
 
-    merged_externs
+/// `comptime_assert!(cond)` or `comptime_assert!(cond, "message")` evaluates `cond` at compile
+/// time and fails compilation with the message if it's `false`.
+#[proc_macro]
+pub fn comptime_assert(input: TokenStream) -> TokenStream {
+    let AssertInput { exprs, message } = syn::parse_macro_input!(input as AssertInput<1>);
+    let [cond] = exprs;
+    let message =
+        message.unwrap_or_else(|| format!("comptime assertion failed: {}", cond.to_token_stream()));
+    // Bound first, since a struct literal can't be parsed bare in an `if` condition.
+    assert_program(quote! {
+        let comptime_cond: bool = #cond;
+        if comptime_cond { String::new() } else { String::from(#message) }
+    })
+}
+
+/// `comptime_assert_eq!(left, right)` or `comptime_assert_eq!(left, right, "message")` evaluates
+/// both sides at compile time and fails compilation if they're not equal.
+#[proc_macro]
+pub fn comptime_assert_eq(input: TokenStream) -> TokenStream {
+    let AssertInput { exprs, message } = syn::parse_macro_input!(input as AssertInput<2>);
+    let [left, right] = exprs;
+    let message = message.unwrap_or_else(|| "comptime assertion `left == right` failed".into());
+    assert_program(quote! {
+        match (&(#left), &(#right)) {
+            (left, right) => if *left == *right {
+                String::new()
+            } else {
+                format!("{}\n  left: {:?}\n right: {:?}", #message, left, right)
+            }
+        }
+    })
+}
+
+/// The comma-separated args of the `comptime_assert` macros: `N` exprs, then an optional message.
+struct AssertInput<const N: usize> {
+    exprs: [syn::Expr; N],
+    message: Option<String>,
+}
+
+impl<const N: usize> Parse for AssertInput<N> {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut exprs = Vec::with_capacity(N);
+        for i in 0..N {
+            if i > 0 {
+                input.parse::<syn::Token![,]>()?;
+            }
+            exprs.push(input.parse()?);
+        }
+        let mut message = None;
+        if !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+            if !input.is_empty() {
+                let lit = input.parse::<syn::LitStr>()?;
+                // The program returns an empty message on success.
+                if lit.value().is_empty() {
+                    return Err(syn::Error::new(
+                        lit.span(),
+                        "comptime assertion message must not be empty",
+                    ));
+                }
+                message = Some(lit.value());
+                input.parse::<Option<syn::Token![,]>>()?;
+            }
+        }
+        Ok(Self {
+            exprs: exprs.try_into().ok().unwrap(),
+            message,
+        })
+    }
+}
+
+/// Evaluates a program that returns an empty string on success or the failure message, and
+/// expands to an unnamed `const` item, which holds a `compile_error!` on failure.
+fn assert_program(program: proc_macro2::TokenStream) -> TokenStream {
+    let body = match check_program(program) {
+        Ok(tracking) => quote!(#(#tracking)*),
+        Err(compile_error) => quote!(#compile_error;),
+    };
+    quote!(const _: () = { #body };).into()
+}
+
+/// Evaluates a program that returns an error message, or an empty string if there's no error,
+/// and returns the items that track its inputs, or the error as a `compile_error!`.
+fn check_program(
+    program: proc_macro2::TokenStream,
+) -> Result<Vec<syn::Stmt>, proc_macro2::TokenStream> {
+    let program = syn::parse2(program).map_err(|e| e.to_compile_error())?;
+    let message = syn::parse2(evaluate(&program, &Directives::default())?).map(untrack);
+    let message = message.and_then(|(tracking, message)| {
+        Ok((
+            tracking,
+            syn::parse2::<syn::LitStr>(message.to_token_stream())?.value(),
+        ))
+    });
+    match message {
+        Ok((tracking, message)) if message.is_empty() => Ok(tracking),
+        Ok((_, message)) => Err(quote!(compile_error!(#message))),
+        Err(_) => Err(quote!(compile_error!(
+            "comptime assertion did not produce a message"
+        ))),
+    }
+}
+
+/// `comptime_regex!("pattern")` expands to `"pattern"` if it's a valid regex, as checked at
+/// compile time by the `regex` crate, which the invoking crate must depend on.
+#[proc_macro]
+pub fn comptime_regex(input: TokenStream) -> TokenStream {
+    let pattern = syn::parse_macro_input!(input as syn::LitStr);
+    let args = expand_argfiles(std::env::args()).unwrap_or_default();
+    if !has_extern(&args, "regex") {
+        return quote!(compile_error!(
+            "comptime_regex! requires the crate to depend on `regex`"
+        ))
+        .into();
+    }
+    let checked = check_program(quote! {
+        match regex::Regex::new(#pattern) {
+            Ok(_) => String::new(),
+            Err(e) => format!("invalid regex: {}", e),
+        }
+    });
+    match checked {
+        Ok(tracking) => quote!({ #(#tracking)* #pattern }).into(),
+        Err(compile_error) => compile_error.into(),
+    }
+}
+
+/// `comptime_cmd!("program", ["arg", ..])` runs `program` at compile time and expands to its
+/// trimmed stdout as a string literal. A relative `program` path is resolved against the crate's
+/// root, and a failure to run it, or a nonzero exit status, fails compilation with its stderr.
+#[proc_macro]
+pub fn comptime_cmd(input: TokenStream) -> TokenStream {
+    let CmdInput { program, args } = syn::parse_macro_input!(input as CmdInput);
+    let program = syn::parse2(quote!(comptime::cmd(#program, &[#(#args),*]))).unwrap();
+    let output = match evaluate(&program, &Directives::default()) {
+        Ok(output) => output,
+        Err(compile_error) => return compile_error.into(),
+    };
+    match syn::parse2(output) {
+        Ok(syn::Expr::Tuple(tuple)) => match (tuple.elems.first(), tuple.elems.last()) {
+            (
+                Some(syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Bool(ok),
+                    ..
+                })),
+                Some(syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(text),
+                    ..
+                })),
+            ) => match ok.value {
+                true => text.to_token_stream().into(),
+                false => quote!(compile_error!(#text)).into(),
+            },
+            _ => unreachable!("`comptime::cmd` emits `(ok, text)`"),
+        },
+        _ => unreachable!("`comptime::cmd` emits `(ok, text)`"),
+    }
+}
+
+/// `"program"` or `"program", ["arg", ..]`
+struct CmdInput {
+    program: syn::LitStr,
+    args: Vec<syn::LitStr>,
+}
+
+impl Parse for CmdInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let program = input.parse()?;
+        let mut args = Vec::new();
+        if input.parse::<Option<syn::Token![,]>>()?.is_some() && !input.is_empty() {
+            let content;
+            syn::bracketed!(content in input);
+            let parsed =
+                content.parse_terminated::<_, syn::Token![,]>(|i| i.parse::<syn::LitStr>())?;
+            args.extend(parsed);
+            input.parse::<Option<syn::Token![,]>>()?;
+        }
+        Ok(Self { program, args })
+    }
+}
+
+/// `comptime_static!(NAME: Type = { .. })` expands to `static NAME: Type = ..;` where the
+/// initializer is the result of the comptime program. Unlike a `const`, large tables emitted this
+/// way exist only once in the binary.
+#[proc_macro]
+pub fn comptime_static(input: TokenStream) -> TokenStream {
+    let StaticInput {
+        attrs,
+        vis,
+        mutability,
+        ident,
+        ty,
+        init: ComptimeInput {
+            directives,
+            program,
+        },
+    } = syn::parse_macro_input!(input as StaticInput);
+    let init = match evaluate(&program, &directives) {
+        Ok(init) => init,
+        Err(compile_error) => return quote!(#compile_error;).into(),
+    };
+    match syn::parse2(init.clone()).map(untrack) {
+        Ok((_, expr)) if directives.include || is_static_initializer(&expr) => {}
+        _ => {
+            let message = format!(
+                "comptime_static! result is not a valid static initializer: `{}`",
+                init
+            );
+            return quote!(compile_error!(#message);).into();
+        }
+    }
+    quote!(#(#attrs)* #vis static #mutability #ident: #ty = #init;).into()
+}
+
+/// `[#[attr]] [vis] [mut] NAME: Type = <comptime program>`
+struct StaticInput {
+    attrs: Vec<syn::Attribute>,
+    vis: syn::Visibility,
+    mutability: Option<syn::Token![mut]>,
+    ident: syn::Ident,
+    ty: syn::Type,
+    init: ComptimeInput,
+}
+
+impl Parse for StaticInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(syn::Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        let mutability = input.parse()?;
+        let ident = input.parse()?;
+        input.parse::<syn::Token![:]>()?;
+        let ty = input.parse()?;
+        input.parse::<syn::Token![=]>()?;
+        Ok(Self {
+            attrs,
+            vis,
+            mutability,
+            ident,
+            ty,
+            init: input.parse()?,
+        })
+    }
+}
+
+/// `comptime_bundle! { const A: Type = { .. }; const B: Type = { .. }; }` expands to the `const`
+/// items, whose initializers are the results of their comptime programs. The programs are run as
+/// one, which is compiled once, and each can use the values that the others compute by name, so
+/// they're run in the order of their references to each other rather than in the bundle's order.
+/// Each value is bound to a `let` and emitted at the end, so it can't be moved out of.
+#[proc_macro]
+pub fn comptime_bundle(input: TokenStream) -> TokenStream {
+    let BundleInput(consts) = syn::parse_macro_input!(input as BundleInput);
+    let order = match bundle_order(&consts) {
+        Ok(order) => order,
+        Err(name) => {
+            let message = format!("comptime_bundle! const `{}` depends on itself", name);
+            return quote!(compile_error!(#message);).into();
+        }
+    };
+    let (names, inits): (Vec<_>, Vec<_>) = order
+        .iter()
+        .map(|&i| (&consts[i].ident, &consts[i].expr))
+        .unzip();
+    let program = quote!(
+        #(
+            #[allow(non_snake_case)]
+            let #names = #inits;
+        )*
+        comptime::bundle(&[#(&#names),*])
+    );
+    let program = match syn::parse2(program) {
+        Ok(program) => program,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let result = match evaluate(&program, &Directives::default()) {
+        Ok(result) => result,
+        Err(compile_error) => return quote!(#compile_error;).into(),
+    };
+    let (tracking, values) = match syn::parse2(result.clone()).map(untrack) {
+        Ok((tracking, syn::Expr::Tuple(tuple))) if tuple.elems.len() == consts.len() => {
+            (tracking, tuple.elems)
+        }
+        _ => {
+            let message = format!("comptime_bundle! result is malformed: `{}`", result);
+            return quote!(compile_error!(#message);).into();
+        }
+    };
+    let items = order.iter().zip(values).map(|(&i, value)| {
+        let syn::ItemConst {
+            attrs,
+            vis,
+            ident,
+            ty,
+            ..
+        } = &consts[i];
+        quote!(#(#attrs)* #vis const #ident: #ty = #value;)
+    });
+    quote!(#(#tracking)* #(#items)*).into()
+}
+
+/// `const NAME: Type = <expr>;`, repeated
+struct BundleInput(Vec<syn::ItemConst>);
+
+impl Parse for BundleInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut consts = Vec::new();
+        while !input.is_empty() {
+            consts.push(input.parse()?);
+        }
+        Ok(Self(consts))
+    }
+}
+
+/// Orders the `consts` of a bundle so that each comes after those whose names its initializer
+/// mentions, keeping the bundle's order otherwise, or returns the name of one that's on a cycle.
+fn bundle_order(consts: &[syn::ItemConst]) -> Result<Vec<usize>, &syn::Ident> {
+    fn mentions(tokens: proc_macro2::TokenStream, names: &mut HashSet<String>) {
+        for tree in tokens {
+            match tree {
+                proc_macro2::TokenTree::Ident(ident) => {
+                    names.insert(ident.to_string());
+                }
+                proc_macro2::TokenTree::Group(group) => mentions(group.stream(), names),
+                _ => {}
+            }
+        }
+    }
+    let deps: Vec<Vec<usize>> = consts
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let mut names = HashSet::new();
+            mentions(item.expr.to_token_stream(), &mut names);
+            (0..consts.len())
+                .filter(|&j| j != i && names.contains(&consts[j].ident.to_string()))
+                .collect()
+        })
+        .collect();
+    let mut order = Vec::with_capacity(consts.len());
+    while order.len() < consts.len() {
+        let next = (0..consts.len())
+            .find(|i| !order.contains(i) && deps[*i].iter().all(|dep| order.contains(dep)));
+        match next {
+            Some(next) => order.push(next),
+            None => {
+                // Each remaining const depends on another remaining one, so following those
+                // dependencies from any of them comes back around to one on a cycle.
+                let mut visited = vec![false; consts.len()];
+                let mut cyclic = (0..consts.len()).find(|i| !order.contains(i)).unwrap();
+                while !visited[cyclic] {
+                    visited[cyclic] = true;
+                    cyclic = *deps[cyclic]
+                        .iter()
+                        .find(|dep| !order.contains(dep))
+                        .unwrap();
+                }
+                return Err(&consts[cyclic].ident);
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Returns whether `expr` is built from the kinds of expressions that can be evaluated in a
+/// `static` initializer, which excludes things like macros and closures. Whether, e.g., a method
+/// it calls is a `const fn` is left to rustc.
+fn is_static_initializer(expr: &syn::Expr) -> bool {
+    use syn::Expr;
+    match expr {
+        Expr::Lit(_) | Expr::Path(_) => true,
+        Expr::Array(array) => array.elems.iter().all(is_static_initializer),
+        Expr::Tuple(tuple) => tuple.elems.iter().all(is_static_initializer),
+        Expr::Call(call) => {
+            matches!(&*call.func, Expr::Path(_)) && call.args.iter().all(is_static_initializer)
+        }
+        Expr::MethodCall(call) => {
+            is_static_initializer(&call.receiver) && call.args.iter().all(is_static_initializer)
+        }
+        Expr::Binary(binary) => {
+            is_static_initializer(&binary.left) && is_static_initializer(&binary.right)
+        }
+        // Its statements, like `let`s, are checked by rustc.
+        Expr::Block(block) => match block.block.stmts.last() {
+            Some(syn::Stmt::Expr(expr)) => is_static_initializer(expr),
+            _ => false,
+        },
+        Expr::Field(field) => is_static_initializer(&field.base),
+        Expr::Index(index) => {
+            is_static_initializer(&index.expr) && is_static_initializer(&index.index)
+        }
+        Expr::Struct(strukt) => {
+            strukt.fields.iter().all(|f| is_static_initializer(&f.expr))
+                && strukt.rest.as_deref().is_none_or(is_static_initializer)
+        }
+        Expr::Repeat(repeat) => is_static_initializer(&repeat.expr),
+        Expr::Reference(reference) => is_static_initializer(&reference.expr),
+        Expr::Unary(unary) => is_static_initializer(&unary.expr),
+        Expr::Cast(cast) => is_static_initializer(&cast.expr),
+        Expr::Paren(paren) => is_static_initializer(&paren.expr),
+        Expr::Group(group) => is_static_initializer(&group.expr),
+        _ => false,
+    }
+}
+
+/// Builds and runs the comptime program, returning the tokens to splice at the call site or a
+/// `compile_error!` if that failed.
+fn evaluate(
+    comptime_program: &BlockInner,
+    directives: &Directives,
+) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    if logs("summary") {
+        let start = std::time::Instant::now();
+        let result = evaluate_uninstrumented(comptime_program, directives);
+        summary::record(start.elapsed());
+        return result;
+    }
+    evaluate_uninstrumented(comptime_program, directives)
+}
+
+fn evaluate_uninstrumented(
+    comptime_program: &BlockInner,
+    directives: &Directives,
+) -> Result<proc_macro2::TokenStream, proc_macro2::TokenStream> {
+    let args = match expand_argfiles(std::env::args()) {
+        Ok(args) => args,
+        Err(e) => err!("comptime could not read rustc's argfile: {}", e),
+    };
+    let get_arg = |arg| {
+        args.iter()
+            .position(|a| a == arg)
+            .and_then(|p| args.get(p + 1))
+    };
+
+    let (out_dir, explicit_externs) = match rustc_out_dir(&args) {
+        Some(out_dir) => out_dir,
+        None => {
+            err!("comptime failed: could not determine rustc out dir.");
+        }
+    };
+    // When rustc is only checking the crate, there are no rlibs to link against unless they're
+    // built on demand.
+    let checking = is_checking(&args);
+    let built_deps_dir = if checking && env_flag("COMPTIME_BUILD_DEPS") {
+        let Some(manifest_dir) = std::env::var_os("CARGO_MANIFEST_DIR") else {
+            err!("COMPTIME_BUILD_DEPS requires building with cargo");
+        };
+        let cache_root = out_dir.join("..").join("comptime-deps");
+        let keep = env_flag("COMPTIME_KEEP") || debug_all();
+        match deps::build_deps(Path::new(&manifest_dir), &cache_root, keep) {
+            Ok(deps_dir) => Some(deps_dir),
+            Err(e) => err!("comptime {}", e),
+        }
+    } else {
+        None
+    };
+    let deps_dir = built_deps_dir.as_deref().unwrap_or(out_dir);
+
+    if env_flag("COMPTIME_STRICT_PERMS") {
+        let mut dirs = vec![out_dir, deps_dir];
+        dirs.extend(args.iter().filter_map(|a| dependency_dir(a)).map(Path::new));
+        for dir in dirs {
+            if is_world_writable(dir) {
+                err!(
+                    "comptime refused to run: `{}` is writable by others (COMPTIME_STRICT_PERMS=1)",
+                    dir.display()
+                );
+            }
+        }
+    }
+
+    // Crates in a workspace share a deps dir, so artifacts are namespaced by the parent crate,
+    // and by its unit, which is told apart by its `-C extra-filename`, or else its `-C metadata`.
+    let crate_name = get_arg("--crate-name").map_or("crate", String::as_str);
+    let extra_filename = match codegen_options(&args, "extra-filename").last() {
+        Some(extra_filename) => extra_filename.to_string(),
+        None => codegen_options(&args, "metadata")
+            .last()
+            .map_or_else(String::new, |metadata| format!("-{}", metadata)),
+    };
+    // With cached outputs, rustc's answers about the unit are cached too, so that an unchanged
+    // program doesn't run rustc at all.
+    let caching = env_flag("COMPTIME_CACHE") && !debug_all();
+    let queries = cache::RustcQueries {
+        out_dir,
+        unit: &extra_filename,
+    };
+    let queries = (caching && !extra_filename.is_empty()).then_some(&queries);
+
+    let target = get_arg("--target");
+    let target_cfg = if directives.target_layout || target.is_some() {
+        // The crate's `target_feature`s depend on its codegen options too.
+        let codegen: Vec<_> = ["target-cpu", "target-feature"]
+            .into_iter()
+            .flat_map(|name| {
+                let values = codegen_options(&args, name).into_iter();
+                values.map(move |value| format!("-C{}={}", name, value))
+            })
+            .collect();
+        match target_cfg(target.map(String::as_str), &codegen, queries) {
+            Ok(cfg) => Some(cfg),
+            Err(e) => err!("comptime could not determine the target layout: {}", e),
+        }
+    } else {
+        None
+    };
+    let mut comptime_program_str = match (&target_cfg, target) {
+        (Some(cfg), Some(_)) => apply_target_cfgs(comptime_program.to_token_stream(), cfg),
+        _ => comptime_program.to_token_stream(),
+    }
+    .to_string();
+    if directives.fallible {
+        if directives.no_std {
+            err!("`@try` requires std, for `Box<dyn std::error::Error>`");
+        }
+        comptime_program_str = format!(
+            "match (|| -> ::std::result::Result<_, ::std::boxed::Box<dyn ::std::error::Error>> {{ \
+             ::std::result::Result::Ok({{ {} }}) }})() {{ \
+             ::std::result::Result::Ok(comptime_result) => comptime_result, \
+             ::std::result::Result::Err(e) => comptime::exit_with_error(e) }}",
+            comptime_program_str
+        );
+    }
+    if let Some(ResultType { ty, kind }) = &directives.result_type {
+        // Let the driver's type inference do the work, e.g. for `2 + 2` as a `u32`. A `&str` is
+        // left alone so that a `String` can be returned.
+        if *kind != LitKind::Str {
+            comptime_program_str = format!(
+                "let comptime_result: {} = {{ {} }}; comptime_result",
+                ty.to_token_stream(),
+                comptime_program_str
+            );
+        }
+    }
+
+    let uses = ProgramUses::of(comptime_program);
+    let mut comptime_module = String::new();
+    if uses.parse_file {
+        // Only programs that use it pay for linking `syn`.
+        comptime_module.push_str(DRIVER_SOURCE);
+    }
+    if uses.json {
+        #[cfg(not(feature = "json"))]
+        err!("`comptime::json` requires the `json` feature of comptime");
+        #[cfg(feature = "json")]
+        {
+            if !has_extern(&args, "serde_json") {
+                err!("`comptime::json` requires the crate to depend on `serde_json`");
+            }
+            comptime_module.push_str(json::DRIVER_JSON);
+        }
+    }
+    match &target_cfg {
+        Some(cfg) if directives.target_layout => comptime_module.push_str(&format!(
+            r#"pub mod target {{
+                pub const POINTER_WIDTH: u32 = {};
+                pub const ENDIAN: &str = {:?};
+                pub const ARCH: &str = {:?};
+                pub const OS: &str = {:?};
+            }}"#,
+            cfg.pointer_width, cfg.endian, cfg.arch, cfg.os
+        )),
+        Some(cfg) if uses.layout_query && cfg.pointer_width != usize::BITS && shows_warnings() => {
+            eprintln!(
+                "warning: comptime programs run on the host, so layout queries reflect a \
+                 {}-bit host rather than the {}-bit target `{}`. Use `@target_layout` and \
+                 `comptime::target` for target-correct values.",
+                usize::BITS,
+                cfg.pointer_width,
+                target.unwrap(),
+            );
+        }
+        _ => {}
+    }
+
+    let mut hasher = DefaultHasher::new();
+    comptime_program_str.hash(&mut hasher);
+    for key in &directives.env_keys {
+        (key, std::env::var_os(key)).hash(&mut hasher);
+    }
+    directives.cfgs.hash(&mut hasher);
+    directives.run_args.hash(&mut hasher);
+    let comptime_disambiguator = hasher.finish();
+
+    let driver_template = match std::env::var("COMPTIME_DRIVER_TEMPLATE") {
+        _ if directives.no_std => String::new(),
+        Ok(path) => match std::fs::read_to_string(&path) {
+            Ok(template) => template,
+            Err(e) => err!("could not read COMPTIME_DRIVER_TEMPLATE `{}`: {}", path, e),
+        },
+        Err(_) => DRIVER_TEMPLATE.to_string(),
+    };
+    // The driver echoes this so that output from some other program's artifact isn't trusted.
+    let comptime_nonce = driver_nonce(
+        comptime_disambiguator,
+        (
+            &comptime_program_str,
+            &comptime_module,
+            &driver_template,
+            directives.no_std,
+            directives.no_std_alloc,
+        ),
+    );
+    comptime_module.push_str(&format!(
+        "pub(crate) const NONCE: &str = {:?};\n",
+        comptime_nonce
+    ));
+
+    let comptime_stem = driver_stem(crate_name, &extra_filename, comptime_disambiguator);
+    let driver = if directives.no_std {
+        if !cfg!(unix) {
+            err!("`@no_std` comptime programs are only supported on Unix hosts");
+        }
+        // `alloc`'s collections only have `Debug`, which is valid Rust for them.
+        let (alloc, format) = match directives.no_std_alloc {
+            true => (DRIVER_NO_STD_ALLOC, "{:?}"),
+            false => ("", "{}"),
+        };
+        DRIVER_NO_STD
+            .replace("{{comptime_alloc}}", alloc)
+            .replace("{{comptime_format}}", format)
+            .replace("{{comptime_program}}", &comptime_program_str)
+            .replace("{{comptime_nonce}}", &comptime_nonce)
+    } else {
+        match render_driver(&driver_template, &comptime_program_str, &comptime_module) {
+            Ok(driver) => driver,
+            Err(e) => err!("invalid comptime driver template: {}", e),
+        }
+    };
+    let comptime_rs = match write_driver(out_dir, &comptime_stem, &driver) {
+        Ok(comptime_rs) => comptime_rs,
+        Err(e) => err!("{}", e),
+    };
+    let keep = env_flag("COMPTIME_KEEP") || debug_all();
+    if keep {
+        let comptime_rs = std::path::absolute(&comptime_rs).unwrap_or(comptime_rs.clone());
+        eprintln!("comptime: keeping `{}`", comptime_rs.display());
+    }
+    let _driver_source = DriverSource {
+        path: &comptime_rs,
+        keep,
+    };
+    // Externs are still found in the out dir, even if it couldn't be written.
+    let artifact_dir = comptime_rs.parent().unwrap();
+    // rustc links to the path as given, but `Command` only runs it on Windows with `.exe`.
+    let comptime_bin =
+        artifact_dir.join(format!("{}{}", comptime_stem, std::env::consts::EXE_SUFFIX));
+
+    let mut rustc_args = filter_rustc_args(&args);
+    if built_deps_dir.is_some() {
+        // Transitive dependencies are found via `-L`, so it must point at the built rlibs too.
+        for arg in rustc_args.iter_mut() {
+            if let Some(dir) = dependency_dir(arg) {
+                *arg = arg.replace(dir, &deps_dir.display().to_string());
+            }
+        }
+    }
+    if let Some(edition) = &directives.edition {
+        if let Err(e) = check_edition(edition, queries) {
+            err!("comptime could not use edition `{}`: {}", edition, e);
+        }
+        remove_option(&mut rustc_args, "--edition");
+        rustc_args.push(format!("--edition={}", edition));
+    }
+    set_cfgs(&mut rustc_args, &directives.cfgs);
+    // The parent's `--check-cfg`s are kept, so the driver expects the same cfgs as the parent,
+    // but its lints, including `unexpected_cfgs`, are capped, so that they can't fail the build
+    // (e.g., under `-D warnings`) or clutter its output.
+    remove_option(&mut rustc_args, "--cap-lints");
+    rustc_args.push("--cap-lints=allow".to_string());
+    // Cargo asks for JSON diagnostics, which are only legible to it, so the driver's errors are
+    // rendered by rustc, unless they're rendered here.
+    remove_option(&mut rustc_args, "--error-format");
+    remove_option(&mut rustc_args, "--json");
+    let json_diagnostics = env_flag("COMPTIME_JSON_DIAGNOSTICS");
+    if json_diagnostics {
+        if cfg!(not(feature = "json")) {
+            err!("COMPTIME_JSON_DIAGNOSTICS=1 requires the `json` feature of comptime");
+        }
+        rustc_args.push("--error-format=json".to_string());
+    }
+    // Cargo builds proc-macro crates with `-C prefer-dynamic`, but the driver is run outside of
+    // rustc, where the dynamic std isn't on the library path.
+    remove_codegen_option(&mut rustc_args, "prefer-dynamic");
+    if directives.no_std {
+        // There's no unwinding runtime without std.
+        remove_codegen_option(&mut rustc_args, "panic");
+        rustc_args.push("-Cpanic=abort".to_string());
+    }
+    set_linker(
+        &mut rustc_args,
+        std::env::var("COMPTIME_LINKER").ok().as_deref(),
+        target.is_some(),
+    );
+    set_target_features(
+        &mut rustc_args,
+        std::env::var("COMPTIME_DRIVER_TARGET_FEATURES")
+            .ok()
+            .as_deref(),
+    );
+    rustc_args.push("--crate-name".to_string());
+    rustc_args.push(driver_crate_name(comptime_disambiguator));
+    rustc_args.push("--crate-type".to_string());
+    rustc_args.push("bin".to_string());
+    rustc_args.push(format!(
+        "--emit=dep-info={},link={}",
+        comptime_rs.with_extension("d").display(),
+        comptime_bin.display()
+    ));
+    let requirements = std::env::var_os("CARGO_MANIFEST_DIR")
+        .and_then(|dir| std::fs::read_to_string(Path::new(&dir).join("Cargo.toml")).ok())
+        .map(|manifest| deps::dependency_requirements(&manifest))
+        .unwrap_or_default();
+    let extern_strategy = match std::env::var("COMPTIME_EXTERN_STRATEGY") {
+        Ok(strategy) => match ExternStrategy::parse(&strategy) {
+            Some(strategy) => strategy,
+            None => err!(
+                "COMPTIME_EXTERN_STRATEGY must be `args`, `scan`, `depinfo`, or `auto`, not `{}`",
+                strategy
+            ),
+        },
+        Err(_) if explicit_externs => ExternStrategy::Args,
+        Err(_) => ExternStrategy::Auto,
+    };
+    let rlib_wait = match std::env::var("COMPTIME_RLIB_WAIT") {
+        Ok(secs) => match secs.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => err!(
+                "COMPTIME_RLIB_WAIT must be a number of seconds, not `{}`",
+                secs
+            ),
+        },
+        Err(_) => DEFAULT_RLIB_WAIT,
+    };
+    match merge_externs(deps_dir, &args, &requirements, extern_strategy, rlib_wait) {
+        Ok(mut externs) => rustc_args.append(&mut externs),
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => err!("comptime {}", e),
+        Err(e) => err!(
+            "comptime could not read the deps dir `{}`: {}",
+            deps_dir.display(),
+            e
+        ),
+    }
+    match comptime_rs.to_str() {
+        Some(comptime_rs) => rustc_args.push(comptime_rs.to_string()),
+        None => err!(
+            "comptime could not compile `{}`, since its path is not utf8",
+            comptime_rs.display()
+        ),
+    }
+
+    let stdin_path = match directives.stdin {
+        true => directives
+            .stdin_path
+            .clone()
+            .or_else(|| std::env::var("COMPTIME_STDIN").ok()),
+        false => None,
+    };
+
+    // Everything that determines the output has been decided by now, so an unchanged program
+    // needn't be compiled or run again.
+    let cache_stdin = if caching {
+        Some(match &stdin_path {
+            Some(stdin_path) => match std::fs::read(stdin_path) {
+                Ok(stdin) => Some(stdin),
+                Err(e) => err!("could not open comptime stdin `{}`: {}", stdin_path, e),
+            },
+            None => None,
+        })
+    } else {
+        None
+    };
+    // The program is run once with each argument of `comptime_map!`, or else once, without one,
+    // but it's only compiled once.
+    let run_args: Vec<Option<&str>> = match &directives.run_args {
+        Some(run_args) => run_args.iter().map(|arg| Some(arg.as_str())).collect(),
+        None => vec![None],
+    };
+    // A driver that was compiled by an earlier build with the same key is run again as it is.
+    cache::record_used(&comptime_bin).ok();
+    let driver_key = cache::driver_key(&driver, &rustc_args);
+    let mut compiled = !debug_all() && cache::is_compiled(&comptime_bin, driver_key);
+    let mut comptime_output_strs = Vec::with_capacity(run_args.len());
+    for run_arg in run_args {
+        let cache_entry = cache_stdin.as_ref().map(|stdin| {
+            let key = cache::output_key(driver_key, stdin.as_deref(), run_arg);
+            cache::entry(artifact_dir, crate_name, key)
+        });
+        let cached = cache_entry
+            .as_deref()
+            .and_then(|entry| cache::lookup(entry, &comptime_nonce));
+        if let Some(cached) = cached {
+            comptime_output_strs.push(cached);
+            continue;
+        }
+        if !compiled {
+            compiled = true;
+            Command::new(tool("RUSTFMT", "rustfmt"))
+                .arg(&comptime_rs)
+                .output()
+                .ok();
+
+            if logs("commands") {
+                eprintln!(
+                    "comptime: compiling `{}`",
+                    command_line(&tool("RUSTC", "rustc"), &rustc_args)
+                );
+            }
+            let mut compile = Command::new(tool("RUSTC", "rustc"));
+            compile.args(&rustc_args);
+            run::die_with_parent(&mut compile);
+            let compile_output = match compile.output() {
+                Ok(output) => output,
+                Err(e) => err!("comptime could not invoke rustc: {}", e),
+            };
+            if !compile_output.status.success() {
+                let stderr = String::from_utf8_lossy(&compile_output.stderr);
+                let missing_crates = missing_crates(&stderr);
+                if !missing_crates.is_empty() {
+                    err!(
+                        "could not compile comptime expr: could not find {}. Crates used by comptime \
+                         must be dependencies of the current crate and must have been built beforehand \
+                         (e.g., by `cargo build`).",
+                        crate_list(&missing_crates)
+                    );
+                }
+                let conflicting_crates = conflicting_crates(&stderr);
+                if !conflicting_crates.is_empty() {
+                    err!("{}", conflicting_crates_message(&conflicting_crates));
+                }
+                if directives.no_std {
+                    if let Some(hint) = no_std_hint(&stderr, directives.no_std_alloc) {
+                        err!("could not compile comptime expr: {}\n\n{}", hint, stderr);
+                    }
+                }
+                #[cfg(feature = "json")]
+                if json_diagnostics {
+                    err!(
+                        "could not compile comptime expr:\n\n{}",
+                        json::render_diagnostics(&String::from_utf8_lossy(&compile_output.stderr))
+                    );
+                }
+                err!(
+                    "{}",
+                    failure_message("could not compile comptime expr", &compile_output.stderr)
+                );
+            }
+            cache::record_compiled(&comptime_bin, driver_key).ok();
+        }
+
+        let manifest_dir = match std::env::var_os("CARGO_MANIFEST_DIR") {
+            Some(manifest_dir) => manifest_dir.into(),
+            None => match std::env::current_dir() {
+                Ok(cwd) => cwd,
+                Err(e) => err!("comptime could not determine the crate root: {}", e),
+            },
+        };
+        let mut run_config = run::RunConfig::new(manifest_dir);
+        run_config.args = run_arg.into_iter().map(Into::into).collect();
+        run_config.env.push((
+            "COMPTIME_SEED".into(),
+            comptime_disambiguator.to_string().into(),
+        ));
+        if directives.stdin {
+            run_config.stdin = Some(match &stdin_path {
+                Some(stdin_path) => match std::fs::File::open(stdin_path) {
+                    Ok(stdin) => stdin.into(),
+                    Err(e) => err!("could not open comptime stdin `{}`: {}", stdin_path, e),
+                },
+                None => Stdio::null(),
+            });
+        }
+        run_config.output_cap = match std::env::var("COMPTIME_MAX_OUTPUT") {
+            Ok(cap) => match cap.parse() {
+                Ok(cap) => Some(cap),
+                Err(_) => err!(
+                    "COMPTIME_MAX_OUTPUT must be a number of bytes, not `{}`",
+                    cap
+                ),
+            },
+            Err(_) => None,
+        };
+        let global_timeout = std::env::var("COMPTIME_TIMEOUT").ok();
+        run_config.timeout = match program_timeout(directives.timeout_secs, global_timeout) {
+            Ok(timeout) => timeout,
+            Err(e) => err!("{}", e),
+        };
+        let stack_mb = match std::env::var("COMPTIME_STACK_MB") {
+            Ok(mb) => match mb.parse::<u64>() {
+                Ok(mb) if mb > 0 => mb,
+                _ => err!(
+                    "COMPTIME_STACK_MB must be a positive number of megabytes, not `{}`",
+                    mb
+                ),
+            },
+            Err(_) => DEFAULT_STACK_MB,
+        };
+        run_config
+            .env
+            .push(("COMPTIME_STACK_MB".into(), stack_mb.to_string().into()));
+        run_config.mem_limit = match std::env::var("COMPTIME_MEM_LIMIT_MB") {
+            Ok(mb) => match mb.parse::<u64>() {
+                Ok(mb) if mb > 0 => Some(mb << 20),
+                _ => err!(
+                    "COMPTIME_MEM_LIMIT_MB must be a positive number of megabytes, not `{}`",
+                    mb
+                ),
+            },
+            Err(_) => None,
+        };
+        if let Ok(sandbox) = std::env::var("COMPTIME_SANDBOX") {
+            run_config.sandbox = sandbox.split_whitespace().map(Into::into).collect();
+        }
+        let pure = env_flag("COMPTIME_PURE");
+        if pure && run_config.sandbox.is_empty() {
+            err!(
+                "COMPTIME_PURE=1 requires a COMPTIME_SANDBOX that denies file and network access \
+                 to comptime programs"
+            );
+        }
+        if logs("commands") {
+            let sandbox = run_config.sandbox.iter().map(|arg| arg.as_os_str());
+            let args: Vec<_> = sandbox
+                .chain([comptime_bin.as_os_str()])
+                .chain(run_config.args.iter().map(|arg| arg.as_os_str()))
+                .map(|arg| arg.to_string_lossy())
+                .collect();
+            eprintln!("comptime: running `{}`", command_line(&args[0], &args[1..]));
+        }
+        let output_cap = run_config.output_cap;
+        let timeout = run_config.timeout;
+        let mem_limit = run_config.mem_limit;
+        let run::RunOutput {
+            output: comptime_output,
+            exceeded_cap,
+            timed_out,
+            exceeded_mem_limit,
+        } = match run_config.run(&comptime_bin) {
+            Ok(output) => output,
+            Err(e) => err!("comptime could not run `{}`: {}", comptime_bin.display(), e),
+        };
+
+        if timed_out {
+            err!(
+                "comptime expr timed out after {}s, {}",
+                timeout.unwrap().as_secs(),
+                match (
+                    directives.timeout_secs,
+                    std::env::var_os("COMPTIME_TIMEOUT")
+                ) {
+                    (Some(_), _) => "set by its timeout",
+                    (None, Some(_)) => "set by COMPTIME_TIMEOUT",
+                    (None, None) => "the default, which COMPTIME_TIMEOUT overrides",
+                }
+            );
+        }
+        if exceeded_mem_limit {
+            err!(
+                "comptime expr exceeded the memory limit of {} MB set by COMPTIME_MEM_LIMIT_MB",
+                mem_limit.unwrap() >> 20
+            );
+        }
+        // The driver usually dies of a broken pipe once its output is cut off, which isn't its
+        // fault.
+        if exceeded_cap {
+            err!(
+                "comptime expr output exceeded the cap of {} bytes set by COMPTIME_MAX_OUTPUT",
+                output_cap.unwrap()
+            );
+        }
+        if is_illegal_instruction(&comptime_output.status) {
+            let hint = match std::env::var("COMPTIME_DRIVER_TARGET_FEATURES") {
+                Ok(features) => format!(
+                    ". The build host must support COMPTIME_DRIVER_TARGET_FEATURES `{}`",
+                    features
+                ),
+                Err(_) => String::new(),
+            };
+            err!(
+                "comptime expr executed an instruction that this machine's CPU doesn't support{}",
+                hint
+            );
+        }
+        if !comptime_output.status.success() {
+            let failure = match pure {
+                true => {
+                    "could not run comptime expr under COMPTIME_PURE=1, which fails programs \
+                         with side effects that COMPTIME_SANDBOX denies"
+                }
+                false => "could not run comptime expr",
+            };
+            let mut message = failure_message(failure, &comptime_output.stderr);
+            if String::from_utf8_lossy(&comptime_output.stderr).contains("has overflowed its stack")
+            {
+                message.push_str(&format!(
+                    "The program ran out of its stack of {} MB, which COMPTIME_STACK_MB sets.",
+                    stack_mb
+                ));
+            }
+            err!("{}", message);
+        }
+        let stderr = String::from_utf8_lossy(&comptime_output.stderr);
+        if shows_warnings() && !stderr.trim().is_empty() {
+            let call_site = proc_macro::Span::call_site();
+            eprint!(
+                "{}",
+                stderr_warnings(
+                    &stderr,
+                    &format!(
+                        "{}:{}:{}",
+                        call_site.file(),
+                        call_site.line(),
+                        call_site.column()
+                    )
+                )
+            );
+        }
+
+        let comptime_output_str = match String::from_utf8(comptime_output.stdout) {
+            Ok(output) => output,
+            Err(_) => err!("comptime expr output was not utf8"),
+        };
+        if let Some(cache_entry) = &cache_entry {
+            if let Err(e) = cache::store(cache_entry, &comptime_output_str) {
+                eprintln!(
+                    "warning: comptime could not write `{}`: {}",
+                    cache_entry.display(),
+                    e
+                );
+            }
+        }
+        comptime_output_strs.push(comptime_output_str);
+    }
+    let mut tracked = Vec::new();
+    let mut results = Vec::with_capacity(comptime_output_strs.len());
+    let mut result_len = 0;
+    for comptime_output_str in &comptime_output_strs {
+        let DriverOutput {
+            tokens: comptime_expr_str,
+            kind: comptime_kind,
+            tracked: run_tracked,
+        } = match parse_driver_output(comptime_output_str, &comptime_nonce) {
+            Ok(output) => output,
+            Err(e) => err!("{}", e),
+        };
+        for path in run_tracked {
+            if !tracked.contains(&path) {
+                tracked.push(path);
+            }
+        }
+        result_len += comptime_expr_str.len();
+
+        // A `TokenStream` result is the user's own code, so it's spliced verbatim rather than
+        // being massaged into an expression.
+        results.push(match comptime_kind {
+            "tokens" => match comptime_expr_str.parse() {
+                Ok(tokens) => tokens,
+                Err(_) => err!("comptime expr output was not valid Rust tokens"),
+            },
+            #[cfg(feature = "json")]
+            "json" => match json::render(comptime_expr_str) {
+                Ok(tokens) => tokens,
+                Err(e) => err!("comptime could not render the JSON result: {}", e),
+            },
+            _ => {
+                let comptime_expr: syn::Expr = match syn::parse_str(comptime_expr_str) {
+                    Ok(expr) => expr,
+                    Err(_) => syn::ExprLit {
+                        attrs: Vec::new(),
+                        lit: syn::LitStr::new(comptime_expr_str, proc_macro2::Span::call_site())
+                            .into(),
+                    }
+                    .into(),
+                };
+                comptime_expr.to_token_stream()
+            }
+        });
+    }
+    let comptime_tokens = match directives.run_args {
+        Some(_) => quote!([#(#results),*]),
+        None => results.pop().unwrap(),
+    };
+
+    // An unsuffixed number takes its type from where it's used, which may be too small for it,
+    // but `overflowing_literals` isn't reported in other crates' macros. Resolving its span at
+    // the program, rather than in the macro, has it reported, at the invocation. Other literals
+    // keep the macro's span, so that lints like `unused_braces` aren't reported about them.
+    let program_span = proc_macro2::Span::call_site().resolved_at(comptime_program.span());
+    let comptime_tokens = map_literals(comptime_tokens, &|lit| {
+        let mut lit = match directives.strip_suffixes {
+            true => strip_suffix(lit),
+            false => lit,
+        };
+        let unsuffixed_number = match syn::Lit::new(lit.clone()) {
+            syn::Lit::Int(int) => int.suffix().is_empty(),
+            syn::Lit::Float(float) => float.suffix().is_empty(),
+            _ => false,
+        };
+        if unsuffixed_number {
+            lit.set_span(program_span);
+        }
+        lit
+    });
+    let comptime_tokens = match directives.span {
+        Some(span) => respan(comptime_tokens, span),
+        None => comptime_tokens,
+    };
+
+    // Artifacts that are committed must be the same on every machine.
+    let committable = env_flag("COMPTIME_COMMITTABLE");
+    if committable {
+        let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR");
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+        let machine_paths: Vec<_> = [Some(out_dir.as_os_str()), manifest_dir.as_deref()]
+            .into_iter()
+            .chain([Some(artifact_dir.as_os_str()), home.as_deref()])
+            .flatten()
+            .map(Path::new)
+            .collect();
+        if let Some(path) = machine_specific(&comptime_tokens.to_string(), &machine_paths) {
+            err!(
+                "comptime expr result contains the machine-specific path `{}` \
+                 (COMPTIME_COMMITTABLE=1)",
+                path
+            );
+        }
+    }
+    let format_artifact = |tokens: &proc_macro2::TokenStream| match committable {
+        true => try_format_tokens(tokens).ok_or(
+            "rustfmt could not format it, and the unformatted result would differ from that of \
+             machines where it can (COMPTIME_COMMITTABLE=1)",
+        ),
+        false => Ok(format_tokens(tokens)),
+    };
+
+    if keep {
+        let call_site = proc_macro::Span::call_site();
+        let sidecar = sidecar_path(
+            artifact_dir,
+            crate_name,
+            &call_site.file(),
+            call_site.line(),
+            call_site.column(),
+        );
+        let artifact = match format_artifact(&comptime_tokens) {
+            Ok(artifact) => artifact,
+            Err(e) => err!("comptime could not write `{}`: {}", sidecar.display(), e),
+        };
+        if let Err(e) = write_sidecar(&sidecar, &artifact) {
+            eprintln!(
+                "warning: comptime could not write `{}`: {}",
+                sidecar.display(),
+                e
+            );
+        }
+    }
+
+    if let Some(golden_dir) = std::env::var_os("COMPTIME_GOLDEN_DIR") {
+        // Relative to the crate's root, like every other path.
+        let golden_dir = std::env::var_os("CARGO_MANIFEST_DIR")
+            .map_or_else(PathBuf::new, PathBuf::from)
+            .join(golden_dir);
+        let program = format!(
+            "{}\n{:?}\n{:?}",
+            comptime_program_str, directives.cfgs, directives.run_args
+        );
+        let golden = golden::path(&golden_dir, crate_name, &program);
+        let artifact = match format_artifact(&comptime_tokens) {
+            Ok(artifact) => artifact,
+            Err(e) => err!("comptime could not check `{}`: {}", golden.display(), e),
+        };
+        if let Err(e) = golden::check(&golden, &artifact, env_flag("COMPTIME_UPDATE_GOLDEN")) {
+            err!("{}", e);
+        }
+    }
+
+    if let Some(ResultType { kind, .. }) = &directives.result_type {
+        if result_lit_kind(&comptime_tokens, *kind) != Some(*kind) {
+            err!(
+                "comptime expr result `{}` is not {} literal, as required by its result type",
+                comptime_tokens,
+                kind.describe()
+            );
+        }
+    }
+
+    let comptime_tokens = match &directives.cast {
+        Some(ty) => {
+            let pointer_width = target_cfg
+                .as_ref()
+                .map_or(usize::BITS, |cfg| cfg.pointer_width);
+            let (cast, truncated) = cast_result(comptime_tokens, ty, pointer_width);
+            if truncated && shows_warnings() {
+                let call_site = proc_macro::Span::call_site();
+                eprintln!(
+                    "warning: comptime expr at {}:{}:{}: result `{}` is truncated by `as {}`",
+                    call_site.file(),
+                    call_site.line(),
+                    call_site.column(),
+                    cast,
+                    ty
+                );
+            }
+            cast
+        }
+        None => comptime_tokens,
+    };
+
+    let is_expr =
+        directives.module.is_none() && syn::parse2::<syn::Expr>(comptime_tokens.clone()).is_ok();
+    let include = directives.include
+        || directives
+            .include_above
+            .is_some_and(|limit| result_len > limit);
+    let comptime_tokens = if let Some(Module { path, attrs, vis }) = &directives.module {
+        if syn::parse2::<syn::File>(comptime_tokens.clone()).is_err() {
+            err!(
+                "comptime_fn module result `{}` is not a sequence of items",
+                comptime_tokens
+            );
+        }
+        let module_rs = module_path(artifact_dir, crate_name, path);
+        let artifact = match format_artifact(&comptime_tokens) {
+            Ok(artifact) => artifact,
+            Err(e) => err!("could not write `{}`: {}", module_rs.display(), e),
+        };
+        if let Err(e) = write_if_changed(&module_rs, &artifact) {
+            err!("could not write `{}`: {}", module_rs.display(), e);
+        }
+        let (name, module_rs) = (path.last().unwrap(), module_rs.to_string_lossy());
+        quote!(#(#attrs)* #[path = #module_rs] #vis mod #name;)
+    } else if include {
+        let comptime_out = comptime_rs.with_extension("out.rs");
+        let artifact = match format_artifact(&comptime_tokens) {
+            Ok(artifact) => artifact,
+            Err(e) => err!("could not write `{}`: {}", comptime_out.display(), e),
+        };
+        if let Err(e) = std::fs::write(&comptime_out, artifact) {
+            err!("could not write `{}`: {}", comptime_out.display(), e);
+        }
+        let comptime_out = comptime_out.to_string_lossy();
+        quote!(include!(#comptime_out))
+    } else {
+        comptime_tokens
+    };
+
+    if tracked.is_empty() && directives.env_keys.is_empty() {
+        return Ok(comptime_tokens);
+    }
+    // Proc macros can't register files and env vars for rebuild, but `include_bytes!` and
+    // `option_env!` do.
+    let env_keys = &directives.env_keys;
+    let tracking = quote!(
+        #(const _: &[u8] = include_bytes!(#tracked);)*
+        #(const _: Option<&str> = option_env!(#env_keys);)*
+    );
+    Ok(if is_expr {
+        quote!({ #tracking #comptime_tokens })
+    } else {
+        quote!(#tracking #comptime_tokens)
+    })
+}
+
+/// The parsed output of the driver.
+#[derive(Debug)]
+struct DriverOutput<'a> {
+    tokens: &'a str,
+    /// `tokens` for a `TokenStream` that should be spliced verbatim, or `value`.
+    kind: &'a str,
+    /// Files that the crate should be rebuilt for.
+    tracked: Vec<&'a str>,
+}
+
+/// The nonce of the driver for the program with `disambiguator`, hashed from `source`, which is
+/// what the driver's source is rendered from. Unlike the disambiguator that names the driver's
+/// artifacts, it differs between programs whose disambiguators collide.
+fn driver_nonce(disambiguator: u64, source: impl Hash) -> String {
+    let mut hasher = DefaultHasher::new();
+    (disambiguator, source).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Parses the output of the driver, which is a line containing the nonce, the tokens, and a
+/// trailing line of tab-separated `key=value` fields.
+fn parse_driver_output<'a>(output: &'a str, nonce: &str) -> Result<DriverOutput<'a>, String> {
+    let malformed = || "comptime expr output was malformed".to_string();
+    let (output_nonce, output) = output.split_once('\n').ok_or_else(malformed)?;
+    if output_nonce != nonce {
+        return Err(format!(
+            "comptime expr output came from a stale or colliding artifact: expected nonce `{}` \
+             but found `{}`. Running `cargo clean` should fix this.",
+            nonce, output_nonce
+        ));
+    }
+    let (tokens, trailer) = output.rsplit_once('\n').ok_or_else(malformed)?;
+    let mut kind = "";
+    let mut tracked = Vec::new();
+    for field in trailer.split('\t') {
+        match field.split_once('=') {
+            Some(("kind", field_kind)) => kind = field_kind,
+            Some(("track", path)) => tracked.push(path),
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(DriverOutput {
+        tokens,
+        kind,
+        tracked,
+    })
+}
+
+/// The file stem of the driver's source and executable for the program with `disambiguator` in
+/// the unit of the crate `crate_name` with `extra_filename`. Cargo builds a crate's lib and its
+/// tests concurrently into the same deps dir, and only their `-C extra-filename`s differ.
+fn driver_stem(crate_name: &str, extra_filename: &str, disambiguator: u64) -> String {
+    format!(
+        "comptime-{}{}-{}",
+        crate_name, extra_filename, disambiguator
+    )
+}
+
+/// The crate name of the driver for the program with `disambiguator`, which is unique like the
+/// names of its artifacts, so that concurrent or repeated invocations never share a crate name.
+fn driver_crate_name(disambiguator: u64) -> String {
+    format!("comptime_bin_{:016x}", disambiguator)
+}
+
+/// Forwards the `stderr` of a comptime program that succeeded as a warning per nonempty line,
+/// which names the `location` of the invocation.
+fn stderr_warnings(stderr: &str, location: &str) -> String {
+    stderr
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| format!("warning: comptime expr at {}: {}\n", location, line))
+        .collect()
+}
+
+/// The error for a failed compile or run of the comptime program with the given `stderr`, which
+/// is decoded lossily, since reporting an error mustn't fail on stray bytes.
+fn failure_message(failure: &str, stderr: &[u8]) -> String {
+    format!("{}:\n\n{}\n", failure, String::from_utf8_lossy(stderr))
+}
+
+/// Replaces each literal in `tokens` with `f` of it.
+fn map_literals(
+    tokens: proc_macro2::TokenStream,
+    f: &impl Fn(proc_macro2::Literal) -> proc_macro2::Literal,
+) -> proc_macro2::TokenStream {
+    use proc_macro2::{Group, TokenTree};
+    tokens
+        .into_iter()
+        .map(|tree| match tree {
+            TokenTree::Group(group) => {
+                let mut mapped = Group::new(group.delimiter(), map_literals(group.stream(), f));
+                mapped.set_span(group.span());
+                TokenTree::Group(mapped)
+            }
+            TokenTree::Literal(lit) => TokenTree::Literal(f(lit)),
+            tree => tree,
+        })
+        .collect()
+}
+
+/// Gives each token in `tokens` the location of `span`, keeping its name resolution, so that errors
+/// in them are reported at `span`.
+fn respan(tokens: proc_macro2::TokenStream, span: proc_macro2::Span) -> proc_macro2::TokenStream {
+    use proc_macro2::{Group, TokenTree};
+    tokens
+        .into_iter()
+        .map(|tree| {
+            let located = tree.span().located_at(span);
+            let mut tree = match tree {
+                TokenTree::Group(group) => {
+                    TokenTree::Group(Group::new(group.delimiter(), respan(group.stream(), span)))
+                }
+                tree => tree,
+            };
+            tree.set_span(located);
+            tree
+        })
+        .collect()
+}
+
+/// Strips the type suffix of a numeric literal, e.g. `5u8` to `5`, so that its type is inferred
+/// where it's used instead. A float like `1f32` keeps being a float, as `1.0`.
+fn strip_suffix(lit: proc_macro2::Literal) -> proc_macro2::Literal {
+    let text = lit.to_string();
+    // `1f32` is an integer literal with a float suffix, as far as syn is concerned.
+    let suffix = match syn::Lit::new(lit.clone()) {
+        syn::Lit::Int(int) => int.suffix().to_string(),
+        syn::Lit::Float(float) => float.suffix().to_string(),
+        _ => String::new(),
+    };
+    let digits = &text[..text.len() - suffix.len()];
+    let unsuffixed = match suffix.as_str() {
+        "" => None,
+        "f32" | "f64" if !digits.contains(['.', 'e', 'E']) => Some(format!("{}.0", digits)),
+        _ => Some(digits.to_string()),
+    };
+    match unsuffixed.and_then(|text| text.parse::<proc_macro2::Literal>().ok()) {
+        Some(mut unsuffixed) => {
+            unsuffixed.set_span(lit.span());
+            unsuffixed
+        }
+        None => lit,
+    }
+}
+
+/// Splits an expression result into the items that `evaluate` added to track files and env vars,
+/// if any, and the expression that the program returned.
+fn untrack(expr: syn::Expr) -> (Vec<syn::Stmt>, syn::Expr) {
+    use syn::{Expr, Item, Stmt};
+    if let Expr::Block(block) = &expr {
+        let stmts = &block.block.stmts;
+        let tracking = stmts.len() > 1
+            && stmts[..stmts.len() - 1]
+                .iter()
+                .all(|stmt| matches!(stmt, Stmt::Item(Item::Const(c)) if c.ident == "_"));
+        if let (true, Some(Stmt::Expr(inner))) = (tracking, stmts.last()) {
+            let tracking = stmts[..stmts.len() - 1].to_vec();
+            return (tracking, inner.clone());
+        }
+    }
+    (Vec::new(), expr)
+}
+
+/// Formats `tokens` with rustfmt, falling back to the unformatted tokens if that fails.
+fn format_tokens(tokens: &proc_macro2::TokenStream) -> String {
+    try_format_tokens(tokens).unwrap_or_else(|| tokens.to_string())
+}
+
+/// Formats `tokens` with rustfmt, if it can. The tokens are formatted as the body of a wrapper
+/// function so that expressions and statements are accepted and not just items.
+fn try_format_tokens(tokens: &proc_macro2::TokenStream) -> Option<String> {
+    let wrapped = format!("fn comptime() {{\n{}\n}}\n", tokens);
+
+    let formatted = Command::new(tool("RUSTFMT", "rustfmt"))
+        .args(["--emit", "stdout", "--edition", "2021"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .and_then(|mut rustfmt| {
+            rustfmt
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(wrapped.as_bytes())?;
+            rustfmt.wait_with_output()
+        });
+    let formatted = match formatted {
+        Ok(output) if output.status.success() => String::from_utf8(output.stdout).ok(),
+        _ => None,
+    };
+    let body = formatted.as_deref().and_then(|f| {
+        f.trim_end()
+            .strip_prefix("fn comptime() {")?
+            .strip_suffix('}')
+    });
+
+    body.map(|body| {
+        body.trim_matches('\n')
+            .lines()
+            .map(|line| format!("{}\n", line.strip_prefix("    ").unwrap_or(line)))
+            .collect()
+    })
+}
+
+/// Returns the first of `machine_paths` or the usual home directories that `text` contains,
+/// including as escaped in a string literal, up to the end of the path it's part of. Paths only
+/// count at the start of a string literal or of a word, so that, e.g., the `/home/` of a URL like
+/// `https://example.com/home/` doesn't.
+fn machine_specific(text: &str, machine_paths: &[&Path]) -> Option<String> {
+    let mut needles: Vec<_> = machine_paths
+        .iter()
+        .filter(|path| path.components().count() > 1) // not just `/`
+        .map(|path| path.display().to_string())
+        .collect();
+    needles.extend(["/home/", "/Users/", r"C:\Users\"].map(String::from));
+    needles
+        .iter()
+        .flat_map(|needle| [needle.clone(), needle.escape_debug().to_string()])
+        .find_map(|needle| {
+            let (start, _) = text.match_indices(&needle).find(|(start, _)| {
+                let before = text[..*start].chars().next_back();
+                before.is_none_or(|c| c.is_whitespace() || "\"'(=,".contains(c))
+            })?;
+            let path = text[start..].split(|c: char| c == '"' || c.is_whitespace());
+            path.map(String::from).next()
+        })
+}
+
+/// Returns where `COMPTIME_KEEP` writes the result of the invocation at `line` and `column` of
+/// `file`, which is `<out dir>/comptime-results/<crate name>/<file>/<line>-<column>.rs`.
+fn sidecar_path(
+    out_dir: &Path,
+    crate_name: &str,
+    file: &str,
+    line: usize,
+    column: usize,
+) -> PathBuf {
+    out_dir
+        .join("comptime-results")
+        .join(crate_name)
+        .join(file.trim_start_matches('/'))
+        .join(format!("{}-{}.rs", line, column))
+}
+
+/// Returns where `#[comptime_fn(module = "a::b")]` writes its result, which is
+/// `<out dir>/comptime-modules/<crate name>/a/b.rs`.
+fn module_path(out_dir: &Path, crate_name: &str, path: &[syn::Ident]) -> PathBuf {
+    let mut module_rs = out_dir.join("comptime-modules").join(crate_name);
+    module_rs.extend(path.iter().map(ToString::to_string));
+    module_rs.set_extension("rs");
+    module_rs
+}
+
+/// Writes `contents` to `path` unless it already holds them, so that its mtime, by which cargo
+/// decides whether the crate that includes it is stale, only changes with its contents.
+fn write_if_changed(path: &Path, contents: &str) -> std::io::Result<()> {
+    if std::fs::read(path).is_ok_and(|existing| existing == contents.as_bytes()) {
+        return Ok(());
+    }
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, contents)
+}
+
+fn write_sidecar(sidecar: &Path, contents: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(sidecar.parent().unwrap())?;
+    std::fs::write(sidecar, contents)
+}
+
+/// Claims the next id of the counter in the file `counter`, which holds the next id to claim. The
+/// file is locked while it's read and incremented, so concurrent rustc processes never claim the
+/// same id, and it stays one small file however many ids are claimed.
+fn next_unique_id(counter: &Path) -> std::io::Result<u64> {
+    use std::io::{Read, Seek, Write};
+    std::fs::create_dir_all(counter.parent().unwrap())?;
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(counter)?;
+    // The lock is released when the file is closed.
+    file.lock()?;
+    let mut next = String::new();
+    file.read_to_string(&mut next)?;
+    let id = match next.trim() {
+        "" => 0,
+        next => next.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("`{}` is not an id", next),
+            )
+        })?,
+    };
+    file.rewind()?;
+    file.set_len(0)?;
+    write!(file, "{}", id + 1)?;
+    Ok(id)
+}
+
+/// Returns whether the environment variable `name` is set to `1`.
+fn env_flag(name: &str) -> bool {
+    std::env::var_os(name).is_some_and(|v| v == "1")
+}
+
+/// Returns whether `COMPTIME_DEBUG_ALL=1`, which turns on every diagnostic: `COMPTIME_KEEP=1`,
+/// each kind of `COMPTIME_LOG`, and neither `COMPTIME_CACHE` nor the reuse of compiled drivers.
+fn debug_all() -> bool {
+    env_flag("COMPTIME_DEBUG_ALL")
+}
+
+/// Returns whether comptime prints the diagnostic `what`, which `COMPTIME_LOG` lists.
+fn logs(what: &str) -> bool {
+    debug_all() || std::env::var("COMPTIME_LOG").is_ok_and(|log| log_includes(&log, what))
+}
+
+/// Returns whether comptime prints warnings about the crate, and forwards the stderr of its
+/// comptime programs. Cargo only sets `CARGO_PRIMARY_PACKAGE` for the crates that it was asked to
+/// build, whose warnings it shows, so dependencies don't clutter the build unless
+/// `COMPTIME_LOG=stderr`.
+fn shows_warnings() -> bool {
+    logs("stderr") || std::env::var_os("CARGO_PRIMARY_PACKAGE").is_some()
+}
+
+/// Returns whether the comma-separated `COMPTIME_LOG` value `log` includes `what`.
+fn log_includes(log: &str, what: &str) -> bool {
+    log.split(',').any(|kind| kind.trim() == what)
+}
+
+/// Converts the numeric `result` to the primitive type `ty` for `@cast`, and returns whether the
+/// conversion truncates it. An integer that fits in `ty` becomes a literal of `ty`, and anything
+/// else becomes `(result) as ty`.
+fn cast_result(
+    result: proc_macro2::TokenStream,
+    ty: &syn::Ident,
+    pointer_width: u32,
+) -> (proc_macro2::TokenStream, bool) {
+    let (negative, lit) = match syn::parse2(result.clone()) {
+        Ok(syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        })) => match *expr {
+            syn::Expr::Lit(syn::ExprLit { lit, .. }) => (true, Some(lit)),
+            _ => (true, None),
+        },
+        Ok(syn::Expr::Lit(syn::ExprLit { lit, .. })) => (false, Some(lit)),
+        _ => (false, None),
+    };
+    let bits = int_bits(&ty.to_string(), pointer_width);
+    let truncated = match (&lit, bits) {
+        (Some(syn::Lit::Int(int)), Some((bits, signed))) => match int.base10_parse::<u128>() {
+            Ok(magnitude) => {
+                let negative = negative && magnitude != 0;
+                let max = match signed {
+                    true => (1u128 << (bits - 1)) - u128::from(!negative),
+                    false if negative => 0,
+                    false => u128::MAX >> (128 - bits),
+                };
+                if magnitude <= max {
+                    let lit = syn::LitInt::new(&format!("{}{}", magnitude, ty), int.span());
+                    return match negative {
+                        true => (quote!(-#lit), false),
+                        false => (quote!(#lit), false),
+                    };
+                }
+                true
+            }
+            Err(_) => true,
+        },
+        (Some(syn::Lit::Float(float)), Some(_)) => float
+            .base10_parse::<f64>()
+            .map_or(true, |float| float.fract() != 0.0),
+        _ => false,
+    };
+    (quote!((#result) as #ty), truncated)
+}
+
+/// Returns the width and signedness of the primitive integer type `ty`, whose `usize` and `isize`
+/// are `pointer_width` bits wide.
+fn int_bits(ty: &str, pointer_width: u32) -> Option<(u32, bool)> {
+    let signed = ty.starts_with('i');
+    let bits = match ty.strip_prefix(['i', 'u'])? {
+        "size" => pointer_width,
+        bits => match bits.parse().ok()? {
+            bits @ (8 | 16 | 32 | 64 | 128) => bits,
+            _ => return None,
+        },
+    };
+    Some((bits, signed))
+}
+
+/// Returns the program that the environment variable `var` names, like `RUSTC`, or else
+/// `default`, which is found on the `PATH`.
+fn tool(var: &str, default: &str) -> String {
+    tool_or(std::env::var(var).ok(), default)
+}
+
+/// Returns the program `configured` by an environment variable, unless it's unset or empty, or
+/// else `default`.
+fn tool_or(configured: Option<String>, default: &str) -> String {
+    configured
+        .filter(|tool| !tool.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Renders the command `program args..` for a log, quoting arguments with whitespace.
+fn command_line(program: &str, args: &[impl AsRef<str>]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(AsRef::as_ref))
+        .map(|arg| match arg.contains(char::is_whitespace) {
+            true => format!("{:?}", arg),
+            false => arg.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Creates rustc's out dir if it doesn't exist yet, which is the case in some build setups, since
+/// rustc itself only creates it once the crate is compiled.
+fn create_out_dir(out_dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(out_dir).map_err(|e| {
+        format!(
+            "comptime could not create rustc's out dir `{}`: {}",
+            out_dir.display(),
+            e
+        )
+    })
+}
+
+/// The driver's source and dep-info, which are removed however evaluation ends, unless they're
+/// kept with `COMPTIME_KEEP`.
+struct DriverSource<'a> {
+    path: &'a Path,
+    keep: bool,
+}
+
+impl Drop for DriverSource<'_> {
+    fn drop(&mut self) {
+        if !self.keep {
+            std::fs::remove_file(self.path).ok();
+            std::fs::remove_file(self.path.with_extension("d")).ok();
+        }
+    }
+}
+
+/// Writes the driver's source, `driver`, to the out dir, or, if it can't be written (e.g., in
+/// sandboxes with read-only outputs), to a dir for it in the system's temp dir, and returns its
+/// path. The driver's other artifacts are written beside it.
+fn write_driver(out_dir: &Path, stem: &str, driver: &str) -> Result<PathBuf, String> {
+    let comptime_rs = out_dir.join(format!("{}.rs", stem));
+    let out_dir_error = match create_out_dir(out_dir) {
+        Ok(()) => match std::fs::write(&comptime_rs, driver) {
+            Ok(()) => return Ok(comptime_rs),
+            Err(e) => format!(
+                "comptime could not write `{}`: {}",
+                comptime_rs.display(),
+                e
+            ),
+        },
+        Err(e) => e,
+    };
+    // Keyed by the out dir, so that the artifacts of different builds are kept apart.
+    let mut hasher = DefaultHasher::new();
+    out_dir.hash(&mut hasher);
+    let fallback_dir = std::env::temp_dir().join(format!("comptime-{:016x}", hasher.finish()));
+    let fallback_rs = fallback_dir.join(format!("{}.rs", stem));
+    create_private_dir(&fallback_dir)
+        .and_then(|_| std::fs::write(&fallback_rs, driver))
+        .map(|_| fallback_rs.clone())
+        .map_err(|e| {
+            format!(
+                "{}, nor could it write `{}` instead: {}",
+                out_dir_error,
+                fallback_rs.display(),
+                e
+            )
+        })
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn geteuid() -> u32;
+}
+
+/// Creates `dir` so that only the current user can access it, or checks that that's so if it
+/// exists. The driver that's built in it is run, so a dir in the shared temp dir that another
+/// user created first is refused.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let metadata = std::fs::symlink_metadata(dir)?;
+        // SAFETY: `geteuid` always succeeds.
+        let uid = unsafe { geteuid() };
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "`{}` is not a dir that only the current user can access",
+                    dir.display()
+                ),
+            ));
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    std::fs::create_dir_all(dir)
+}
+
+/// Returns whether `path` can be written by users other than its owner and group.
+/// Always `false` on platforms without Unix permissions.
+fn is_world_writable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o002 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// The default source of the comptime program, which can be overridden using
+/// `COMPTIME_DRIVER_TEMPLATE`.
+const DRIVER_TEMPLATE: &str = include_str!("driver/template.rs");
+
+/// Support code for `DRIVER_PRINT` that's appended to every comptime program.
+const DRIVER_PRELUDE: &str = include_str!("driver/prelude.rs");
+
+/// The items of the `comptime` module that's appended to every comptime program.
+const DRIVER_COMPTIME: &str = include_str!("driver/comptime.rs");
+
+/// `comptime::parse_file`, which is only added to programs that use it.
+const DRIVER_SOURCE: &str = include_str!("driver/source.rs");
+
+/// Returns how long a program may run: for its `@timeout` seconds, if it has one, or else for the
+/// seconds of `COMPTIME_TIMEOUT`, where `0` is for as long as it likes, or else for the default.
+fn program_timeout(
+    timeout_secs: Option<u64>,
+    global: Option<String>,
+) -> Result<Option<Duration>, String> {
+    match (timeout_secs, global) {
+        (Some(secs), _) => Ok(Some(Duration::from_secs(secs))),
+        (None, Some(secs)) => match secs.parse() {
+            Ok(0) => Ok(None),
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => Err(format!(
+                "COMPTIME_TIMEOUT must be a number of seconds, not `{}`",
+                secs
+            )),
+        },
+        (None, None) => Ok(Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS))),
+    }
+}
+
+/// The megabytes of stack that comptime programs run with, unless `COMPTIME_STACK_MB` is set.
+const DEFAULT_STACK_MB: u64 = 64;
+
+/// The seconds that comptime programs may run for, unless `COMPTIME_TIMEOUT` or `@timeout` is set.
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// The source of `@no_std` comptime programs.
+const DRIVER_NO_STD: &str = include_str!("driver/no_std.rs");
+
+/// The `alloc` setup of `@no_std(alloc)` comptime programs, which is spliced into `DRIVER_NO_STD`.
+const DRIVER_NO_STD_ALLOC: &str = include_str!("driver/no_std_alloc.rs");
+
+/// Prints the result of the comptime program in the format read by `parse_driver_output`.
+const DRIVER_PRINT: &str = r#"{
+    let (tokens, kind) = (&ComptimeKind(&comptime_output)).comptime_emit();
+    print!("{}\n{}\nkind={}", comptime::NONCE, tokens, kind);
+    for path in comptime::tracked() {
+        print!("\ttrack={}", path.display());
+    }
+}"#;
+
+/// Renders the source of the comptime program from a template containing the
+/// `{{comptime_program}}` and `{{comptime_print}}` placeholders. `comptime_module` holds extra
+/// items for the program's `comptime` module.
+fn render_driver(template: &str, program: &str, comptime_module: &str) -> Result<String, String> {
+    for placeholder in ["{{comptime_program}}", "{{comptime_print}}"] {
+        if !template.contains(placeholder) {
+            return Err(format!("missing the `{}` placeholder", placeholder));
+        }
+    }
+    Ok(format!(
+        "{}\n{}\nmod comptime {{\n{}\n{}\n}}\n",
+        template
+            .replace("{{comptime_program}}", program)
+            .replace("{{comptime_print}}", DRIVER_PRINT),
+        DRIVER_PRELUDE,
+        DRIVER_COMPTIME,
+        comptime_module
+    ))
+}
+
+/// Returns whether rustc's `args` make `name` available to the parent crate as an extern.
+fn has_extern(args: &[String], name: &str) -> bool {
+    args.windows(2)
+        .any(|w| w[0] == "--extern" && w[1].split('=').next() == Some(name))
+}
+
+/// Returns whether an rlib of `lib_name` is in one of the parent's `-L dependency=` dirs, which
+/// makes it available to the driver even if the parent doesn't depend on it directly (e.g., if
+/// it's only a build-dependency).
+fn has_dependency_rlib(args: &[String], lib_name: &str) -> bool {
+    let prefix = format!("lib{}-", lib_name);
+    args.iter()
+        .filter_map(|a| dependency_dir(a))
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|de| de.ok())
+        .any(|de| {
+            let fname = de.file_name();
+            let fname = fname.to_string_lossy();
+            fname.starts_with(&prefix) && fname.ends_with(".rlib")
+        })
+}
+
+/// Returns "crate `foo`" or "crates `foo`, `bar`".
+fn crate_list(crates: &[String]) -> String {
+    format!(
+        "{} {}",
+        if crates.len() == 1 { "crate" } else { "crates" },
+        crates
+            .iter()
+            .map(|c| format!("`{}`", c))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Returns the crates that rustc's `stderr` says were found in several incompatible versions,
+/// which happens when the deps dir holds stale builds of them, in order of appearance.
+fn conflicting_crates(stderr: &str) -> Vec<String> {
+    // E0464, E0462, E0460, and E0523
+    const PATTERNS: &[&str] = &[
+        "multiple candidates for `rlib` dependency `",
+        "multiple candidates for `rmeta` dependency `",
+        "multiple candidates for `dylib` dependency `",
+        "found staticlib `",
+        "found possibly newer version of crate `",
+        "found two different crates with name `",
+    ];
+    let mut mentions: Vec<_> = PATTERNS
+        .iter()
+        .flat_map(|pattern| {
+            stderr.match_indices(pattern).map(|(i, _)| {
+                let name = stderr[i + pattern.len()..].split('`').next().unwrap();
+                (i, name)
+            })
+        })
+        .collect();
+    mentions.sort();
+
+    let mut crates: Vec<String> = Vec::new();
+    for (_, name) in mentions {
+        if !name.is_empty() && !crates.iter().any(|c| c == name) {
+            crates.push(name.to_string());
+        }
+    }
+    crates
+}
+
+fn conflicting_crates_message(crates: &[String]) -> String {
+    format!(
+        "could not compile comptime expr: found conflicting builds of {} in the deps dir. Run \
+         `cargo clean` to remove stale ones, or require a single version in `Cargo.toml`.",
+        crate_list(crates)
+    )
+}
+
+/// Explains the failure of an `@no_std` program that uses `std`, or `alloc` without
+/// `@no_std(alloc)`, given the `stderr` of its compile.
+fn no_std_hint(stderr: &str, alloc: bool) -> Option<&'static str> {
+    let uses = |name: &str| {
+        [
+            "use of undeclared crate or module `",
+            "use of unresolved module or unlinked crate `",
+            "unresolved import `",
+        ]
+        .iter()
+        .flat_map(|pattern| ["`", "::"].map(|end| format!("{}{}{}", pattern, name, end)))
+        .any(|mention| stderr.contains(&mention))
+    };
+    if uses("std") {
+        Some(match alloc {
+            true => "`@no_std(alloc)` programs can't use `std`, only `core` and `alloc`.",
+            false => {
+                "`@no_std` programs can't use `std`, only `core`, or also `alloc` with \
+                 `@no_std(alloc)`."
+            }
+        })
+    } else if !alloc && (uses("alloc") || stderr.contains("cannot find type `Vec`")) {
+        Some("`@no_std` programs can only use `alloc` with `@no_std(alloc)`.")
+    } else {
+        None
+    }
+}
+
+/// Returns the crates that rustc's `stderr` says could not be found, in order of appearance.
+fn missing_crates(stderr: &str) -> Vec<String> {
+    const PATTERNS: &[&str] = &[
+        "unresolved import `",
+        "cannot find module or crate `",
+        "use of undeclared crate or module `",
+        "use of unresolved module or unlinked crate `",
+        "can't find crate for `",
+    ];
+    let mut mentions: Vec<_> = PATTERNS
+        .iter()
+        .flat_map(|pattern| {
+            stderr.match_indices(pattern).map(|(i, _)| {
+                let name = stderr[i + pattern.len()..].split('`').next().unwrap();
+                (i, name)
+            })
+        })
+        .collect();
+    mentions.sort();
+
+    let mut crates: Vec<String> = Vec::new();
+    for (_, name) in mentions {
+        // A path like `foo::Bar` is an item missing from a crate that was found.
+        if !name.is_empty()
+            && !name.contains("::")
+            && !["crate", "self", "super", "std", "core", "alloc"].contains(&name)
+            && !crates.iter().any(|c| c == name)
+        {
+            crates.push(name.to_string());
+        }
+    }
+    crates
+}
+
+/// A target's configuration, of which the parts that describe its layout are parsed.
+#[derive(Clone)]
+struct TargetCfg {
+    pointer_width: u32,
+    endian: String,
+    arch: String,
+    os: String,
+    /// Every cfg, e.g. `("target_os", Some("linux"))` or `("unix", None)`.
+    cfgs: Vec<(String, Option<String>)>,
+}
+
+/// Returns the configuration of `target`, or of the host if `None`, as printed by
+/// `rustc --print cfg` with the `codegen` options, like `-Ctarget-feature=+avx2`, that its
+/// `target_feature`s depend on. It's queried once per target in each rustc process, unless it's
+/// among the stored `queries`.
+fn target_cfg(
+    target: Option<&str>,
+    codegen: &[String],
+    queries: Option<&cache::RustcQueries>,
+) -> Result<TargetCfg, String> {
+    type Key = (Option<String>, Vec<String>);
+    static TARGET_CFGS: std::sync::Mutex<BTreeMap<Key, TargetCfg>> =
+        std::sync::Mutex::new(BTreeMap::new());
+    let key = (target.map(str::to_string), codegen.to_vec());
+    if let Some(cfg) = TARGET_CFGS.lock().unwrap().get(&key) {
+        return Ok(cfg.clone());
+    }
+    let cfg = query_target_cfg(target, codegen, queries)?;
+    TARGET_CFGS.lock().unwrap().insert(key, cfg.clone());
+    Ok(cfg)
+}
+
+fn query_target_cfg(
+    target: Option<&str>,
+    codegen: &[String],
+    queries: Option<&cache::RustcQueries>,
+) -> Result<TargetCfg, String> {
+    let mut args = vec!["--print".to_string(), "cfg".to_string()];
+    if let Some(target) = target {
+        args.extend(["--target".to_string(), target.to_string()]);
+    }
+    args.extend_from_slice(codegen);
+    parse_target_cfg(&rustc_print(&args, queries)?)
+}
+
+/// Returns the stdout of rustc run with the `--print` query `args`, or its stderr if it fails,
+/// taking the answer from `queries` if it's stored there.
+fn rustc_print(args: &[String], queries: Option<&cache::RustcQueries>) -> Result<String, String> {
+    let query = || {
+        let output = Command::new(tool("RUSTC", "rustc"))
+            .args(args)
+            .output()
+            .map_err(|e| format!("could not invoke rustc: {}", e))?;
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+        }
+    };
+    match queries {
+        Some(queries) => queries.get(args, query),
+        None => query(),
+    }
+}
+
+fn parse_target_cfg(cfg: &str) -> Result<TargetCfg, String> {
+    let get = |key: &str| {
+        cfg.lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.trim_matches('"').to_string())
+            .ok_or_else(|| format!("`{}` is missing from the target cfg", key))
+    };
+    Ok(TargetCfg {
+        pointer_width: get("target_pointer_width")?
+            .parse()
+            .map_err(|_| "`target_pointer_width` is not a number".to_string())?,
+        endian: get("target_endian")?,
+        arch: get("target_arch")?,
+        os: get("target_os")?,
+        cfgs: cfg
+            .lines()
+            .map(|line| match line.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.trim_matches('"').to_string())),
+                None => (line.to_string(), None),
+            })
+            .collect(),
+    })
+}
+
+/// What a comptime program uses, found by walking its syntax, including the tokens passed to
+/// macros, rather than by matching its text, which would also match strings and comments.
+#[derive(Default)]
+struct ProgramUses {
+    /// Whether it calls `size_of` or `align_of`, which are answered for the host.
+    layout_query: bool,
+    /// Whether it uses `comptime::parse_file`, which needs `syn` to be linked.
+    parse_file: bool,
+    /// Whether it uses `comptime::json`, which needs the `json` feature and `serde_json`.
+    json: bool,
+    /// Whether it imports `comptime::*`, so that the above may be used unqualified.
+    glob: bool,
+    /// The first segments of the paths that it uses.
+    unqualified: HashSet<String>,
+}
+
+impl ProgramUses {
+    fn of(program: &BlockInner) -> Self {
+        let mut uses = Self::default();
+        for stmt in &program.stmts {
+            syn::visit::Visit::visit_stmt(&mut uses, stmt);
+        }
+        if uses.glob {
+            uses.parse_file |= uses.unqualified.contains("parse_file");
+            uses.json |= uses.unqualified.contains("json");
+        }
+        uses
+    }
+
+    /// Notes the use of the path with `segments`.
+    fn use_path(&mut self, segments: &[String]) {
+        let through_comptime = |name: &str| {
+            segments
+                .windows(2)
+                .any(|pair| pair[0] == "comptime" && pair[1] == name)
+        };
+        self.parse_file |= through_comptime("parse_file");
+        self.json |= through_comptime("json");
+        if let Some(first) = segments.first() {
+            self.unqualified.insert(first.clone());
+        }
+        let last = segments.last().map(String::as_str);
+        if matches!(
+            last,
+            Some("size_of" | "align_of" | "size_of_val" | "align_of_val")
+        ) {
+            self.layout_query = true;
+        }
+    }
+
+    /// Notes the items that `tree`, which follows `prefix` in a `use`, imports.
+    fn use_tree(&mut self, prefix: &mut Vec<String>, tree: &syn::UseTree) {
+        match tree {
+            syn::UseTree::Path(path) => {
+                prefix.push(path.ident.to_string());
+                self.use_tree(prefix, &path.tree);
+                prefix.pop();
+            }
+            syn::UseTree::Name(syn::UseName { ident })
+            | syn::UseTree::Rename(syn::UseRename { ident, .. }) => {
+                prefix.push(ident.to_string());
+                self.use_path(prefix);
+                prefix.pop();
+            }
+            syn::UseTree::Glob(_) => self.glob |= prefix.last().is_some_and(|p| p == "comptime"),
+            syn::UseTree::Group(group) => {
+                for tree in &group.items {
+                    self.use_tree(prefix, tree);
+                }
+            }
+        }
+    }
+
+    /// Notes the paths among `tokens`, which are passed to a macro and so aren't parsed.
+    fn use_tokens(&mut self, tokens: proc_macro2::TokenStream) {
+        use proc_macro2::TokenTree;
+        let mut path = Vec::new();
+        let mut colons = 0;
+        for token in tokens {
+            match &token {
+                TokenTree::Ident(ident) if path.is_empty() || colons == 2 => {
+                    path.push(ident.to_string());
+                    colons = 0;
+                    continue;
+                }
+                TokenTree::Punct(punct) if punct.as_char() == ':' && !path.is_empty() => {
+                    colons += 1;
+                    if colons <= 2 {
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            self.use_path(&path);
+            path.clear();
+            colons = 0;
+            match token {
+                TokenTree::Ident(ident) => path.push(ident.to_string()),
+                TokenTree::Group(group) => self.use_tokens(group.stream()),
+                _ => {}
+            }
+        }
+        self.use_path(&path);
+    }
+}
+
+impl<'ast> syn::visit::Visit<'ast> for ProgramUses {
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        let segments: Vec<_> = path.segments.iter().map(|s| s.ident.to_string()).collect();
+        self.use_path(&segments);
+        syn::visit::visit_path(self, path);
+    }
+
+    fn visit_item_use(&mut self, item: &'ast syn::ItemUse) {
+        self.use_tree(&mut Vec::new(), &item.tree);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        self.use_tokens(mac.tokens.clone());
+        syn::visit::visit_macro(self, mac);
+    }
+}
+
+/// Replaces each `cfg!(..)` in `tokens` that only asks about the target, like
+/// `cfg!(target_pointer_width = "32")` or `cfg!(any(unix, target_os = "wasi"))`, with whether
+/// it holds for `target`, since the program is compiled for the host.
+fn apply_target_cfgs(
+    tokens: proc_macro2::TokenStream,
+    target: &TargetCfg,
+) -> proc_macro2::TokenStream {
+    use proc_macro2::{Group, Ident, TokenTree};
+    let trees: Vec<_> = tokens.into_iter().collect();
+    let mut applied = Vec::with_capacity(trees.len());
+    let mut i = 0;
+    while i < trees.len() {
+        // A path like `core::cfg!` is left alone, since it can't be replaced by a `bool`.
+        let after_path = matches!(applied.last(), Some(TokenTree::Punct(p)) if p.as_char() == ':');
+        if let (TokenTree::Ident(ident), Some(TokenTree::Punct(bang)), Some(TokenTree::Group(g))) =
+            (&trees[i], trees.get(i + 1), trees.get(i + 2))
+        {
+            let holds = syn::parse2::<syn::NestedMeta>(g.stream())
+                .ok()
+                .and_then(|predicate| eval_target_cfg(&predicate, target));
+            if let (true, Some(holds)) = (
+                ident == "cfg" && bang.as_char() == '!' && !after_path,
+                holds,
+            ) {
+                applied.push(TokenTree::Ident(Ident::new(
+                    &holds.to_string(),
+                    ident.span(),
+                )));
+                i += 3;
+                continue;
+            }
+        }
+        applied.push(match &trees[i] {
+            TokenTree::Group(group) => {
+                let mut mapped =
+                    Group::new(group.delimiter(), apply_target_cfgs(group.stream(), target));
+                mapped.set_span(group.span());
+                TokenTree::Group(mapped)
+            }
+            tree => tree.clone(),
+        });
+        i += 1;
+    }
+    applied.into_iter().collect()
+}
+
+/// Evaluates the cfg `predicate` for `target`, or returns `None` if it depends on anything but the
+/// target, e.g. a feature, which the program's own cfgs answer.
+fn eval_target_cfg(predicate: &syn::NestedMeta, target: &TargetCfg) -> Option<bool> {
+    let is_target_key = |key: &str| key.starts_with("target_") || key == "unix" || key == "windows";
+    let has = |key: &str, value: Option<&str>| {
+        target
+            .cfgs
+            .iter()
+            .any(|(k, v)| k == key && v.as_deref() == value)
+    };
+    let syn::NestedMeta::Meta(meta) = predicate else {
+        return None;
+    };
+    let key = meta.path().get_ident()?.to_string();
+    match meta {
+        syn::Meta::Path(_) if is_target_key(&key) => Some(has(&key, None)),
+        syn::Meta::NameValue(syn::MetaNameValue {
+            lit: syn::Lit::Str(value),
+            ..
+        }) if is_target_key(&key) => Some(has(&key, Some(&value.value()))),
+        syn::Meta::List(list) => {
+            let mut holds = list.nested.iter().map(|p| eval_target_cfg(p, target));
+            match key.as_str() {
+                "all" => holds.try_fold(true, |all, holds| Some(all && holds?)),
+                "any" => holds.try_fold(false, |any, holds| Some(any || holds?)),
+                "not" if list.nested.len() == 1 => holds.next()?.map(|holds| !holds),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Replaces each `@path` argument with the arguments in the file at `path`, one per line, as
+/// rustc does. Build systems use these argfiles for long command lines.
+fn expand_argfiles(args: impl IntoIterator<Item = String>) -> std::io::Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for (i, arg) in args.into_iter().enumerate() {
+        match arg.strip_prefix('@') {
+            Some(path) if i > 0 => {
+                let argfile = std::fs::read_to_string(path).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("could not read `{}`: {}", path, e))
+                })?;
+                expanded.extend(argfile.lines().map(str::to_string));
+            }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Returns the dir that rustc writes the crate to, and whether the crate's externs are all given
+/// explicitly. Cargo passes `--out-dir`, but build systems like Bazel and Buck name the output
+/// with `-o` instead, and give every extern, so the deps dir needn't be scanned for them.
+fn rustc_out_dir(args: &[String]) -> Option<(&Path, bool)> {
+    let arg = |name| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|p| args.get(p + 1))
+            .map(Path::new)
+    };
+    if let Some(out_dir) = arg("--out-dir") {
+        return Some((out_dir, false));
+    }
+    let output = arg("-o")?;
+    match output.parent() {
+        Some(out_dir) if out_dir != Path::new("") => Some((out_dir, true)),
+        _ => Some((Path::new("."), true)),
+    }
+}
+
+/// Returns the dir of a `-L dependency=dir` arg, which build systems other than cargo may pass as
+/// `-Ldependency=dir`.
+fn dependency_dir(arg: &str) -> Option<&str> {
+    arg.strip_prefix("-L")
+        .unwrap_or(arg)
+        .strip_prefix("dependency=")
+}
+
+/// Returns the rustc args needed to build the comptime executable.
+fn filter_rustc_args(args: &[String]) -> Vec<String> {
+    let mut rustc_args = Vec::with_capacity(args.len());
+    let mut skip = true; // skip the invoked program
+    for arg in args {
+        if skip {
+            skip = false;
+            continue;
+        }
+        if arg == "--crate-type" || arg == "--crate-name" || arg == "--extern" || arg == "-o" {
+            skip = true;
+        } else if arg.ends_with(".rs")
+            || arg.starts_with("--crate-type=")
+            || arg.starts_with("--crate-name=")
+            || arg.starts_with("--extern=")
+            || arg == "--test"
+            || Path::new(arg).file_stem().is_some_and(|stem| stem == "rustc")
+            // ^ `rustc` itself, when invoked via a wrapper like `clippy-driver`
+            || arg.starts_with("--emit")
+        {
+            continue;
+        } else {
+            rustc_args.push(arg.clone());
+        }
+    }
+    rustc_args
+}
+
+/// Removes rustc's option `name` from `rustc_args`, whether it's given as `name value` or as
+/// `name=value`.
+fn remove_option(rustc_args: &mut Vec<String>, name: &str) {
+    if let Some(i) = rustc_args.iter().position(|arg| arg == name) {
+        rustc_args.drain(i..(i + 2).min(rustc_args.len()));
+    }
+    let prefix = format!("{}=", name);
+    rustc_args.retain(|arg| !arg.starts_with(&prefix));
+}
+
+/// Returns whether rustc supports `edition`, according to rustc itself so that new editions work
+/// without changes here.
+fn check_edition(edition: &str, queries: Option<&cache::RustcQueries>) -> Result<(), String> {
+    let args = ["--edition", edition, "--print", "sysroot"].map(str::to_string);
+    match rustc_print(&args, queries) {
+        Ok(_) => Ok(()),
+        Err(stderr) => {
+            let message = stderr.lines().next().unwrap_or_default();
+            Err(message
+                .strip_prefix("error: ")
+                .unwrap_or(message)
+                .to_string())
+        }
+    }
+}
+
+/// Makes the comptime executable link with `linker`, if given. Otherwise, the parent's linker is
+/// kept unless it's cross-compiling, since a target's linker generally can't link for the host.
+fn set_linker(rustc_args: &mut Vec<String>, linker: Option<&str>, cross_compiling: bool) {
+    if linker.is_some() || cross_compiling {
+        remove_codegen_option(rustc_args, "linker");
+    }
+    if let Some(linker) = linker {
+        rustc_args.push("-C".to_string());
+        rustc_args.push(format!("linker={}", linker));
+    }
+}
+
+/// Enables the comma-separated target `features`, if given, e.g. `+avx2,+fma`, for the comptime
+/// executable only. They're added to any that the parent enables.
+fn set_target_features(rustc_args: &mut Vec<String>, features: Option<&str>) {
+    if let Some(features) = features {
+        rustc_args.push("-C".to_string());
+        rustc_args.push(format!("target-feature={}", features));
+    }
+}
+
+/// Returns whether the process that exited with `status` was killed for executing an instruction
+/// that the CPU doesn't support.
+fn is_illegal_instruction(status: &std::process::ExitStatus) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal() == Some(4) // SIGILL
+    }
+    #[cfg(windows)]
+    {
+        status.code() == Some(0xC000001Du32 as i32) // STATUS_ILLEGAL_INSTRUCTION
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = status;
+        false
+    }
+}
+
+/// Removes the codegen option `name`, given as `-C name=..` or `-Cname=..` (or without a value,
+/// for flags like `-C prefer-dynamic`), from `rustc_args`.
+fn remove_codegen_option(rustc_args: &mut Vec<String>, name: &str) {
+    let prefix = format!("{}=", name);
+    let is_option = |a: &str| a == name || a.starts_with(&prefix);
+    let mut i = 0;
+    while i < rustc_args.len() {
+        if rustc_args[i].strip_prefix("-C").is_some_and(is_option) {
+            rustc_args.remove(i);
+        } else if rustc_args[i] == "-C" && rustc_args.get(i + 1).is_some_and(|a| is_option(a)) {
+            rustc_args.drain(i..i + 2);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// The values of the codegen option `name` in `rustc_args`, e.g. `+avx2` for `-C
+/// target-feature=+avx2`, in order.
+fn codegen_options<'a>(rustc_args: &'a [String], name: &str) -> Vec<&'a str> {
+    let prefix = format!("{}=", name);
+    let mut values = Vec::new();
+    for (i, arg) in rustc_args.iter().enumerate() {
+        let option = match arg.strip_prefix("-C") {
+            Some("") => rustc_args.get(i + 1).map(String::as_str),
+            option => option,
+        };
+        if let Some(value) = option.and_then(|option| option.strip_prefix(&prefix)) {
+            values.push(value);
+        }
+    }
+    values
+}
+
+/// The name of the `--cfg` spec `cfg`, e.g. `feature` for `feature="x"`, if it's valid.
+fn cfg_name(cfg: &str) -> Option<String> {
+    match syn::parse_str::<syn::Meta>(cfg).ok()? {
+        syn::Meta::Path(path) => path.get_ident().map(ToString::to_string),
+        syn::Meta::NameValue(syn::MetaNameValue {
+            path,
+            lit: syn::Lit::Str(_),
+            ..
+        }) => path.get_ident().map(ToString::to_string),
+        _ => None,
+    }
+}
+
+/// Replaces the `--cfg`s in `rustc_args` that have the same names as `cfgs` with `cfgs`, so that,
+/// e.g., `feature="x"` makes `x` the only enabled feature.
+fn set_cfgs(rustc_args: &mut Vec<String>, cfgs: &[String]) {
+    let names: Vec<_> = cfgs.iter().filter_map(|cfg| cfg_name(cfg)).collect();
+    let is_replaced = |cfg: &str| cfg_name(cfg).is_some_and(|name| names.contains(&name));
+    let mut i = 0;
+    while i < rustc_args.len() {
+        if rustc_args[i]
+            .strip_prefix("--cfg=")
+            .is_some_and(is_replaced)
+        {
+            rustc_args.remove(i);
+        } else if rustc_args[i] == "--cfg" && rustc_args.get(i + 1).is_some_and(|a| is_replaced(a))
+        {
+            rustc_args.drain(i..i + 2);
+        } else {
+            i += 1;
+        }
+    }
+    for cfg in cfgs {
+        rustc_args.push("--cfg".to_string());
+        rustc_args.push(cfg.clone());
+    }
+}
+
+/// The crates that the driver itself uses, with the requirements that comptime has on them. These
+/// are linked even if the parent crate doesn't depend on them, in which case they're found among
+/// the dependencies of comptime itself.
+const DRIVER_DEPS: [(&str, &str); 2] = [("quote", "1.0"), ("proc_macro2", "1.0")];
+
+/// How the driver's externs are found (`COMPTIME_EXTERN_STRATEGY`). Whatever the strategy, the
+/// driver's own dependencies are found in the deps dirs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExternStrategy {
+    /// Only the parent's `--extern`s.
+    Args,
+    /// Every rlib in the deps dir, the highest version of each crate first.
+    Scan,
+    /// The parent's `--extern`s, and the rlibs in the deps dir of the parent's dependencies whose
+    /// versions, as recorded by their dep-info, are compatible with the parent's requirements.
+    DepInfo,
+    /// What `DepInfo` finds, and then what `Scan` finds of the other crates, in one pass over the
+    /// deps dir, whose rlibs of compatible versions are sorted first.
+    Auto,
+}
+
+impl ExternStrategy {
+    fn parse(strategy: &str) -> Option<Self> {
+        Some(match strategy {
+            "args" => Self::Args,
+            "scan" => Self::Scan,
+            "depinfo" => Self::DepInfo,
+            "auto" => Self::Auto,
+            _ => return None,
+        })
+    }
+}
+
+fn merge_externs(
+    deps_dir: &Path,
+    args: &[String],
+    requirements: &HashMap<String, String>,
+    strategy: ExternStrategy,
+    rlib_wait: Duration,
+) -> std::io::Result<Vec<String>> {
+    // Ordered so that the rustc invocation, and hence the cache key, is the same across builds.
+    let mut cargo_rlibs = BTreeMap::new(); // libfoo -> /path/to/libfoo-12345.rlib
+    let mut renamed_driver_deps = Vec::new();
+    // libfoo -> the rlib of crate `foo` that the parent links, under whatever name.
+    let mut extern_rlibs = HashMap::new();
+    let mut next_is_extern = false;
+    let checking = is_checking(args);
+    for arg in args.iter().filter(|_| strategy != ExternStrategy::Scan) {
+        // A sysroot crate is passed without a path, e.g. `--extern proc_macro` to a proc-macro
+        // crate, and isn't needed by the driver.
+        if let (true, Some((lib_name, path))) = (next_is_extern, arg.split_once('=')) {
+            let rlib = match Path::new(path) {
+                path if path.extension().is_some_and(|ext| ext == "rmeta") => {
+                    rlib_of_rmeta(path, checking, rlib_wait)?
+                }
+                path => Some(path.to_path_buf()),
+            };
+            if let Some(path) = rlib.filter(|path| path.extension().is_some_and(|e| e == "rlib")) {
+                let path = path.as_path();
+                cargo_rlibs.insert(format!("lib{}", lib_name), path.to_path_buf());
+                // A driver dep that the parent renamed, e.g. `q = { package = "quote" }`, is also
+                // linked under its own name, which the driver uses, as the same rlib.
+                let crate_name = path
+                    .file_name()
+                    .and_then(|name| name.to_str()?.strip_prefix("lib")?.rsplit_once('-'))
+                    .map(|(crate_name, _)| crate_name);
+                if let Some(crate_name) = crate_name {
+                    extern_rlibs.insert(format!("lib{}", crate_name), path.to_path_buf());
+                }
+                if let Some(crate_name) = crate_name.filter(|name| {
+                    *name != lib_name && DRIVER_DEPS.iter().any(|(dep, _)| dep == name)
+                }) {
+                    renamed_driver_deps.push((format!("lib{}", crate_name), path.to_path_buf()));
+                }
+            }
+        }
+        next_is_extern = arg == "--extern";
+    }
+    // The parent's own extern of that name, if any, is linked instead.
+    for (lib_name, path) in renamed_driver_deps {
+        cargo_rlibs.entry(lib_name).or_insert(path);
+    }
+
+    let rlibs = match strategy {
+        ExternStrategy::Scan => sorted_rlibs(deps_dir, &HashMap::new()),
+        _ => sorted_rlibs(deps_dir, requirements),
+    }?;
+    let is_compatible = |lib_name: &str, rlib: &Path| {
+        let requirement = requirements.get(lib_name.strip_prefix("lib").unwrap_or(lib_name));
+        match (requirement, rlib_version(rlib)) {
+            (Some(requirement), Some(version)) => is_semver_compatible(requirement, &version),
+            _ => false,
+        }
+    };
+    // Of the rlibs of a crate that the parent links, the scan only finds the parent's, since
+    // another version's types wouldn't match those of the crates that use it.
+    let parents_rlib = |lib_name: &str, path: PathBuf| match extern_rlibs.get(lib_name) {
+        Some(extern_rlib) => extern_rlib.clone(),
+        None => path,
+    };
+    for (lib_name, path) in rlibs {
+        let path = parents_rlib(&lib_name, path);
+        let scanned = match strategy {
+            ExternStrategy::Args => false,
+            ExternStrategy::DepInfo => is_compatible(&lib_name, &path),
+            ExternStrategy::Scan | ExternStrategy::Auto => true,
+        };
+        if let (true, Entry::Vacant(ve)) = (scanned, cargo_rlibs.entry(lib_name)) {
+            ve.insert(path);
+        }
+    }
+
+    // When cross-compiling, comptime's own dependencies are only in the host's deps dir.
+    let dependency_dirs = args.iter().filter_map(|a| dependency_dir(a)).map(Path::new);
+    for dir in [deps_dir].into_iter().chain(dependency_dirs) {
+        for (lib_name, path) in sorted_rlibs(dir, requirements).unwrap_or_default() {
+            let is_driver_dep = DRIVER_DEPS
+                .iter()
+                .any(|(name, _)| lib_name.strip_prefix("lib") == Some(name));
+            if let (true, Entry::Vacant(ve)) = (is_driver_dep, cargo_rlibs.entry(lib_name)) {
+                ve.insert(path);
+            }
+        }
+    }
+
+    let mut merged_externs = Vec::with_capacity(cargo_rlibs.len() * 2);
+    for (lib_name, path) in cargo_rlibs.iter() {
+        merged_externs.push("--extern".to_string());
+        merged_externs.push(format!(
+            "{}={}",
+            &lib_name.strip_prefix("lib").unwrap_or(lib_name),
+            path.display()
+        ));
+    }
+
+    Ok(merged_externs)
+}
+
+/// How long to wait for the rlib of an `.rmeta` extern to be written, unless `COMPTIME_RLIB_WAIT`
+/// is set. It's normally written soon after the rmeta, and not at all if the dependency's codegen
+/// failed.
+const DEFAULT_RLIB_WAIT: Duration = Duration::from_secs(30);
+
+/// Returns whether rustc is only checking the crate, in which case there are no rlibs of its
+/// dependencies, only their metadata.
+fn is_checking(args: &[String]) -> bool {
+    args.iter()
+        .find(|a| a.starts_with("--emit"))
+        .is_some_and(|emit| !emit.contains("link"))
+}
+
+/// Returns the rlib beside the metadata-only extern `rmeta`, which cargo passes under pipelined
+/// compilation. The dependency's rustc writes the rmeta first and may still be writing the rlib,
+/// so unless the crate is only being checked, this waits up to `wait` for an rlib no older than
+/// the rmeta, give or take a second, since rustc writes the rlib first without pipelining. A
+/// stale rlib isn't linked, but in a check, whatever rlib there is is.
+fn rlib_of_rmeta(rmeta: &Path, checking: bool, wait: Duration) -> std::io::Result<Option<PathBuf>> {
+    let rlib = rmeta.with_extension("rlib");
+    if checking {
+        return Ok(rlib.exists().then_some(rlib));
+    }
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let is_fresh = || match (modified(&rlib), modified(rmeta)) {
+        (Some(rlib), Some(rmeta)) => rlib + Duration::from_secs(1) >= rmeta,
+        _ => false,
+    };
+    let deadline = std::time::Instant::now() + wait;
+    while !is_fresh() {
+        if std::time::Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "could not link `{}`: its rlib wasn't written within {}s, which \
+                     COMPTIME_RLIB_WAIT sets, e.g. because the dependency failed to build",
+                    rmeta.display(),
+                    wait.as_secs()
+                ),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(Some(rlib))
+}
+
+/// Returns the `(libfoo, path)` of the rlibs in `dir`, preferring the highest version compatible
+/// with the parent's requirement, if the requirement and versions are known. Rlibs of the same
+/// version, or of unknown versions, are ordered newest first, and then by name, so that the order
+/// is the same wherever modification times are.
+fn sorted_rlibs(
+    dir: &Path,
+    requirements: &HashMap<String, String>,
+) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut dep_dirents = Vec::new();
+    for de in std::fs::read_dir(dir)? {
+        let de = de?;
+        let p = de.path();
+        let fname = p
+            .file_name()
+            .and_then(|fname| fname.to_str())
+            .unwrap_or_default();
+        // Cargo names rlibs `lib<name>-<hash>.rlib`, and other files are ignored.
+        if fname.starts_with("lib") && fname.ends_with(".rlib") && fname.contains('-') {
+            dep_dirents.push(de);
+        }
+    }
+    dep_dirents.sort_by_cached_key(|de| {
+        let path = de.path();
+        let fname = path.file_name().unwrap().to_str().unwrap();
+        let lib_name = fname
+            .rsplit_once('-')
+            .unwrap()
+            .0
+            .strip_prefix("lib")
+            .unwrap();
+        let requirement = requirements.get(lib_name).map(String::as_str).or_else(|| {
+            DRIVER_DEPS
+                .iter()
+                .find(|(name, _)| *name == lib_name)
+                .map(|(_, requirement)| *requirement)
+        });
+        let compatible = match (requirement, rlib_version(&path)) {
+            (Some(requirement), Some(version)) => is_semver_compatible(requirement, &version),
+            _ => true,
+        };
+        let version = rlib_version(&path).as_deref().and_then(version_parts);
+        let modified = de.metadata().and_then(|m| m.modified()).ok();
+        std::cmp::Reverse((compatible, version, modified, de.file_name()))
+    });
+
+    Ok(dep_dirents
+        .into_iter()
+        .map(|dirent| {
+            let path = dirent.path();
+            let fname = path.file_name().unwrap().to_str().unwrap();
+            let lib_name = fname.rsplit_once('-').unwrap().0.to_string();
+            // ^ reverse "libfoo-disambiguator" then split off the disambiguator
+            (lib_name, path)
+        })
+        .collect())
+}
+
+/// Returns the version of the crate that `rlib` was built from, as recorded by the package
+/// directory (e.g., `rand-0.7.3`) of its sources in the dep-info that cargo writes beside it.
+fn rlib_version(rlib: &Path) -> Option<String> {
+    let stem = rlib.file_stem()?.to_str()?;
+    let dep_info =
+        std::fs::read_to_string(rlib.with_file_name(format!("{}.d", stem.strip_prefix("lib")?)))
+            .ok()?;
+    let lib_name = stem.rsplit_once('-')?.0.strip_prefix("lib")?;
+    let source = dep_info
+        .lines()
+        .find_map(|line| line.split_once(": ")?.1.split(' ').next())?;
+    Path::new(source).components().find_map(|component| {
+        let component = component.as_os_str().to_str()?;
+        let version = component.get(lib_name.len() + 1..)?;
+        (component.get(..=lib_name.len())?.replace('-', "_") == format!("{}_", lib_name)
+            && version.starts_with(|c: char| c.is_ascii_digit()))
+        .then(|| version.to_string())
+    })
+}
+
+/// Returns the numeric components of `version`, ignoring any pre-release or build metadata.
+fn version_parts(version: &str) -> Option<Vec<u64>> {
+    version
+        .split(['-', '+'])
+        .next()?
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Returns whether `version` satisfies the cargo `requirement`. Only default (caret) and `=`
+/// requirements of up to three components are understood; anything else is assumed to be
+/// satisfied.
+fn is_semver_compatible(requirement: &str, version: &str) -> bool {
+    let requirement = requirement.trim();
+    let (exact, requirement) = match requirement.strip_prefix('=') {
+        Some(requirement) => (true, requirement.trim()),
+        None => (false, requirement.strip_prefix('^').unwrap_or(requirement)),
+    };
+    let (Some(required), Some(version)) = (version_parts(requirement), version_parts(version))
+    else {
+        return true;
+    };
+    // A requirement has at most three components, like the versions it's compared with.
+    if required.len() > 3 {
+        return true;
+    }
+    let version: Vec<_> = (0..3)
+        .map(|i| version.get(i).copied().unwrap_or(0))
+        .collect();
+    if exact {
+        return required.iter().zip(&version).all(|(r, v)| r == v);
+    }
+    // The leftmost nonzero component (or the last one given) must match, and the rest mustn't be
+    // older.
+    let significant = required
+        .iter()
+        .position(|&part| part != 0)
+        .unwrap_or(required.len() - 1);
+    let padded: Vec<_> = (0..3)
+        .map(|i| required.get(i).copied().unwrap_or(0))
+        .collect();
+    required[..=significant] == version[..=significant] && version >= padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{write_aged, TempDir};
+
+    #[test]
+    fn test_format_tokens() {
+        let tokens = quote!(vec![(1, "one"), (2, "two")]
+            .into_iter()
+            .map(|(n, s)| format!("{}{}", n, s)));
+        assert_eq!(
+            format_tokens(&tokens),
+            "vec![(1, \"one\"), (2, \"two\")]\n    .into_iter()\n    .map(|(n, s)| format!(\"{}{}\", n, s))\n"
+        );
+    }
+
+    #[test]
+    fn test_committable() {
+        // A result without machine-specific paths is committable on both machines.
+        let alice = [
+            Path::new("/home/alice/app/target/debug/deps"),
+            Path::new("/home/alice/app"),
+        ];
+        let ci = [
+            Path::new("/build/app/target/debug/deps"),
+            Path::new("/build/app"),
+        ];
+        let tokens = quote!(
+            const GREETING: &str = "hello";
+        );
+        assert_eq!(machine_specific(&tokens.to_string(), &alice), None);
+        assert_eq!(machine_specific(&tokens.to_string(), &ci), None);
+        assert_eq!(
+            try_format_tokens(&tokens).as_deref(),
+            Some("const GREETING: &str = \"hello\";\n")
+        );
+
+        let leaky = quote!(
+            const DATA: &str = include_str!("/build/app/data.txt");
+        );
+        assert_eq!(
+            machine_specific(&leaky.to_string(), &ci).as_deref(),
+            Some("/build/app/data.txt")
+        );
+        let windows = quote!(
+            const DATA: &str = "C:\\Users\\bob\\data.txt";
+        );
+        assert_eq!(
+            machine_specific(&windows.to_string(), &ci).as_deref(),
+            Some(r"C:\\Users\\bob\\data.txt")
+        );
+        assert_eq!(
+            machine_specific("\"/home/carol/notes\"", &[]).as_deref(),
+            Some("/home/carol/notes")
+        );
+        // Home directories only count as the start of a path.
+        let url = quote!(
+            const DOCS: &str = "https://example.com/home/users/C:\\Users\\x";
+        );
+        assert_eq!(machine_specific(&url.to_string(), &ci), None);
+        assert_eq!(
+            machine_specific("see /Users/dave/x", &[]).as_deref(),
+            Some("/Users/dave/x")
+        );
+    }
+
+    #[test]
+    fn test_merge_externs_ignores_comptime_artifacts() {
+        let deps_dir = TempDir::new("deps");
+        for artifact in [
+            "libfoo-0123.rlib",
+            "comptime-crate_a-42",
+            "comptime-crate_a-42.rs",
+            "comptime-crate_b-42",
+            "comptime-crate_b-42.d",
+            "libbar.rlib",
+        ] {
+            std::fs::write(deps_dir.join(artifact), "").unwrap();
+        }
+        // Proc-macro crates are passed the sysroot's `proc_macro`, which has no path.
+        let args = ["--extern".to_string(), "proc_macro".to_string()];
+        let externs = merge_externs(
+            &deps_dir,
+            &args,
+            &HashMap::new(),
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        let missing = deps_dir.join("missing");
+        assert!(merge_externs(
+            &missing,
+            &args,
+            &HashMap::new(),
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT
+        )
+        .is_err());
+        assert_eq!(
+            externs,
+            [
+                "--extern".to_string(),
+                format!("foo={}", deps_dir.join("libfoo-0123.rlib").display())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_externs_prefers_compatible_version() {
+        let deps_dir = TempDir::new("semver");
+        // The incompatible version is the newest.
+        for (age, (hash, version)) in [("0123", "0.7.3"), ("4567", "0.8.5")]
+            .iter()
+            .rev()
+            .enumerate()
+        {
+            let rlib = deps_dir.join(format!("librand-{}.rlib", hash));
+            write_aged(&rlib, age as u64);
+            std::fs::write(
+                deps_dir.join(format!("rand-{}.d", hash)),
+                format!(
+                    "{}: /registry/rand-{}/src/lib.rs\n\n/registry/rand-{}/src/lib.rs:\n",
+                    rlib.display(),
+                    version,
+                    version
+                ),
+            )
+            .unwrap();
+        }
+        let requirements = HashMap::from([("rand".to_string(), "0.7".to_string())]);
+        let externs = merge_externs(
+            &deps_dir,
+            &[],
+            &requirements,
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        let newest = merge_externs(
+            &deps_dir,
+            &[],
+            &HashMap::new(),
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        assert_eq!(
+            externs[1],
+            format!("rand={}", deps_dir.join("librand-0123.rlib").display())
+        );
+        assert_eq!(
+            newest[1],
+            format!("rand={}", deps_dir.join("librand-4567.rlib").display())
+        );
+    }
+
+    #[test]
+    fn test_merge_externs_disambiguates_versions() {
+        let deps_dir = TempDir::new("versions");
+        // The lower versions are the newest.
+        for (age, (name, hash, version)) in [
+            ("rand", "0123", "0.8.5"),
+            ("rand", "4567", "0.8.1"),
+            ("foo", "89ab", "1.0.0"),
+            ("foo", "cdef", "1.1.0"),
+        ]
+        .into_iter()
+        .rev()
+        .enumerate()
+        {
+            let rlib = deps_dir.join(format!("lib{}-{}.rlib", name, hash));
+            write_aged(&rlib, age as u64);
+            std::fs::write(
+                deps_dir.join(format!("{}-{}.d", name, hash)),
+                format!(
+                    "{}: /registry/{}-{}/src/lib.rs\n",
+                    rlib.display(),
+                    name,
+                    version
+                ),
+            )
+            .unwrap();
+        }
+        // The parent links the older `foo`, under another name.
+        let foo = deps_dir.join("libfoo-89ab.rlib");
+        let args = ["--extern".to_string(), format!("f={}", foo.display())];
+        let externs = merge_externs(
+            &deps_dir,
+            &args,
+            &HashMap::new(),
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        assert_eq!(
+            externs,
+            [
+                "--extern".to_string(),
+                format!("f={}", foo.display()),
+                "--extern".to_string(),
+                format!("foo={}", foo.display()),
+                "--extern".to_string(),
+                format!("rand={}", deps_dir.join("librand-0123.rlib").display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_externs_strategies() {
+        let deps_dir = TempDir::new("strategies");
+        for (age, (hash, version)) in [("0123", "0.7.3"), ("4567", "0.8.5")]
+            .iter()
+            .rev()
+            .enumerate()
+        {
+            let rlib = deps_dir.join(format!("librand-{}.rlib", hash));
+            write_aged(&rlib, age as u64);
+            std::fs::write(
+                deps_dir.join(format!("rand-{}.d", hash)),
+                format!(
+                    "{}: /registry/rand-{}/src/lib.rs\n",
+                    rlib.display(),
+                    version
+                ),
+            )
+            .unwrap();
+        }
+        for rlib in ["libfoo-89ab.rlib", "libbar-cdef.rlib", "libquote-0000.rlib"] {
+            std::fs::write(deps_dir.join(rlib), "").unwrap();
+        }
+        let args = [
+            "--extern".to_string(),
+            format!("bar={}", deps_dir.join("libbar-cdef.rlib").display()),
+        ];
+        let requirements = HashMap::from([("rand".to_string(), "0.7".to_string())]);
+        let externs = |strategy| {
+            merge_externs(&deps_dir, &args, &requirements, strategy, DEFAULT_RLIB_WAIT)
+                .unwrap()
+                .chunks(2)
+                .map(|w| {
+                    let (name, path) = w[1].split_once('=').unwrap();
+                    format!(
+                        "{}={}",
+                        name,
+                        Path::new(path).file_name().unwrap().to_str().unwrap()
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let strategies = [
+            ExternStrategy::Args,
+            ExternStrategy::Scan,
+            ExternStrategy::DepInfo,
+            ExternStrategy::Auto,
+        ]
+        .map(externs);
+        let [args, scan, depinfo, auto] = strategies;
+        assert_eq!(args, ["bar=libbar-cdef.rlib", "quote=libquote-0000.rlib"]);
+        assert_eq!(
+            scan,
+            [
+                "bar=libbar-cdef.rlib",
+                "foo=libfoo-89ab.rlib",
+                "quote=libquote-0000.rlib",
+                "rand=librand-4567.rlib"
+            ]
+        );
+        assert_eq!(
+            depinfo,
+            [
+                "bar=libbar-cdef.rlib",
+                "quote=libquote-0000.rlib",
+                "rand=librand-0123.rlib"
+            ]
+        );
+        assert_eq!(
+            auto,
+            [
+                "bar=libbar-cdef.rlib",
+                "foo=libfoo-89ab.rlib",
+                "quote=libquote-0000.rlib",
+                "rand=librand-0123.rlib"
+            ]
+        );
+        // `auto` links what `depinfo` finds, which includes the `args`, and what `scan` finds of
+        // the other crates.
+        let by_name = |e: &String| (e.split_once('=').unwrap().0.to_string(), e.clone());
+        let mut fallback: BTreeMap<_, _> = scan.iter().map(by_name).collect();
+        fallback.extend(depinfo.iter().map(by_name));
+        assert_eq!(auto, fallback.into_values().collect::<Vec<_>>());
+        assert_eq!(
+            ExternStrategy::parse("depinfo"),
+            Some(ExternStrategy::DepInfo)
+        );
+        assert_eq!(ExternStrategy::parse("dep-info"), None);
+    }
+
+    #[test]
+    fn test_merge_externs_links_rlibs_of_rmetas() {
+        let deps_dir = TempDir::new("rmeta");
+        let (foo, bar) = (deps_dir.join("libfoo-0123"), deps_dir.join("libbar-4567"));
+        for rmeta in [&foo, &bar] {
+            std::fs::write(rmeta.with_extension("rmeta"), "").unwrap();
+        }
+        let args = |emit: &str| {
+            let mut args = vec![emit.to_string()];
+            for (name, rmeta) in [("foo", &foo), ("bar", &bar)] {
+                args.push("--extern".to_string());
+                args.push(format!(
+                    "{}={}",
+                    name,
+                    rmeta.with_extension("rmeta").display()
+                ));
+            }
+            args
+        };
+        // A check has no rlibs to wait for.
+        let checked = merge_externs(
+            &deps_dir,
+            &args("--emit=dep-info,metadata"),
+            &HashMap::new(),
+            ExternStrategy::Args,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        // The rlib of `foo` is still being written, as under pipelining.
+        std::fs::write(bar.with_extension("rlib"), "").unwrap();
+        let writer = std::thread::spawn({
+            let foo = foo.with_extension("rlib");
+            move || {
+                std::thread::sleep(Duration::from_millis(100));
+                std::fs::write(foo, "").unwrap();
+            }
+        });
+        let built = merge_externs(
+            &deps_dir,
+            &args("--emit=dep-info,metadata,link"),
+            &HashMap::new(),
+            ExternStrategy::Args,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        writer.join().unwrap();
+        assert!(checked.is_empty());
+        assert_eq!(
+            built,
+            [
+                "--extern".to_string(),
+                format!("bar={}", bar.with_extension("rlib").display()),
+                "--extern".to_string(),
+                format!("foo={}", foo.with_extension("rlib").display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rlib_of_rmeta() {
+        let deps_dir = TempDir::new("rlib-of-rmeta");
+        let (rmeta, rlib) = (
+            deps_dir.join("libfoo-0123.rmeta"),
+            deps_dir.join("libfoo-0123.rlib"),
+        );
+        std::fs::write(&rlib, "").unwrap();
+        let stale = std::time::SystemTime::now() - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&rlib)
+            .unwrap()
+            .set_modified(stale)
+            .unwrap();
+        std::fs::write(&rmeta, "").unwrap();
+
+        // The rlib of an earlier build is never linked in place of the rmeta's.
+        let wait = Duration::from_millis(100);
+        let e = rlib_of_rmeta(&rmeta, false, wait).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(
+            e.to_string().contains(&rmeta.display().to_string()),
+            "{}",
+            e
+        );
+        assert!(e.to_string().contains("COMPTIME_RLIB_WAIT"), "{}", e);
+        // A check has nothing fresher.
+        assert_eq!(
+            rlib_of_rmeta(&rmeta, true, wait).unwrap(),
+            Some(rlib.clone())
+        );
+        std::fs::write(&rlib, "").unwrap();
+        assert_eq!(rlib_of_rmeta(&rmeta, false, wait).unwrap(), Some(rlib));
+    }
+
+    #[test]
+    fn test_merge_externs_links_renamed_driver_deps() {
+        let deps_dir = TempDir::new("renamed");
+        // The rlib that isn't the parent's is the newest.
+        write_aged(&deps_dir.join("libquote-0123.rlib"), 1);
+        write_aged(&deps_dir.join("libquote-4567.rlib"), 0);
+        let quote = deps_dir.join("libquote-0123.rlib");
+        let args = ["--extern".to_string(), format!("q={}", quote.display())];
+        let externs = merge_externs(
+            &deps_dir,
+            &args,
+            &HashMap::new(),
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        assert_eq!(
+            externs,
+            [
+                "--extern".to_string(),
+                format!("q={}", quote.display()),
+                "--extern".to_string(),
+                format!("quote={}", quote.display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_externs_links_driver_deps() {
+        let dir = TempDir::new("driver-deps");
+        let (deps_dir, host_deps_dir) = (dir.join("target"), dir.join("host"));
+        std::fs::create_dir_all(&deps_dir).unwrap();
+        std::fs::create_dir_all(&host_deps_dir).unwrap();
+        std::fs::write(deps_dir.join("libfoo-0123.rlib"), "").unwrap();
+        for rlib in [
+            "libquote-4567.rlib",
+            "libproc_macro2-89ab.rlib",
+            "libbar-cdef.rlib",
+        ] {
+            std::fs::write(host_deps_dir.join(rlib), "").unwrap();
+        }
+        let args = [
+            "-L".to_string(),
+            format!("dependency={}", deps_dir.display()),
+            "-L".to_string(),
+            format!("dependency={}", host_deps_dir.display()),
+        ];
+        let externs = merge_externs(
+            &deps_dir,
+            &args,
+            &HashMap::new(),
+            ExternStrategy::Auto,
+            DEFAULT_RLIB_WAIT,
+        )
+        .unwrap();
+        assert_eq!(
+            externs,
+            [
+                "--extern".to_string(),
+                format!("foo={}", deps_dir.join("libfoo-0123.rlib").display()),
+                "--extern".to_string(),
+                format!(
+                    "proc_macro2={}",
+                    host_deps_dir.join("libproc_macro2-89ab.rlib").display()
+                ),
+                "--extern".to_string(),
+                format!(
+                    "quote={}",
+                    host_deps_dir.join("libquote-4567.rlib").display()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let sidecar = sidecar_path(Path::new("/target/debug/deps"), "app", "src/main.rs", 3, 17);
+        assert_eq!(
+            sidecar,
+            Path::new("/target/debug/deps/comptime-results/app/src/main.rs/3-17.rs")
+        );
+
+        let out_dir = TempDir::new("sidecar");
+        let sidecar = sidecar_path(&out_dir, "app", "src/main.rs", 3, 17);
+        write_sidecar(&sidecar, &format_tokens(&quote!([1u8, 2u8]))).unwrap();
+        let written = std::fs::read_to_string(&sidecar).unwrap();
+        assert_eq!(written, "[1u8, 2u8]\n");
+    }
+
+    #[test]
+    fn test_module_path() {
+        let path = parse_module_path(&syn::parse_quote!("gen::tables")).unwrap();
+        assert_eq!(
+            module_path(Path::new("/target/debug/deps"), "app", &path),
+            Path::new("/target/debug/deps/comptime-modules/app/gen/tables.rs")
+        );
+        for invalid in ["", "::gen", "gen::Vec<u8>", "gen-tables"] {
+            let invalid = syn::LitStr::new(invalid, proc_macro2::Span::call_site());
+            assert!(parse_module_path(&invalid).is_err());
+        }
+
+        let out_dir = TempDir::new("module");
+        let module_rs = module_path(&out_dir, "app", &path);
+        write_if_changed(&module_rs, "pub const A: u8 = 1;\n").unwrap();
+        let mtime = || std::fs::metadata(&module_rs).unwrap().modified().unwrap();
+        let written = mtime();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write_if_changed(&module_rs, "pub const A: u8 = 1;\n").unwrap();
+        let unchanged = mtime();
+        assert_eq!(written, unchanged);
+    }
+
+    #[test]
+    fn test_driver_crate_name() {
+        let (a, b) = (driver_crate_name(1), driver_crate_name(u64::MAX));
+        assert_eq!(a, "comptime_bin_0000000000000001");
+        assert_eq!(b, "comptime_bin_ffffffffffffffff");
+        assert!(syn::parse_str::<syn::Ident>(&b).is_ok());
+        assert_ne!(driver_stem("app", "", 1), driver_stem("app", "", 2));
+        assert_ne!(driver_stem("app", "", 1), driver_stem("lib", "", 1));
+        // A crate's lib and its tests build the same programs concurrently.
+        assert_ne!(driver_stem("app", "-1f", 1), driver_stem("app", "-2e", 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failure_message() {
+        let mut config = run::RunConfig::new(std::env::temp_dir());
+        config.args = vec!["-c".into(), "printf 'bad \\377 byte' >&2; exit 1".into()];
+        let run = config.run(Path::new("sh")).unwrap();
+        assert!(!run.output.status.success());
+        assert_eq!(
+            failure_message("could not run comptime expr", &run.output.stderr),
+            "could not run comptime expr:\n\nbad \u{FFFD} byte\n"
+        );
+    }
+
+    #[test]
+    fn test_stderr_warnings() {
+        assert_eq!(
+            stderr_warnings("loaded schema v2\n\n  3 tables\n", "src/lib.rs:4:9"),
+            "warning: comptime expr at src/lib.rs:4:9: loaded schema v2\n\
+             warning: comptime expr at src/lib.rs:4:9:   3 tables\n"
+        );
+        assert_eq!(stderr_warnings("", "src/lib.rs:4:9"), "");
+    }
+
+    #[test]
+    fn test_respan() {
+        let tokens = respan(quote!(a[b(1u8)]), proc_macro2::Span::call_site());
+        assert_eq!(tokens.to_string(), quote!(a[b(1u8)]).to_string());
+    }
+
+    #[test]
+    fn test_comptime_input() {
+        let input = syn::parse_str::<ComptimeInput>("@include { let x = 1; x + 1 }").unwrap();
+        assert!(input.directives.include);
+        assert_eq!(input.program.stmts.len(), 2);
+        let e = syn::parse_str::<ComptimeInput>("let x = ; x + 1")
+            .err()
+            .unwrap();
+        assert!(
+            e.to_string()
+                .starts_with("comptime program is not a valid sequence of statements: "),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_range_overlap() {
+        assert_eq!(range_overlap(&[]), None);
+        assert_eq!(
+            range_overlap(&[(0x30, 0x39), (0x3a, 0x40), (0x41, 0x41)]),
+            None
+        );
+        assert_eq!(
+            range_overlap(&[(0x30, 0x39), (0x39, 0x40)]).unwrap(),
+            "comptime_range_match! ranges `48..=57` and `57..=64` overlap"
+        );
+        // A range that contains the next overlaps it.
+        assert!(range_overlap(&[(-10, 10), (-5, -5)]).is_some());
+    }
+
+    #[test]
+    fn test_enum_variants() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let variants = enum_variants(names(&["US", "FR", "r#type"])).unwrap();
+        let idents: Vec<_> = variants
+            .iter()
+            .map(|(_, ident)| ident.to_string())
+            .collect();
+        assert_eq!(idents, ["US", "FR", "r#type"]);
+        for (names, error) in [
+            (
+                names(&["US", "0A"]),
+                "variant name `0A` is not a valid identifier",
+            ),
+            (
+                names(&["US", "U S"]),
+                "variant name `U S` is not a valid identifier",
+            ),
+            (
+                names(&["US", "type"]),
+                "variant name `type` is not a valid identifier",
+            ),
+            (names(&["US", "FR", "US"]), "variant `US` is duplicated"),
+        ] {
+            assert_eq!(
+                enum_variants(names).unwrap_err(),
+                format!("comptime_enum! {}", error)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_map_args() {
+        let parse = |args| parse_map_args(&syn::parse_str(args).unwrap());
+        assert_eq!(parse("0..3").unwrap(), ["0", "1", "2"]);
+        assert_eq!(parse("-1..=1").unwrap(), ["-1", "0", "1"]);
+        assert_eq!(
+            parse(r#"["a b", 'c', 5u8, -2.5, true]"#).unwrap(),
+            ["a b", "c", "5", "-2.5", "true"]
+        );
+        assert_eq!(parse("[x]").unwrap_err().to_string(), "expected a literal");
+        assert_eq!(
+            parse("0..").unwrap_err().to_string(),
+            "expected a range with both bounds"
+        );
+        assert_eq!(parse("0..4096").unwrap().len(), 4096);
+        assert_eq!(parse("3..1").unwrap(), Vec::<String>::new());
+        for range in [
+            "0..=4096",
+            "0..18446744073709551615",
+            "-170141183460469231731687303715884105728..1",
+        ] {
+            assert_eq!(
+                parse(range).unwrap_err().to_string(),
+                "expected a range of at most 4096 integers"
+            );
+        }
+        assert!(parse("v").is_err());
+    }
+
+    #[test]
+    fn test_strip_suffix() {
+        assert_eq!(
+            map_literals(
+                quote!([5u8, -0x1fi64, 1f32, 2.5e3f64, 7, "8u8", x(9usize)]),
+                &strip_suffix
+            )
+            .to_string(),
+            quote!([5, -0x1f, 1.0, 2.5e3, 7, "8u8", x(9)]).to_string()
+        );
+    }
+
+    #[test]
+    fn test_create_out_dir() {
+        let root = TempDir::new("out-dir");
+        let out_dir = root.join("target").join("debug").join("deps");
+        create_out_dir(&out_dir).unwrap();
+        assert!(out_dir.is_dir());
+        create_out_dir(&out_dir).unwrap();
+
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let e = create_out_dir(&file.join("deps")).unwrap_err();
+        assert!(e.starts_with(&format!(
+            "comptime could not create rustc's out dir `{}`: ",
+            file.join("deps").display()
+        )));
+    }
+
+    #[test]
+    fn test_write_driver() {
+        let root = TempDir::new("write-driver");
+        let out_dir = root.join("deps");
+        let comptime_rs = write_driver(&out_dir, "comptime-app-1", "fn main() {}").unwrap();
+        assert_eq!(comptime_rs, out_dir.join("comptime-app-1.rs"));
+
+        // An out dir that can't be created, like one that's read-only, falls back to the temp dir.
+        let file = root.join("file");
+        std::fs::write(&file, "").unwrap();
+        let comptime_rs = write_driver(&file.join("deps"), "comptime-app-1", "fn main() {}");
+        let comptime_rs = comptime_rs.unwrap();
+        assert!(comptime_rs.starts_with(std::env::temp_dir()));
+        assert!(!comptime_rs.starts_with(&root));
+        assert_eq!(
+            std::fs::read_to_string(&comptime_rs).unwrap(),
+            "fn main() {}"
+        );
+        std::fs::remove_dir_all(comptime_rs.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_dir_creation() {
+        let root = TempDir::new("concurrent-dirs");
+        let out_dir = root.join("target").join("debug").join("deps");
+        let private_dir = root.join("private").join("drivers");
+        let barrier = std::sync::Barrier::new(8);
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let (out_dir, private_dir, barrier) = (&out_dir, &private_dir, &barrier);
+                scope.spawn(move || {
+                    barrier.wait();
+                    let stem = format!("comptime-app-{}", i);
+                    let comptime_rs = write_driver(out_dir, &stem, "fn main() {}").unwrap();
+                    assert_eq!(comptime_rs, out_dir.join(format!("{}.rs", stem)));
+                    create_private_dir(private_dir).unwrap();
+                });
+            }
+        });
+        assert_eq!(std::fs::read_dir(&out_dir).unwrap().count(), 8);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = TempDir::new("private-dir");
+        let dir = root.join("drivers");
+        create_private_dir(&dir).unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        create_private_dir(&dir).unwrap();
+
+        // Others could have planted a driver in a dir that they can write.
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let e = create_private_dir(&dir).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        let link = root.join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert!(create_private_dir(&link).is_err());
+    }
+
+    #[test]
+    fn test_rustc_out_dir() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let cargo = args(&["rustc", "--out-dir", "/target/debug/deps", "src/lib.rs"]);
+        assert_eq!(
+            rustc_out_dir(&cargo),
+            Some((Path::new("/target/debug/deps"), false))
+        );
+        // Bazel names the output, and gives every extern explicitly.
+        let bazel = args(&[
+            "rustc",
+            "src/lib.rs",
+            "--crate-name=app",
+            "--crate-type=rlib",
+            "--emit=dep-info,link",
+            "-o",
+            "bazel-out/k8-fastbuild/bin/app/libapp-123.rlib",
+            "--extern",
+            "rand=bazel-out/k8-fastbuild/bin/external/rand/librand-456.rlib",
+            "-Ldependency=bazel-out/k8-fastbuild/bin/external/rand",
+        ]);
+        assert_eq!(
+            rustc_out_dir(&bazel),
+            Some((Path::new("bazel-out/k8-fastbuild/bin/app"), true))
+        );
+        assert_eq!(
+            filter_rustc_args(&bazel),
+            ["-Ldependency=bazel-out/k8-fastbuild/bin/external/rand"]
+        );
+        assert_eq!(
+            dependency_dir(&bazel[9]),
+            Some("bazel-out/k8-fastbuild/bin/external/rand")
+        );
+        assert_eq!(
+            dependency_dir("dependency=/target/debug/deps"),
+            Some("/target/debug/deps")
+        );
+        assert_eq!(
+            rustc_out_dir(&args(&["rustc", "-o", "libapp.rlib"])),
+            Some((Path::new("."), true))
+        );
+        assert_eq!(rustc_out_dir(&args(&["rustc", "src/lib.rs"])), None);
+    }
+
+    #[test]
+    fn test_next_unique_id() {
+        let dir = TempDir::new("ids");
+        let counter = dir.join("comptime-unique-id");
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    (0..4)
+                        .map(|_| next_unique_id(&counter).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut ids: Vec<_> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, (0..32).collect::<Vec<_>>());
+        assert_eq!(std::fs::read_to_string(&counter).unwrap(), "32");
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_remove_option() {
+        let mut rustc_args = [
+            "--error-format=json",
+            "--cap-lints",
+            "warn",
+            "--json",
+            "artifacts",
+        ]
+        .map(String::from)
+        .to_vec();
+        remove_option(&mut rustc_args, "--error-format");
+        remove_option(&mut rustc_args, "--json");
+        assert_eq!(rustc_args, ["--cap-lints", "warn"]);
+        remove_option(&mut rustc_args, "--cap-lints");
+        assert!(rustc_args.is_empty());
+
+        // The parent's `--check-cfg`s are passed on to the driver.
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let parent = args(&[
+            "rustc",
+            "--check-cfg",
+            "cfg(feature, values(\"json\"))",
+            "--check-cfg=cfg(docsrs)",
+            "src/lib.rs",
+        ]);
+        assert_eq!(filter_rustc_args(&parent), parent[1..4]);
+    }
+
+    #[test]
+    fn test_expand_argfiles() {
+        let dir = TempDir::new("argfile");
+        let argfile = dir.join("args");
+        std::fs::write(
+            &argfile,
+            "--crate-name\napp\n--out-dir\n/target/debug/deps\n",
+        )
+        .unwrap();
+        let args = ["rustc", &format!("@{}", argfile.display()), "src/main.rs"];
+        let expanded = expand_argfiles(args.iter().map(|a| a.to_string()));
+        assert_eq!(
+            expanded.unwrap(),
+            [
+                "rustc",
+                "--crate-name",
+                "app",
+                "--out-dir",
+                "/target/debug/deps",
+                "src/main.rs"
+            ]
+        );
+        assert!(expand_argfiles(["rustc".to_string(), "@/nonexistent".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_driver_output() {
+        let output = parse_driver_output("00ff\n[1, 2]\nkind=tokens\ttrack=/a.rs", "00ff").unwrap();
+        assert_eq!(output.tokens, "[1, 2]");
+        assert_eq!(output.kind, "tokens");
+        assert_eq!(output.tracked, ["/a.rs"]);
+        // Tokens can span lines, e.g. a block that a program formatted itself.
+        let block = "{\n    let x = 1;\n    x + 1\n}";
+        let output = format!("00ff\n{}\nkind=tokens", block);
+        assert_eq!(parse_driver_output(&output, "00ff").unwrap().tokens, block);
+
+        // Output planted by another invocation's artifact.
+        assert!(parse_driver_output("0abc\n7\nkind=value", "00ff")
+            .unwrap_err()
+            .starts_with("comptime expr output came from a stale or colliding artifact"));
+        assert!(parse_driver_output("00ff\n7", "00ff").is_err());
+    }
+
+    #[test]
+    fn test_planted_driver() {
+        let nonce = driver_nonce(1, ("1 + 1", "", DRIVER_TEMPLATE, false, false));
+        assert_ne!(nonce, format!("{:016x}", 1));
+
+        // The output of a colliding program's driver, left at this one's path, starts with the
+        // disambiguator, which is all that the two programs share.
+        let output = format!("{:016x}\n3\nkind=value", 1);
+        let e = parse_driver_output(&output, &nonce).unwrap_err();
+        assert!(e.contains("stale or colliding artifact"), "{}", e);
+    }
+
+    #[test]
+    fn test_check_edition() {
+        assert_eq!(check_edition("2021", None), Ok(()));
+        assert!(check_edition("2030", None)
+            .unwrap_err()
+            .starts_with("argument for `--edition` must be one of"));
+    }
+
+    #[test]
+    fn test_set_linker() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let parent = args(&["-C", "linker=target-cc", "-C", "opt-level=0", "-Clinker=cc"]);
+
+        let mut rustc_args = parent.clone();
+        set_linker(&mut rustc_args, None, false);
+        assert_eq!(rustc_args, parent);
+
+        set_linker(&mut rustc_args, None, true);
+        assert_eq!(rustc_args, args(&["-C", "opt-level=0"]));
+
+        let mut rustc_args = parent;
+        set_linker(&mut rustc_args, Some("/bin/wrapper"), false);
+        assert_eq!(
+            rustc_args,
+            args(&["-C", "opt-level=0", "-C", "linker=/bin/wrapper"])
+        );
+    }
+
+    #[test]
+    fn test_set_target_features() {
+        let mut rustc_args = vec!["-C".to_string(), "target-feature=+sse4.2".to_string()];
+        set_target_features(&mut rustc_args, None);
+        assert_eq!(rustc_args.len(), 2);
+        set_target_features(&mut rustc_args, Some("+avx2,+fma"));
+        assert_eq!(rustc_args[2..], ["-C", "target-feature=+avx2,+fma"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_illegal_instruction() {
+        let status = |script| Command::new("sh").args(["-c", script]).status().unwrap();
+        assert!(is_illegal_instruction(&status("kill -ILL $$")));
+        assert!(!is_illegal_instruction(&status("kill -SEGV $$")));
+        assert!(!is_illegal_instruction(&status("exit 4")));
+    }
+
+    #[test]
+    fn test_remove_codegen_option() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let mut rustc_args = args(&[
+            "-C",
+            "prefer-dynamic",
+            "-Cprefer-dynamic=yes",
+            "-C",
+            "opt-level=0",
+        ]);
+        remove_codegen_option(&mut rustc_args, "prefer-dynamic");
+        assert_eq!(rustc_args, args(&["-C", "opt-level=0"]));
+    }
+
+    #[test]
+    fn test_codegen_options() {
+        let rustc_args = [
+            "-C",
+            "target-feature=+avx2",
+            "-Ctarget-feature=+fma",
+            "-Cmetadata=1f",
+        ]
+        .map(String::from);
+        assert_eq!(
+            codegen_options(&rustc_args, "target-feature"),
+            ["+avx2", "+fma"]
+        );
+        assert_eq!(codegen_options(&rustc_args, "metadata"), ["1f"]);
+        assert!(codegen_options(&rustc_args, "extra-filename").is_empty());
+    }
+
+    #[test]
+    fn test_set_cfgs() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let mut rustc_args = args(&[
+            "--cfg",
+            "feature=\"default\"",
+            "--cfg=feature=\"std\"",
+            "--cfg",
+            "tokio_unstable",
+        ]);
+        set_cfgs(&mut rustc_args, &args(&["feature=\"x\""]));
+        assert_eq!(
+            rustc_args,
+            args(&["--cfg", "tokio_unstable", "--cfg", "feature=\"x\""])
+        );
+        assert_eq!(cfg_name("feature = \"x\"").as_deref(), Some("feature"));
+        assert_eq!(cfg_name("feature=x"), None);
+        assert_eq!(cfg_name("a::b"), None);
+    }
+
+    #[test]
+    fn test_is_semver_compatible() {
+        for (requirement, version) in [
+            ("0.7", "0.7.3"),
+            ("^1.2", "1.9.0"),
+            ("1", "1.0.0-beta.1"),
+            ("0.0.3", "0.0.3"),
+            ("=0.4.1", "0.4.1"),
+            (">=2, <3", "0.1.0"),
+            ("0.0.0.1", "0.0.0"),
+            ("=1.2.3.4", "1.2.3"),
+        ] {
+            assert!(
+                is_semver_compatible(requirement, version),
+                "{} {}",
+                requirement,
+                version
+            );
+        }
+        for (requirement, version) in [
+            ("0.7", "0.8.5"),
+            ("1.2", "1.1.9"),
+            ("1", "2.0.0"),
+            ("0.0.3", "0.0.4"),
+            ("=0.4.1", "0.4.2"),
+        ] {
+            assert!(
+                !is_semver_compatible(requirement, version),
+                "{} {}",
+                requirement,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_static_initializer() {
+        for init in [
+            quote!([1u8, 2u8, 3u8]),
+            quote!(&[("a", -1), ("b", 2)]),
+            quote!(Point {
+                x: 1.5,
+                y: f64::INFINITY
+            }),
+            quote!([0u8; 32]),
+            quote!(u32::MAX - 1),
+            quote!([1u64 << 40, !0]),
+            quote!(Some(u8::MAX).unwrap_or(0)),
+            quote!({ 7 }),
+            quote!(TABLE[1].0),
+        ] {
+            assert!(is_static_initializer(&syn::parse2(init).unwrap()));
+        }
+        for init in [
+            quote!(vec![1, 2, 3]),
+            quote!((|| 1)()),
+            quote!({
+                1;
+            }),
+        ] {
+            assert!(!is_static_initializer(&syn::parse2(init).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_render_driver() {
+        let template = "#![allow(unused)]\n\
+                        fn main() {\n\
+                            let comptime_output = { {{comptime_program}} };\n\
+                            eprintln!(\"generated\");\n\
+                            {{comptime_print}}\n\
+                        }";
+        let driver = render_driver(template, "1 + 2", "pub const EXTRA: u8 = 0;").unwrap();
+        assert!(driver.starts_with("#![allow(unused)]\nfn main() {\n"));
+        assert!(driver.contains("let comptime_output = { 1 + 2 };\neprintln!(\"generated\");"));
+        assert!(driver.contains(DRIVER_PRINT));
+        assert!(driver.contains(DRIVER_PRELUDE));
+        assert!(driver.ends_with("pub const EXTRA: u8 = 0;\n}\n"));
+
+        assert_eq!(
+            render_driver("fn main() { {{comptime_program}} }", "1", "").unwrap_err(),
+            "missing the `{{comptime_print}}` placeholder"
+        );
+    }
+
+    #[test]
+    fn test_missing_crates() {
+        let stderr = r#"
+            {"message":"unresolved import `nonexistent_crate`","code":{"code":"E0432"}}
+            {"message":"unresolved import `rand::Nonexistent`","code":{"code":"E0432"}}
+            {"message":"cannot find module or crate `other_missing` in this scope"}
+            {"message":"use of unresolved module or unlinked crate `nonexistent_crate`"}
+        "#;
+        assert_eq!(
+            missing_crates(stderr),
+            ["nonexistent_crate", "other_missing"]
+        );
+        assert!(missing_crates("error[E0308]: mismatched types").is_empty());
+    }
+
+    #[test]
+    fn test_shared_value() {
+        // Only this test sets `OUT_DIR`, which a build script's crate is compiled with.
+        let out_dir = TempDir::new("shared");
+        std::env::set_var("OUT_DIR", &*out_dir);
+        let shared = out_dir.join("comptime_shared.json");
+        std::fs::write(&shared, r#"{"lanes": 8, "name": "simd"}"#).unwrap();
+        let value = shared_value("lanes");
+        if cfg!(not(feature = "json")) {
+            assert!(value.unwrap_err().contains("requires the `json` feature"));
+            return;
+        }
+        // The file is tracked, so the crate is rebuilt when the build script rewrites it.
+        let path = shared.display().to_string();
+        assert_eq!(
+            value.unwrap().to_string(),
+            quote!({
+                const _: &[u8] = include_bytes!(#path);
+                8
+            })
+            .to_string()
+        );
+        assert!(shared_value("name")
+            .unwrap()
+            .to_string()
+            .contains("\"simd\""));
+        assert!(shared_value("width")
+            .unwrap_err()
+            .ends_with("it has no key `width`"));
+        std::fs::remove_file(&shared).unwrap();
+        assert!(shared_value("lanes")
+            .unwrap_err()
+            .starts_with("could not read"));
+    }
+
+    #[test]
+    fn test_bundle_order() {
+        let BundleInput(consts) = syn::parse_quote! {
+            const C: u8 = A + B;
+            const A: u8 = 1;
+            const B: u8 = { let a = A; a + 1 };
+            const D: u8 = 4;
+        };
+        assert_eq!(bundle_order(&consts), Ok(vec![1, 2, 0, 3]));
+
+        let BundleInput(consts) = syn::parse_quote! {
+            const A: u8 = B;
+            const B: u8 = A;
+        };
+        assert_eq!(bundle_order(&consts).unwrap_err(), "A");
+
+        // `A` only depends on the cycle.
+        let BundleInput(consts) = syn::parse_quote! {
+            const A: u8 = B;
+            const B: u8 = C;
+            const C: u8 = B;
+        };
+        assert_eq!(bundle_order(&consts).unwrap_err(), "B");
+    }
+
+    #[test]
+    fn test_build_timestamp() {
+        assert_eq!(
+            build_timestamp(Some("0")),
+            Ok("1970-01-01T00:00:00Z".to_string())
+        );
+        assert_eq!(
+            build_timestamp(Some("1567166400")),
+            Ok("2019-08-30T12:00:00Z".to_string())
+        );
+        assert_eq!(
+            build_timestamp(Some("951825599")),
+            Ok("2000-02-29T11:59:59Z".to_string())
+        );
+        assert_eq!(
+            build_timestamp(Some("-1")),
+            Ok("1969-12-31T23:59:59Z".to_string())
+        );
+        assert!(build_timestamp(Some("yesterday"))
+            .unwrap_err()
+            .starts_with("SOURCE_DATE_EPOCH must be a number of seconds"));
+        assert!(build_timestamp(None).unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn test_cast_result() {
+        let cast = |result: proc_macro2::TokenStream, ty: &str| {
+            let ty = syn::Ident::new(ty, proc_macro2::Span::call_site());
+            let (cast, truncated) = cast_result(result, &ty, 32);
+            (cast.to_string(), truncated)
+        };
+        assert_eq!(cast(quote!(42i64), "usize"), ("42usize".to_string(), false));
+        assert_eq!(cast(quote!(-128i64), "i8"), ("- 128i8".to_string(), false));
+        assert_eq!(
+            cast(quote!(-1i64), "u64"),
+            ("(- 1i64) as u64".to_string(), true)
+        );
+        assert_eq!(
+            cast(quote!(5000000000i64), "usize"),
+            ("(5000000000i64) as usize".to_string(), true)
+        );
+        assert_eq!(
+            cast(quote!(2.5f64), "u8"),
+            ("(2.5f64) as u8".to_string(), true)
+        );
+        assert_eq!(cast(quote!(-0i64), "u8"), ("0u8".to_string(), false));
+        assert_eq!(
+            cast(quote!(3i32), "f32"),
+            ("(3i32) as f32".to_string(), false)
+        );
+        assert_eq!(
+            cast(quote!(u128::MAX), "u8"),
+            ("(u128 :: MAX) as u8".to_string(), false)
+        );
+        assert_eq!(int_bits("u128", 64), Some((128, false)));
+        assert_eq!(int_bits("isize", 16), Some((16, true)));
+        assert_eq!(int_bits("u7", 64), None);
+    }
+
+    #[test]
+    fn test_result_type() {
+        let parse = |ty| syn::parse_str::<ResultType>(ty).map(|ty| ty.kind);
+        assert_eq!(parse("u32").unwrap(), LitKind::Int);
+        assert_eq!(parse("&str").unwrap(), LitKind::Str);
+        assert_eq!(parse("f64").unwrap(), LitKind::Float);
+        for invalid in ["String", "&mut str", "Vec<u8>", "u7"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let kind = |result: proc_macro2::TokenStream, expected| result_lit_kind(&result, expected);
+        assert_eq!(kind(quote!(4i32), LitKind::Int), Some(LitKind::Int));
+        assert_eq!(kind(quote!(-4i32), LitKind::Int), Some(LitKind::Int));
+        assert_eq!(kind(quote!(4i32), LitKind::Str), Some(LitKind::Int));
+        assert_eq!(kind(quote!("4"), LitKind::Int), Some(LitKind::Str));
+        assert_eq!(kind(quote!(-'a'), LitKind::Char), Some(LitKind::Char));
+        assert_eq!(kind(quote!(f64::NAN), LitKind::Float), Some(LitKind::Float));
+        assert_eq!(kind(quote!(f64::NAN), LitKind::Int), None);
+        assert_eq!(kind(quote!([1, 2]), LitKind::Int), None);
+    }
+
+    #[test]
+    fn test_program_timeout() {
+        let secs = |secs| Ok(Some(Duration::from_secs(secs)));
+        let global = |secs: &str| Some(secs.to_string());
+        assert_eq!(program_timeout(None, None), secs(DEFAULT_TIMEOUT_SECS));
+        assert_eq!(program_timeout(None, global("5")), secs(5));
+        assert_eq!(program_timeout(None, global("0")), Ok(None));
+        assert!(program_timeout(None, global("5s")).is_err());
+        // A program's `@timeout` takes precedence, even over no timeout at all.
+        assert_eq!(program_timeout(Some(120), None), secs(120));
+        assert_eq!(program_timeout(Some(120), global("5")), secs(120));
+        assert_eq!(program_timeout(Some(120), global("0")), secs(120));
+        assert_eq!(program_timeout(Some(120), global("5s")), secs(120));
+    }
+
+    #[test]
+    fn test_tool() {
+        assert_eq!(
+            tool_or(Some("/opt/rust/bin/rustc".to_string()), "rustc"),
+            "/opt/rust/bin/rustc"
+        );
+        assert_eq!(tool_or(None, "rustfmt"), "rustfmt");
+        assert_eq!(tool_or(Some(String::new()), "rustfmt"), "rustfmt");
+    }
+
+    #[test]
+    fn test_log_includes() {
+        assert!(log_includes("summary", "summary"));
+        assert!(log_includes("summary, commands", "commands"));
+        assert!(!log_includes("summary", "commands"));
+        assert!(!log_includes("", "stderr"));
+        assert_eq!(
+            command_line("rustc", &["--cfg", "feature=\"a b\"", "x.rs"]),
+            r#"rustc --cfg "feature=\"a b\"" x.rs"#
+        );
+    }
+
+    #[test]
+    fn test_no_std_hint() {
+        let std =
+            "error[E0433]: failed to resolve: use of unresolved module or unlinked crate `std`";
+        assert!(no_std_hint(std, false).unwrap().contains("or also `alloc`"));
+        assert!(no_std_hint(std, true)
+            .unwrap()
+            .contains("only `core` and `alloc`"));
+        let vec = "error[E0412]: cannot find type `Vec` in this scope";
+        assert!(no_std_hint(vec, false)
+            .unwrap()
+            .contains("with `@no_std(alloc)`"));
+        assert_eq!(no_std_hint(vec, true), None);
+        assert_eq!(no_std_hint("unresolved import `stdx`", false), None);
+    }
+
+    #[test]
+    fn test_conflicting_crates() {
+        // Two builds of the same crate that differ only in their metadata.
+        let dir = TempDir::new("conflicting");
+        std::fs::write(dir.join("foo.rs"), "pub fn f() {}").unwrap();
+        std::fs::write(
+            dir.join("main.rs"),
+            "extern crate foo; fn main() { foo::f() }",
+        )
+        .unwrap();
+        for metadata in ["a", "b"] {
+            let status = Command::new("rustc")
+                .args(["--crate-type", "rlib", "--crate-name", "foo", "--out-dir"])
+                .arg(&*dir)
+                .arg(format!("-Cmetadata={}", metadata))
+                .arg(format!("-Cextra-filename=-{}", metadata))
+                .arg(dir.join("foo.rs"))
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let output = Command::new("rustc")
+            .args(["--edition", "2021", "--emit", "metadata", "-L"])
+            .arg(&*dir)
+            .arg("--out-dir")
+            .arg(&*dir)
+            .arg(dir.join("main.rs"))
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        let crates = conflicting_crates(&stderr);
+        assert_eq!(crates, ["foo"]);
+        assert_eq!(
+            conflicting_crates_message(&crates),
+            "could not compile comptime expr: found conflicting builds of crate `foo` in the deps \
+             dir. Run `cargo clean` to remove stale ones, or require a single version in \
+             `Cargo.toml`."
+        );
+        assert!(conflicting_crates("error[E0308]: mismatched types").is_empty());
+    }
+
+    #[test]
+    fn test_target_cfg() {
+        let cfg = target_cfg(Some("i686-unknown-linux-gnu"), &[], None).unwrap();
+        assert_eq!(cfg.pointer_width, 32);
+        assert_eq!(cfg.arch, "x86");
+        assert_eq!(cfg.endian, "little");
+
+        let host = target_cfg(None, &[], None).unwrap();
+        assert_eq!(host.pointer_width, usize::BITS);
+
+        // A crate built with `+avx2` answers `cfg!(target_feature = "avx2")` for it.
+        let avx2 = ("target_feature".to_string(), Some("avx2".to_string()));
+        let target = Some("x86_64-unknown-linux-gnu");
+        assert!(!target_cfg(target, &[], None).unwrap().cfgs.contains(&avx2));
+        let codegen = ["-Ctarget-feature=+avx2".to_string()];
+        assert!(target_cfg(target, &codegen, None)
+            .unwrap()
+            .cfgs
+            .contains(&avx2));
+    }
+
+    #[test]
+    fn test_cstr_literal() {
+        let lit = |s: &str| syn::LitStr::new(s, proc_macro2::Span::call_site());
+        assert_eq!(
+            cstr_literal(&lit("a\"b"), "2021").unwrap().to_string(),
+            r#"c"a\"b""#
+        );
+        assert_eq!(
+            cstr_literal(&lit("ab"), "2018").unwrap().to_string(),
+            quote! {
+                match ::core::ffi::CStr::from_bytes_with_nul(b"ab\0") {
+                    Ok(cstr) => cstr,
+                    Err(_) => panic!(),
+                }
+            }
+            .to_string()
+        );
+        assert_eq!(
+            cstr_literal(&lit("a\0b"), "2021").unwrap_err(),
+            r#"comptime_cstr result has an interior NUL at byte 1: "a\0b""#
+        );
+
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(parent_edition(&args(&["--edition=2018", "x.rs"])), "2018");
+        assert_eq!(parent_edition(&args(&["--edition", "2024"])), "2024");
+        assert_eq!(parent_edition(&args(&["x.rs"])), "2015");
+    }
+
+    #[test]
+    fn test_program_uses() {
+        let uses = |program: &str| ProgramUses::of(&syn::parse_str(program).unwrap());
+        assert!(uses("std::mem::size_of::<usize>()").layout_query);
+        assert!(uses("use std::mem::align_of; align_of::<u8>()").layout_query);
+        assert!(uses("println!(\"{}\", core::mem::size_of_val(&1u8))").layout_query);
+        // Neither strings nor comments are queries.
+        assert!(!uses("\"size_of\" // align_of\n").layout_query);
+
+        assert!(uses("comptime::parse_file(\"src/lib.rs\")").parse_file);
+        assert!(uses("use comptime::{parse_file as parse}; parse(\"src/lib.rs\")").parse_file);
+        assert!(uses("use comptime::*; parse_file(\"src/lib.rs\").items.len()").parse_file);
+        assert!(uses("format!(\"{:?}\", comptime::parse_file(\"a.rs\"))").parse_file);
+        assert!(!uses("syn::parse_file(\"fn f() {}\").unwrap()").parse_file);
+        assert!(!uses("\"parse_file\"").parse_file);
+        assert!(!uses("use std::*; parse_file()").parse_file);
+
+        assert!(uses("comptime::json::emit(1)").json);
+        assert!(uses("use comptime::{json}; json::emit(1)").json);
+        assert!(uses("use comptime::*; json::emit(1)").json);
+        assert!(!uses("serde_json::json!({\"comptime\": 1})").json);
+        assert!(!uses("\"comptime::json\"").json);
+    }
+
+    #[test]
+    fn test_apply_target_cfgs() {
+        let target = target_cfg(Some("i686-unknown-linux-gnu"), &[], None).unwrap();
+        let program = quote! {
+            let a = cfg!(target_pointer_width = "32");
+            let b = [cfg!(all(unix, not(target_os = "windows"))), cfg!(windows)];
+            let c = [cfg!(any(target_arch = "x86", feature = "x")), cfg!(any(target_arch = "arm", feature = "x"))];
+            let d = core::cfg!(target_pointer_width = "64");
+        };
+        assert_eq!(
+            apply_target_cfgs(program, &target).to_string(),
+            quote! {
+                let a = true;
+                let b = [true, false];
+                let c = [true, cfg!(any(target_arch = "arm", feature = "x"))];
+                let d = core::cfg!(target_pointer_width = "64");
+            }
+            .to_string()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_world_writable() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("perms");
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(!is_world_writable(&dir));
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(is_world_writable(&dir));
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_assert_input() {
+        let input = syn::parse_str::<AssertInput<1>>(r#"x == 1, "x is not 1""#).unwrap();
+        assert_eq!(input.message.as_deref(), Some("x is not 1"));
+        // An empty message would mean success.
+        let e = syn::parse_str::<AssertInput<1>>(r#"x == 1, """#)
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "comptime assertion message must not be empty"
+        );
+        // A program that isn't valid fails compilation rather than the macro.
+        let e = check_program(quote!(let = 1;)).err().unwrap();
+        assert!(e.to_string().starts_with("compile_error"), "{}", e);
+    }
 }
@@ -0,0 +1,388 @@
+//! Running the comptime executable. Everything that controls how it's spawned lives in
+//! `RunConfig`, so that every comptime macro runs its program the same way.
+
+use std::{
+    ffi::OsString,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    time::{Duration, Instant},
+};
+
+/// How to run the comptime executable.
+pub(crate) struct RunConfig {
+    /// The working directory, which is the crate's root by default.
+    pub(crate) cwd: PathBuf,
+    /// Environment variables to set, on top of those inherited from rustc.
+    pub(crate) env: Vec<(OsString, OsString)>,
+    /// Inherited environment variables to unset.
+    pub(crate) env_remove: Vec<OsString>,
+    pub(crate) args: Vec<OsString>,
+    /// The program's stdin, which is inherited if `None`.
+    pub(crate) stdin: Option<Stdio>,
+    /// The number of bytes of stdout after which the program is cut off.
+    pub(crate) output_cap: Option<u64>,
+    /// How long the program may run before it's killed.
+    pub(crate) timeout: Option<Duration>,
+    /// The number of bytes of address space that the program may use, on Linux.
+    pub(crate) mem_limit: Option<u64>,
+    /// A command that the program is run under, with the program and its arguments appended.
+    pub(crate) sandbox: Vec<OsString>,
+}
+
+/// The output of the comptime executable, and whether it was cut short.
+pub(crate) struct RunOutput {
+    pub(crate) output: Output,
+    /// Whether stdout exceeded the output cap, in which case the program's stdout was closed early.
+    pub(crate) exceeded_cap: bool,
+    /// Whether the program was killed for exceeding the timeout.
+    pub(crate) timed_out: bool,
+    /// Whether the program aborted because an allocation exceeded the memory limit.
+    pub(crate) exceeded_mem_limit: bool,
+}
+
+impl RunConfig {
+    /// Runs the program in `manifest_dir`, which is also provided as `CARGO_MANIFEST_DIR` so that
+    /// `comptime::manifest_dir` works even when rustc is run without cargo.
+    pub(crate) fn new(manifest_dir: PathBuf) -> Self {
+        Self {
+            env: vec![("CARGO_MANIFEST_DIR".into(), manifest_dir.clone().into())],
+            cwd: manifest_dir,
+            env_remove: Vec::new(),
+            args: Vec::new(),
+            stdin: None,
+            output_cap: None,
+            timeout: None,
+            mem_limit: None,
+            sandbox: Vec::new(),
+        }
+    }
+
+    /// Returns the command that runs `program`. The stdin is moved into the command.
+    pub(crate) fn command(&mut self, program: &Path) -> Command {
+        let mut cmd = match self.sandbox.split_first() {
+            Some((sandbox, sandbox_args)) => {
+                let mut cmd = Command::new(sandbox);
+                cmd.args(sandbox_args).arg(program);
+                cmd
+            }
+            None => Command::new(program),
+        };
+        cmd.current_dir(&self.cwd).args(&self.args);
+        for key in &self.env_remove {
+            cmd.env_remove(key);
+        }
+        cmd.envs(self.env.iter().map(|(key, value)| (key, value)));
+        if let Some(stdin) = self.stdin.take() {
+            cmd.stdin(stdin);
+        }
+        die_with_parent(&mut cmd);
+        if let Some(limit) = self.mem_limit {
+            limit_memory(&mut cmd, limit);
+        }
+        cmd
+    }
+
+    /// Runs `program` and returns its output.
+    pub(crate) fn run(mut self, program: &Path) -> std::io::Result<RunOutput> {
+        let mut output = run_capped(&mut self.command(program), self.output_cap, self.timeout)?;
+        // Rust programs abort with this message when an allocation fails.
+        output.exceeded_mem_limit = self.mem_limit.is_some()
+            && !output.output.status.success()
+            && String::from_utf8_lossy(&output.output.stderr).contains("memory allocation of ");
+        Ok(output)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct Rlimit {
+    current: std::os::raw::c_ulong,
+    max: std::os::raw::c_ulong,
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn prctl(option: std::os::raw::c_int, ...) -> std::os::raw::c_int;
+    fn getppid() -> std::os::raw::c_int;
+    fn setrlimit(resource: std::os::raw::c_int, limit: *const Rlimit) -> std::os::raw::c_int;
+}
+
+/// Limits the address space of `cmd` to `limit` bytes, so that its allocations fail beyond that.
+/// This is only supported on Linux, and elsewhere nothing is done.
+fn limit_memory(cmd: &mut Command, limit: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::CommandExt;
+        const RLIMIT_AS: std::os::raw::c_int = 9;
+        #[allow(clippy::useless_conversion)] // `c_ulong` is 32 bits on 32-bit targets
+        let limit = limit.try_into().unwrap_or(std::os::raw::c_ulong::MAX);
+        // SAFETY: `setrlimit` is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                let limit = Rlimit {
+                    current: limit,
+                    max: limit,
+                };
+                match setrlimit(RLIMIT_AS, &limit) {
+                    0 => Ok(()),
+                    _ => Err(std::io::Error::last_os_error()),
+                }
+            })
+        };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (cmd, limit);
+}
+
+/// Has `cmd` killed when the thread that spawns it exits, so that the compiler and programs that
+/// comptime spawns are not orphaned when rustc is killed, e.g., by cargo after another crate
+/// fails to build. The child is waited for by that same thread, so it's only killed early when
+/// rustc is. A Ctrl-C in a terminal already reaches the children, which are in rustc's process
+/// group. This is only supported on Linux, and elsewhere nothing is done.
+pub(crate) fn die_with_parent(cmd: &mut Command) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::process::CommandExt;
+        const PR_SET_PDEATHSIG: std::os::raw::c_int = 1;
+        const SIGKILL: std::os::raw::c_ulong = 9;
+        let parent = std::process::id() as std::os::raw::c_int;
+        // SAFETY: `prctl` and `getppid` are async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                if prctl(PR_SET_PDEATHSIG, SIGKILL) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                // The parent may have died before the death signal was set.
+                if getppid() != parent {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                Ok(())
+            })
+        };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = cmd;
+}
+
+/// Runs `cmd` and collects its output, but stops reading stdout after `cap` bytes, and kills the
+/// command after `timeout`, discarding its output.
+fn run_capped(
+    cmd: &mut Command,
+    cap: Option<u64>,
+    timeout: Option<Duration>,
+) -> std::io::Result<RunOutput> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stderr_pipe = child.stderr.take().unwrap();
+    let stderr_reader = std::thread::spawn(move || {
+        let mut stderr = Vec::new();
+        stderr_pipe.read_to_end(&mut stderr).map(|_| stderr)
+    });
+    let mut stdout_pipe = child.stdout.take().unwrap();
+    let stdout_reader = std::thread::spawn(move || {
+        let mut stdout = Vec::new();
+        match cap {
+            Some(cap) => (&mut stdout_pipe).take(cap + 1).read_to_end(&mut stdout)?,
+            None => stdout_pipe.read_to_end(&mut stdout)?,
+        };
+        // A driver that's still writing now gets a broken pipe rather than blocking forever.
+        drop(stdout_pipe);
+        Ok::<_, std::io::Error>(stdout)
+    });
+
+    let mut timed_out = false;
+    let status = match timeout {
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    timed_out = true;
+                    child.kill().ok();
+                    break child.wait()?;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        None => child.wait()?,
+    };
+    // The partial output of a program that timed out is discarded, and isn't waited for, since
+    // a process that it spawned may still hold the pipes open.
+    if timed_out {
+        return Ok(RunOutput {
+            output: Output {
+                status,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            },
+            exceeded_cap: false,
+            timed_out,
+            exceeded_mem_limit: false,
+        });
+    }
+    let mut stdout = stdout_reader.join().unwrap()?;
+    let stderr = stderr_reader.join().unwrap()?;
+    let exceeded_cap = cap.is_some_and(|cap| stdout.len() as u64 > cap);
+    stdout.truncate(cap.unwrap_or(u64::MAX) as usize);
+    Ok(RunOutput {
+        output: Output {
+            status,
+            stdout,
+            stderr,
+        },
+        exceeded_cap,
+        timed_out,
+        exceeded_mem_limit: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_os = "linux")]
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_run_config_defaults() {
+        let manifest_dir = std::env::temp_dir();
+        let mut config = RunConfig::new(manifest_dir.clone());
+        assert_eq!(config.output_cap, None);
+        let cmd = config.command(Path::new("comptime-bin"));
+        assert_eq!(cmd.get_current_dir(), Some(manifest_dir.as_path()));
+        assert_eq!(cmd.get_args().count(), 0);
+        assert_eq!(
+            cmd.get_envs().collect::<Vec<_>>(),
+            [(
+                "CARGO_MANIFEST_DIR".as_ref(),
+                Some(manifest_dir.as_os_str())
+            )]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_capped() {
+        let yes = run_capped(&mut Command::new("yes"), Some(10), None).unwrap();
+        assert!(yes.exceeded_cap);
+        assert!(!yes.output.status.success());
+        assert_eq!(yes.output.stdout, b"y\ny\ny\ny\ny\n");
+
+        let mut echo = Command::new("echo");
+        echo.arg("hi");
+        let echo = run_capped(&mut echo, Some(3), None).unwrap();
+        assert!(!echo.exceeded_cap);
+        assert!(echo.output.status.success());
+        assert_eq!(echo.output.stdout, b"hi\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_timeout() {
+        let mut sleep = Command::new("sleep");
+        sleep.arg("30");
+        let start = Instant::now();
+        let sleep = run_capped(&mut sleep, None, Some(Duration::from_millis(100))).unwrap();
+        assert!(sleep.timed_out);
+        assert!(!sleep.output.status.success());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // A grandchild that outlives the program holds its stdout open.
+        let mut sh = Command::new("sh");
+        sh.args(["-c", "echo partial; sleep 30 & sleep 30"]);
+        let start = Instant::now();
+        let sh = run_capped(&mut sh, None, Some(Duration::from_millis(100))).unwrap();
+        assert!(sh.timed_out);
+        assert!(sh.output.stdout.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let mut echo = Command::new("echo");
+        echo.arg("hi");
+        let echo = run_capped(&mut echo, None, Some(Duration::from_secs(30))).unwrap();
+        assert!(!echo.timed_out);
+        assert_eq!(echo.output.stdout, b"hi\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs `unshare` and unprivileged user namespaces"]
+    fn test_sandbox() {
+        let dir = TempDir::new("sandbox");
+        std::fs::write(dir.join("input.txt"), "secret").unwrap();
+        // Hides `dir` behind an empty tmpfs, where unprivileged user namespaces are allowed.
+        let hide = "mount -t tmpfs tmpfs \"$PWD\" && cd \"$PWD\" && exec \"$@\"";
+        let run = |program: &str, args: &[&str]| {
+            let mut config = RunConfig::new(dir.to_path_buf());
+            config.sandbox = [
+                "unshare",
+                "--map-root-user",
+                "--mount",
+                "sh",
+                "-c",
+                hide,
+                "sh",
+            ]
+            .map(Into::into)
+            .to_vec();
+            config.args = args.iter().map(Into::into).collect();
+            config.run(Path::new(program)).unwrap()
+        };
+        let (pure, impure) = (run("echo", &["pure"]), run("cat", &["input.txt"]));
+        assert!(
+            pure.output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&pure.output.stderr)
+        );
+        assert_eq!(pure.output.stdout, b"pure\n");
+        assert!(!impure.output.status.success());
+        assert!(impure.output.stdout.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mem_limit() {
+        let dir = TempDir::new("mem-limit");
+        let source = dir.join("alloc.rs");
+        std::fs::write(
+            &source,
+            "fn main() { let n = std::env::args().nth(1).unwrap().parse().unwrap(); \
+             println!(\"{}\", vec![1u8; n].len()); }",
+        )
+        .unwrap();
+        let status = Command::new("rustc")
+            .arg(&source)
+            .arg("--out-dir")
+            .arg(&*dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let run = |bytes: u64| {
+            let mut config = RunConfig::new(dir.to_path_buf());
+            config.mem_limit = Some(256 << 20);
+            config.args.push(bytes.to_string().into());
+            config.run(&dir.join("alloc")).unwrap()
+        };
+        let (small, large) = (run(1 << 20), run(1 << 30));
+        assert!(!small.exceeded_mem_limit);
+        assert_eq!(small.output.stdout, b"1048576\n");
+        assert!(large.exceeded_mem_limit);
+        assert!(!large.output.status.success());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_die_with_parent() {
+        use std::os::unix::process::ExitStatusExt;
+        // The spawning thread exiting stands in for rustc being killed.
+        let mut child = std::thread::spawn(|| {
+            let mut sleep = Command::new("sleep");
+            sleep.arg("30");
+            die_with_parent(&mut sleep);
+            sleep.spawn().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(9));
+    }
+}
@@ -0,0 +1,97 @@
+//! The build summary printed under `COMPTIME_LOG=summary`, which shows how much of a crate's
+//! compile time was spent in comptime programs.
+//!
+//! Every invocation of a comptime macro in a crate is expanded by the same rustc process, but a
+//! macro can't tell which invocation is the last. Instead, timings are accumulated in a static,
+//! and the summary is printed when rustc exits.
+
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Once,
+    },
+    time::Duration,
+};
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> std::os::raw::c_int;
+}
+
+static SUMMARY: Summary = Summary::new();
+static REGISTER: Once = Once::new();
+
+/// The invocations of comptime macros by one crate.
+pub(crate) struct Summary {
+    invocations: AtomicUsize,
+    nanos: AtomicU64,
+}
+
+impl Summary {
+    const fn new() -> Self {
+        Self {
+            invocations: AtomicUsize::new(0),
+            nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, elapsed: Duration) {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn message(&self, crate_name: &str) -> String {
+        let invocations = self.invocations.load(Ordering::Relaxed);
+        let total = Duration::from_nanos(self.nanos.load(Ordering::Relaxed));
+        format!(
+            "comptime: {} invocation{} took {:.2}s in total while compiling `{}`",
+            invocations,
+            if invocations == 1 { "" } else { "s" },
+            total.as_secs_f64(),
+            crate_name,
+        )
+    }
+}
+
+/// Records an invocation that took `elapsed`, and prints the summary when rustc exits.
+pub(crate) fn record(elapsed: Duration) {
+    SUMMARY.record(elapsed);
+    REGISTER.call_once(|| {
+        // SAFETY: `print` doesn't panic, and rustc never unloads proc macros, so it's still
+        // loaded at exit.
+        unsafe { atexit(print) };
+    });
+}
+
+extern "C" fn print() {
+    let args: Vec<_> = std::env::args().collect();
+    let crate_name = args
+        .iter()
+        .position(|a| a == "--crate-name")
+        .and_then(|p| args.get(p + 1))
+        .map_or("crate", String::as_str);
+    // Unlike `eprintln!`, this doesn't panic (and so abort) if stderr is closed.
+    writeln!(std::io::stderr(), "{}", SUMMARY.message(crate_name)).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::new();
+        summary.record(Duration::from_millis(1500));
+        assert_eq!(
+            summary.message("app"),
+            "comptime: 1 invocation took 1.50s in total while compiling `app`"
+        );
+        summary.record(Duration::from_millis(250));
+        summary.record(Duration::from_millis(10));
+        assert_eq!(
+            summary.message("app"),
+            "comptime: 3 invocations took 1.76s in total while compiling `app`"
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Fixtures for the unit tests.
+
+use std::path::{Path, PathBuf};
+
+/// An empty directory in the system's temp dir, which is removed when it's dropped, even by a
+/// failing test.
+pub(crate) struct TempDir(PathBuf);
+
+/// Writes an empty file that was last modified `age + 1` minutes ago, so that tests can order files
+/// by age without sleeping, whatever the filesystem's timestamp granularity.
+pub(crate) fn write_aged(path: &Path, age: u64) {
+    let file = std::fs::File::create(path).unwrap();
+    let mtime = std::time::SystemTime::now() - std::time::Duration::from_secs(60 * (age + 1));
+    file.set_modified(mtime).unwrap();
+}
+
+impl TempDir {
+    /// Creates `comptime-<name>-<pid>`, first removing whatever a run with the same pid left.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("comptime-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
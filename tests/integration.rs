@@ -34,3 +34,832 @@ fn test_inner_crate() {
         9_482_535_800_248_027_256u64
     );
 }
+
+#[test]
+fn test_seed() {
+    // The same source is given the same seed, and a different source a different one.
+    let (a, b) = (comptime!(comptime::seed()), comptime!(comptime::seed()));
+    let other = comptime!(comptime::seed() + 0);
+    assert_eq!(a, b);
+    assert_ne!(a, other);
+    let from_env = comptime!(std::env::var("COMPTIME_SEED")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap());
+    assert_ne!(from_env, 0);
+}
+
+#[test]
+fn test_cfg() {
+    // The driver is given this crate's `--check-cfg`s, but its lints are capped, so an unexpected
+    // cfg is only false, even under `-D warnings`.
+    assert_eq!(
+        comptime!(cfg!(feature = "nonexistent").to_string()),
+        "false"
+    );
+}
+
+#[test]
+fn test_cfg_directive() {
+    assert_eq!(
+        comptime!(@cfg("feature=\"forced\"", "comptime_test") {
+            cfg!(all(feature = "forced", comptime_test)).to_string()
+        }),
+        "true"
+    );
+}
+
+#[test]
+fn test_token_stream_result() {
+    assert_eq!(
+        comptime! {
+            let mut ts = proc_macro2::TokenStream::new();
+            ts.extend(quote::quote!(5 +));
+            ts.extend(quote::quote!(6));
+            ts
+        },
+        11
+    );
+}
+
+#[test]
+fn test_token_stream_body() {
+    assert_eq!(imperative_body(), 10);
+}
+#[comptime::comptime_fn]
+fn imperative_body() -> u32 {
+    let mut ts = proc_macro2::TokenStream::new();
+    ts.extend(quote::quote!(let x = 5;));
+    ts.extend(quote::quote!(x * 2));
+    ts
+}
+
+#[test]
+fn test_include() {
+    assert_eq!(
+        comptime!(@include {
+            let squares = (0..4u32).map(|i| i * i);
+            quote::quote!([#(#squares),*])
+        }),
+        [0, 1, 4, 9]
+    );
+}
+
+#[test]
+fn test_stdin() {
+    assert_eq!(
+        comptime!(@stdin("tests/data/stdin.txt") {
+            let mut data = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut data).unwrap();
+            data.lines().map(str::to_uppercase).collect::<Vec<_>>().join(" ")
+        }),
+        "HELLO WORLD"
+    );
+    assert_eq!(
+        comptime!(@stdin {
+            let mut data = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut data).unwrap();
+            data.len()
+        }),
+        0
+    );
+}
+
+#[test]
+fn test_include_str() {
+    let text: &str = comptime!(comptime::IncludeStr("tests/data/stdin.txt"));
+    assert_eq!(text, include_str!("data/stdin.txt"));
+    let bytes: &[u8] = comptime! {
+        let path = comptime::manifest_dir().join("tests/data/stdin.txt");
+        comptime::IncludeBytes(path)
+    };
+    assert_eq!(bytes, include_bytes!("data/stdin.txt"));
+    // The file is included by rustc, which tracks it, rather than inlined.
+    let tokens = comptime! {
+        let include = comptime::IncludeStr("tests/data/stdin.txt");
+        quote::quote!(#include).to_string()
+    };
+    assert_eq!(
+        tokens,
+        r#"include_str ! (concat ! (env ! ("CARGO_MANIFEST_DIR") , "/" , "tests/data/stdin.txt"))"#
+    );
+}
+
+#[test]
+fn test_assert() {
+    comptime::comptime_assert!(std::mem::size_of::<u64>() == 8);
+    comptime::comptime_assert!((1..=10).sum::<u32>() == 55, "bad sum");
+    comptime::comptime_assert!(std::ops::Range { start: 1, end: 3 }.len() == 2);
+    comptime::comptime_assert_eq!("abc".repeat(2), "abcabc");
+}
+
+// At the top level, where a build-time assertion usually goes.
+comptime::comptime_assert!(u32::MAX.count_ones() == 32);
+
+#[test]
+#[allow(clippy::approx_constant)] // the emitted literal is exactly `PI`
+fn test_float() {
+    assert_eq!(
+        comptime!(std::f64::consts::PI).to_bits(),
+        std::f64::consts::PI.to_bits()
+    );
+    assert_eq!(comptime!(1.0f32 / 3.0).to_bits(), (1.0f32 / 3.0).to_bits());
+    assert_eq!(comptime!(1.0 / 0.0), f64::INFINITY);
+    assert_eq!(comptime!(-1.0 / 0.0), f64::NEG_INFINITY);
+    assert!(comptime!(f64::NAN).is_nan());
+}
+
+#[test]
+fn test_suffix() {
+    assert_eq!(std::mem::size_of_val(&comptime!(5u8)), 1);
+    let wide: u64 = comptime!(@suffix(false) { 5u8 });
+    let floats: [f64; 2] = comptime!(@suffix(false) {
+        let floats = [1.0f32, 0.5];
+        quote::quote!([#(#floats),*])
+    });
+    assert_eq!((wide, floats), (5, [1.0, 0.5]));
+    assert_eq!(comptime!(@suffix(true) { 5u8 }), 5u8);
+}
+
+#[test]
+fn test_target_layout() {
+    assert_eq!(
+        comptime!(@target_layout { comptime::target::POINTER_WIDTH / 8 }),
+        std::mem::size_of::<usize>() as u32
+    );
+}
+
+comptime::comptime_static!(REVERSED: [u8; 256] = {
+    let bytes = (0..=255u8).map(u8::reverse_bits);
+    quote::quote!([#(#bytes),*])
+});
+
+comptime::comptime_static!(NEAR_MAX: u32 = { quote::quote!(u32::MAX - 1) });
+
+#[test]
+fn test_static() {
+    assert_eq!(REVERSED[1], 0b1000_0000);
+    assert_eq!(REVERSED[0b1010_0000], 0b0000_0101);
+    assert_eq!(NEAR_MAX, 4294967294);
+}
+
+#[test]
+fn test_parse_file() {
+    let deps_fns: &[&str] = &comptime! {
+        let file = comptime::parse_file("src/deps.rs");
+        let names = file.items.iter().filter_map(|item| match item {
+            syn::Item::Fn(f) => Some(f.sig.ident.to_string()),
+            _ => None,
+        });
+        quote::quote!([#(#names),*])
+    };
+    assert!(deps_fns.contains(&"build_deps"));
+}
+
+comptime::comptime_items! {
+    let name = quote::format_ident!("generated_{}", 7u8);
+    quote::quote!(fn #name() -> u8 { 7 })
+}
+
+comptime::comptime_item! {
+    quote::quote!(const GENERATED_ITEM: u8 = 8;)
+}
+
+comptime::comptime_items! {
+    if cfg!(feature = "never-enabled") {
+        quote::quote!(compile_error!("feature is off");)
+    } else {
+        comptime::nothing()
+    }
+}
+
+comptime::comptime_items! {
+    if cfg!(feature = "never-enabled") {
+        println!("fn unreachable() {{}}");
+    }
+}
+
+mod codegen {
+    comptime::comptime_items! {
+        let ops = [("double", 2u32), ("triple", 3)].map(|(name, factor)| {
+            let name = quote::format_ident!("{}", name);
+            quote::quote!(pub fn #name(n: u32) -> u32 { scale(n, #factor) })
+        });
+        quote::quote! {
+            use std::ops::Mul;
+            fn scale(n: u32, factor: u32) -> u32 {
+                n.mul(factor)
+            }
+            #(#ops)*
+        }
+    }
+}
+
+#[test]
+fn test_items() {
+    assert_eq!((codegen::double(4), codegen::triple(4)), (8, 12));
+    assert_eq!(generated_7(), 7);
+    assert_eq!(GENERATED_ITEM, 8);
+    #[allow(clippy::let_unit_value)]
+    let unit = comptime!(());
+    assert_eq!(unit, ());
+}
+
+#[test]
+fn test_environment() {
+    let cwd = comptime!(std::env::current_dir().unwrap().display().to_string());
+    let version = comptime!(std::env::var("CARGO_PKG_VERSION").unwrap());
+    assert_eq!(cwd, env!("CARGO_MANIFEST_DIR"));
+    assert_eq!(version, env!("CARGO_PKG_VERSION"));
+}
+
+#[comptime::comptime_fn(try)]
+fn stdin_lines() -> usize {
+    std::fs::read_to_string("tests/data/stdin.txt")?
+        .lines()
+        .count()
+}
+
+#[test]
+fn test_try() {
+    const STDIN_LEN: usize = comptime!(@try {
+        let stdin = std::fs::read_to_string("tests/data/stdin.txt")?;
+        stdin.len()
+    });
+    assert_eq!(STDIN_LEN, include_str!("data/stdin.txt").len());
+    assert_eq!(
+        stdin_lines(),
+        include_str!("data/stdin.txt").lines().count()
+    );
+    let parsed: u8 = comptime!(@try @cast(u8) { "42".parse::<u8>()? });
+    assert_eq!(parsed, 42);
+    // `return` returns a `Result` from the closure that the program is the body of.
+    let early: u8 = comptime!(@try {
+        if "0".parse::<u8>()? == 0 {
+            return Ok(7u8);
+        }
+        8u8
+    });
+    assert_eq!(early, 7);
+}
+
+#[comptime::comptime_fn(timeout_secs = 60)]
+fn slow() -> u32 {
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    1u32
+}
+
+#[test]
+fn test_timeout() {
+    assert_eq!(slow(), 1);
+    assert_eq!(
+        comptime!(@timeout(60) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            2
+        }),
+        2
+    );
+}
+
+#[comptime::comptime_fn(module = "generated::shapes")]
+pub fn shapes() {
+    let sides = [3u8, 4, 5];
+    quote::quote! {
+        pub const SIDES: [u8; 3] = [#(#sides),*];
+        pub struct Polygon(pub u8);
+        pub fn polygons() -> Vec<Polygon> {
+            SIDES.iter().map(|&sides| Polygon(sides)).collect()
+        }
+    }
+}
+
+#[test]
+fn test_module() {
+    use shapes::{polygons, Polygon, SIDES};
+    assert_eq!(SIDES, [3, 4, 5]);
+    let Polygon(sides) = &polygons()[1];
+    assert_eq!(*sides, 4);
+}
+
+#[comptime::comptime_fn(env_key(COMPTIME_TEST_BUILD_ENV))]
+fn build_env() -> &'static str {
+    match std::env::var("COMPTIME_TEST_BUILD_ENV").as_deref() {
+        Ok("prod") => "prod",
+        _ => "dev",
+    }
+}
+
+#[test]
+fn test_env_key() {
+    let expected = match option_env!("COMPTIME_TEST_BUILD_ENV") {
+        Some("prod") => "prod",
+        _ => "dev",
+    };
+    assert_eq!(build_env(), expected);
+    assert_eq!(
+        comptime!(@env_key(COMPTIME_TEST_BUILD_ENV) {
+            std::env::var("COMPTIME_TEST_BUILD_ENV").is_ok()
+        }),
+        option_env!("COMPTIME_TEST_BUILD_ENV").is_some()
+    );
+}
+
+#[test]
+fn test_edition() {
+    // Let chains are only available in the 2024 edition.
+    let first_even = comptime!(@edition(2024) {
+        let numbers = [3, 5, 8];
+        if let Some(n) = numbers.iter().find(|n| *n % 2 == 0) && *n > 4 {
+            *n
+        } else {
+            0
+        }
+    });
+    assert_eq!(first_even, 8);
+    assert_eq!(first_odd(), 7);
+}
+
+// A `comptime_fn` must parse in the crate's edition, so this relies on boxed slices iterating by
+// value in the 2024 edition rather than on new syntax.
+#[comptime::comptime_fn(edition = "2024")]
+fn first_odd() -> i32 {
+    let numbers: Box<[i32]> = Box::new([4, 7, 5]);
+    let odd: Vec<i32> = numbers.into_iter().filter(|n| n % 2 == 1).collect();
+    odd[0]
+}
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+const POINTS: [Point; 3] = comptime::comptime_table!(Point; {
+    struct Point { x: i32, y: i32 }
+    comptime::emit_struct!(Point { x, y });
+    (1..=3).map(|x| Point { x, y: x * x }).collect::<Vec<_>>()
+});
+
+const KEY: [u8; 32] = comptime::comptime_table!(u8; {
+    (0..32u32).map(|i| (i * 37 % 256) as u8).collect::<Vec<_>>()
+});
+
+#[test]
+fn test_table() {
+    assert_eq!(POINTS[2], Point { x: 3, y: 9 });
+    let empty = comptime::comptime_table!(Point; { Vec::<i32>::new() });
+    assert!(empty.is_empty());
+    let large = comptime::comptime_table!(u32; { 0..20_000u32 });
+    assert_eq!(large.len(), 20_000);
+    assert_eq!(large[19_999], 19_999);
+    assert_eq!((KEY[1], KEY[31]), (37, (31 * 37 % 256) as u8));
+    let bytes = comptime::comptime_table!(u8; { vec![7u8; 100_000] });
+    assert!(bytes.iter().all(|&b| b == 7));
+}
+
+#[derive(Debug, PartialEq)]
+enum Class {
+    Digit,
+    Letter,
+}
+
+fn classify(c: u8) -> Option<Class> {
+    comptime::comptime_range_match!(c; {
+        let ranges = [(b'0', b'9', "Digit"), (b'A', b'Z', "Letter"), (b'a', b'z', "Letter")];
+        ranges.map(|(start, end, class)| {
+            let class = quote::format_ident!("{}", class);
+            (start as i64..=end as i64, quote::quote!(Class::#class))
+        })
+    })
+}
+
+#[test]
+fn test_range_match() {
+    assert_eq!(classify(b'7'), Some(Class::Digit));
+    assert_eq!(classify(b'q'), Some(Class::Letter));
+    assert_eq!(classify(b'Z'), Some(Class::Letter));
+    assert_eq!(classify(b'-'), None);
+    let sign =
+        |n: i64| comptime::comptime_range_match!(n; { vec![(i64::MIN..=-1, -1), (1..=9, 1)] });
+    assert_eq!((sign(-3), sign(0), sign(5)), (Some(-1), None, Some(1)));
+}
+
+struct Buf<const N: usize>([u8; N]);
+
+#[test]
+fn test_const_generic_arg() {
+    // A macro in generic argument position is parsed as a type, so the const argument is braced.
+    let buf = Buf::<{ comptime!(2 + 2usize) }>([0; 4]);
+    let typed: Buf<{ comptime!(1usize << 3) }> = Buf([0; 8]);
+    let array: [u8; comptime!(3usize)] = [1, 2, 3];
+    assert_eq!((buf.0.len(), typed.0.len(), array.len()), (4, 8, 3));
+}
+
+const LANES: usize = comptime!(2usize.pow(3));
+
+// A const parameter's default must be braced too, or a plain path like `LANES`.
+struct Chunk<const N: usize = { comptime!(2usize.pow(4)) }>([u8; N]);
+struct Lanes<const N: usize = LANES>([u32; N]);
+
+#[test]
+fn test_const_generic_default() {
+    let chunk: Chunk = Chunk([0; 16]);
+    let lanes: Lanes = Lanes([0; 8]);
+    let small: Chunk<2> = Chunk([0; 2]);
+    assert_eq!((chunk.0.len(), lanes.0.len(), small.0.len()), (16, 8, 2));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_no_std() {
+    assert_eq!(comptime!(@no_std { core::mem::size_of::<u64>() * 2 }), 16);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_no_std_alloc() {
+    let squares: [u8; 4] = comptime!(@no_std(alloc) {
+        let squares: Vec<u8> = (0..4u8).map(|i| i * i).collect();
+        squares
+    });
+    assert_eq!(squares, [0, 1, 4, 9]);
+    assert_eq!(
+        comptime!(@no_std(alloc) { format!("{}-{}", 1, "a") }),
+        "1-a"
+    );
+}
+
+#[test]
+fn test_result_type() {
+    let four: u32 = comptime!(as u32 { 2 + 2 });
+    assert_eq!(four, 4);
+    assert_eq!(comptime::comptime_as!(&str, { "ab".repeat(2) }), "abab");
+    assert_eq!(comptime!(as f64 { -1.0 / 0.0 }), f64::NEG_INFINITY);
+    assert_eq!(comptime!(as i8 { -3 }), -3i8);
+}
+
+#[test]
+fn test_unsafe() {
+    let one: f32 = comptime!(quote::quote!(unsafe {
+        std::mem::transmute(0x3f80_0000u32)
+    }));
+    assert_eq!(one, 1.0);
+    let half: f32 = comptime::comptime_unsafe!(quote::quote!(std::mem::transmute(0x3f00_0000u32)));
+    assert_eq!(half, 0.5);
+}
+
+#[test]
+fn test_block_result() {
+    let two: i32 = comptime::comptime!(quote::quote!({
+        let x = 1;
+        x + 1
+    }));
+    assert_eq!(two, 2);
+    // A block result in item position is a const's initializer.
+    const LINES: &str = comptime::comptime!(quote::quote!({
+        const FIRST: &str = "a\nb";
+        FIRST
+    }));
+    assert_eq!(LINES, "a\nb");
+    let block = comptime::comptime!(quote::quote!({
+        let v = vec![1, 2];
+        v
+    })
+    .to_string());
+    assert_eq!(block, "{ let v = vec ! [1 , 2] ; v }");
+}
+
+#[test]
+fn test_cast() {
+    const LEN: usize = comptime::comptime!(@cast(usize) {
+        let len: i64 = (1..=4).product();
+        len
+    });
+    let zeros = [0u8; LEN];
+    assert_eq!(zeros.len(), 24);
+    let ratio: f32 = comptime::comptime!(@cast(f32) { 3i64 });
+    assert_eq!(ratio, 3.0);
+}
+
+#[test]
+fn test_cstr() {
+    extern "C" {
+        fn strlen(s: *const std::ffi::c_char) -> usize;
+    }
+    const GREETING: &std::ffi::CStr = comptime::comptime_cstr!(["hello", "world"].join(", "));
+    assert_eq!(GREETING.to_str(), Ok("hello, world"));
+    assert_eq!(unsafe { strlen(GREETING.as_ptr()) }, 12);
+}
+
+#[test]
+fn test_regex() {
+    const DATE: &str = comptime::comptime_regex!(r"^\d{4}-\d{2}-\d{2}$");
+    let date = regex::Regex::new(DATE).unwrap();
+    assert!(date.is_match("2020-01-02"));
+    assert!(!date.is_match("2020-1-2"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cmd() {
+    assert_eq!(comptime::comptime_cmd!("echo", ["  a b  "]), "a b");
+    // Run in the crate's root.
+    assert_eq!(comptime::comptime_cmd!("ls", ["Cargo.toml"]), "Cargo.toml");
+    let rustc = comptime::comptime_cmd!("rustc", ["--version"],);
+    assert!(rustc.starts_with("rustc "));
+    // A failing command's status and stderr are what fails the build.
+    let failed = comptime! {
+        comptime::cmd("sh", &["-c", "echo out; echo oops >&2; exit 3"]).to_string()
+    };
+    assert_eq!(
+        failed,
+        r#"(false , "`sh -c echo out; echo oops >&2; exit 3` failed with exit status: 3:\noops")"#
+    );
+}
+
+comptime::comptime_enum!(#[derive(Clone, Copy, Debug, PartialEq)] pub Country {
+    "us,fr,jp".split(',').map(str::to_uppercase).collect::<Vec<_>>()
+});
+
+#[test]
+fn test_enum() {
+    assert_eq!(Country::FR.as_str(), "FR");
+    assert_eq!("JP".parse(), Ok(Country::JP));
+    assert_eq!(
+        "DE".parse::<Country>(),
+        Err("unknown `Country` variant `DE`".to_string())
+    );
+    let all = [Country::US, Country::FR, Country::JP].map(|c| c.as_str());
+    assert_eq!(all, ["US", "FR", "JP"]);
+}
+
+fn http_status(reason: &str) -> Option<&'static u16> {
+    // A `phf::Map`, since this crate depends on `phf` and `phf_codegen`.
+    comptime::comptime_phf!(reason => u16; {
+        let reasons = [("OK", 200), ("Not Found", 404), ("I'm a teapot", 418)];
+        reasons.map(|(reason, status)| (reason.to_string(), status as u16))
+    })
+}
+
+#[test]
+fn test_phf() {
+    assert_eq!(http_status("Not Found"), Some(&404));
+    assert_eq!(http_status("I'm a teapot"), Some(&418));
+    assert_eq!(http_status("Gone"), None);
+    // A duplicate key is emitted as an error, whether or not the map is a `phf::Map`.
+    let duplicated = comptime! {
+        comptime::map(vec![("OK", 200u16), ("Not Found", 404), ("OK", 204)], None).to_string()
+    };
+    assert_eq!(
+        duplicated,
+        r#"compile_error ! ("comptime_phf! key `OK` is duplicated")"#
+    );
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json() {
+    let scores: [(&str, [(u8, f32); 2]); 2] = comptime! {
+        let mut scores = std::collections::BTreeMap::new();
+        scores.insert("b", vec![(1u8, 0.5f32), (2, -1.0)]);
+        scores.insert("a", vec![(3, 2.25), (4, 0.0)]);
+        comptime::json::emit(scores)
+    };
+    assert_eq!(scores[0], ("a", [(3, 2.25), (4, 0.0)]));
+    assert_eq!(scores[1].1[1], (2, -1.0));
+    let nested: ((char, bool),) = comptime!(comptime::json::emit((('\'', true),)));
+    assert_eq!(nested, (('\'', true),));
+    let imported: [u8; 2] = comptime! {
+        use comptime::{json};
+        json::emit([1u8, 2])
+    };
+    assert_eq!(imported, [1, 2]);
+}
+
+#[test]
+fn test_hash_map() {
+    let mut primes: std::collections::HashMap<&'static str, u32> = comptime! {
+        let names = ["two", "three", "five", "seven"];
+        comptime::hash_map(names.iter().zip([2u32, 3, 5, 7]).map(|(&name, n)| (name, n)))
+    };
+    assert_eq!(primes["five"], 5);
+    primes.insert("eleven", 11);
+    assert_eq!(primes.len(), 5);
+    let empty: std::collections::HashMap<u8, u8> =
+        comptime!(comptime::hash_map(Vec::<(u8, u8)>::new()));
+    assert!(empty.is_empty());
+}
+
+// A `once_cell::sync::Lazy`, since this crate depends on `once_cell`.
+static SCORES: once_cell::sync::Lazy<std::collections::HashMap<&str, u32>> =
+    comptime_lazy!(std::collections::HashMap<&'static str, u32>, {
+        comptime::hash_map(
+            ["alice", "bob"]
+                .iter()
+                .map(|&name| (name, name.len() as u32)),
+        )
+    });
+
+#[test]
+fn test_lazy() {
+    assert_eq!(SCORES["alice"], 5);
+    assert_eq!(SCORES.get("carol"), None);
+}
+
+macro_rules! squares {
+    ($name:ident = $n:literal) => {
+        const $name: [u32; $n] = comptime!(@span($name) {
+            let squares = (0..$n as u32).map(|i| i * i);
+            quote::quote!([#(#squares),*])
+        });
+    };
+}
+squares!(SQUARES = 4);
+
+#[test]
+fn test_span() {
+    assert_eq!(SQUARES, [0, 1, 4, 9]);
+}
+
+static NESTED: &[(&str, &[&[u8]])] = comptime!(quote::quote!(&[
+    ("ab", &[b"a" as &[u8], &[b'b']]),
+    ("none", &[])
+]));
+
+#[comptime::comptime_fn]
+fn greetings() -> &'static [&'static str] {
+    let greetings = ["hi", "hello"];
+    quote::quote!(&[#(#greetings),*])
+}
+
+#[test]
+fn test_references() {
+    let uppercase: &'static [&'static str] = comptime!({
+        let names = ["alpha", "beta"].map(|name| name.to_uppercase());
+        quote::quote!(&[#(#names),*])
+    });
+    assert_eq!(uppercase, ["ALPHA", "BETA"]);
+    assert_eq!(NESTED[0], ("ab", &[&b"a"[..], &b"b"[..]][..]));
+    assert!(NESTED[1].1.is_empty());
+    assert_eq!(greetings(), ["hi", "hello"]);
+    let borrowed: &&str = comptime!(quote::quote!(&"borrowed"));
+    assert_eq!(*borrowed, "borrowed");
+}
+
+#[test]
+fn test_map() {
+    const FACTORIALS: [u64; 8] = comptime_map!(n: u64 in 0..8 { (1..=n).product::<u64>() });
+    assert_eq!(FACTORIALS, [1, 1, 2, 6, 24, 120, 720, 5040]);
+    let lengths: [usize; 3] = comptime_map!(word: String in ["a", "bb", ""] { word.len() });
+    assert_eq!(lengths, [1, 2, 0]);
+    let negated = comptime_map!(x: i8 in -1..=1 @suffix(false) { -x });
+    assert_eq!(negated, [1i8, 0, -1]);
+}
+
+comptime_const_fn!(
+    fn square(i: usize) -> u64 {
+        (0..16u64).map(|i| i * i).collect::<Vec<_>>()
+    }
+);
+comptime_const_fn!(
+    pub(crate) fn bit_count(byte: u8) -> Option<u8> {
+        (0..16u8)
+            .map(|byte| byte.count_ones() as u8)
+            .collect::<Vec<_>>()
+    }
+);
+
+#[test]
+fn test_const_fn() {
+    const NINE: u64 = square(3);
+    const SEVEN_BITS: Option<u8> = bit_count(7);
+    assert_eq!(NINE, 9);
+    assert_eq!(square(15), 225);
+    assert_eq!(SEVEN_BITS, Some(3));
+    assert_eq!(bit_count(16), None);
+}
+
+#[test]
+fn test_unique_id() {
+    const IDS: [u32; 3] = [
+        comptime_unique_id!(),
+        comptime_unique_id!(),
+        comptime_unique_id!(),
+    ];
+    assert_ne!(IDS[0], IDS[1]);
+    assert_ne!(IDS[1], IDS[2]);
+    assert_ne!(IDS[0], IDS[2]);
+}
+
+#[test]
+fn test_timestamp() {
+    const BUILT_AT: &str = comptime::comptime_timestamp!();
+    let (date, time) = BUILT_AT.split_once('T').unwrap();
+    assert_eq!(date.len(), "2019-08-30".len());
+    assert_eq!(time.len(), "12:00:00Z".len());
+    assert!(chrono::DateTime::parse_from_rfc3339(BUILT_AT).is_ok());
+}
+
+struct Server {
+    host: &'static str,
+    port: u16,
+}
+
+struct Config {
+    name: &'static str,
+    verbose: bool,
+    server: Server,
+}
+
+// The program's types mirror the crate's, but own their strings, which are emitted as literals.
+const CONFIG: Config = comptime::comptime_toml!(Config, "tests/data/config.toml", {
+    #[derive(serde::Deserialize)]
+    struct Server {
+        host: String,
+        port: u16,
+    }
+    #[derive(serde::Deserialize)]
+    struct Config {
+        name: String,
+        verbose: bool,
+        server: Server,
+    }
+    comptime::emit_struct!(Server { host, port });
+    comptime::emit_struct!(Config {
+        name,
+        verbose,
+        server
+    });
+});
+
+#[test]
+fn test_config() {
+    assert_eq!((CONFIG.name, CONFIG.verbose), ("app", true));
+    assert_eq!(
+        (CONFIG.server.host, CONFIG.server.port),
+        ("localhost", 8080)
+    );
+
+    let server: Server = comptime::comptime_yaml!(Server, "tests/data/server.yaml", {
+        #[derive(serde::Deserialize)]
+        struct Server {
+            host: String,
+            port: u16,
+        }
+        comptime::emit_struct!(Server { host, port });
+    });
+    assert_eq!((server.host, server.port), ("example.com", 443));
+}
+
+comptime::comptime_bundle! {
+    /// The sum of the squares below `LIMIT`, which is computed after `LIMIT`.
+    pub const SQUARE_SUM: u32 = (0..LIMIT).map(|i| i * i).sum::<u32>();
+    const LIMIT: u32 = {
+        let exponent = 3;
+        2u32.pow(exponent)
+    };
+    const LABEL: &str = format!("{}:{}", LIMIT, SQUARE_SUM);
+    /// `LABEL` is a `String` here, which is still emitted, so it's cloned rather than moved.
+    const SHOUTED_LABEL: &str = LABEL.clone() + "!";
+}
+
+#[test]
+fn test_bundle() {
+    assert_eq!((LIMIT, SQUARE_SUM, LABEL), (8, 140, "8:140"));
+    assert_eq!(SHOUTED_LABEL, "8:140!");
+}
+
+/// `missing_docs` fails the build unless the comptime docs are attached.
+#[deny(missing_docs)]
+pub mod documented {
+    #[comptime::comptime_doc {
+        let bits = u32::BITS;
+        format!("A {}-bit answer, computed at build time.", bits)
+    }]
+    pub struct Answer;
+
+    #[doc = comptime::comptime!(concat!("Summed", " at build time.").to_string())]
+    pub const SUM: u32 = 6 * 7;
+}
+
+#[test]
+fn test_doc() {
+    let _ = documented::Answer;
+    assert_eq!(documented::SUM, 42);
+}
+
+#[test]
+fn test_deep_recursion() {
+    // About 20 MB of stack, which overflows a main thread's 8 MB but not `COMPTIME_STACK_MB`.
+    let depth: u32 = comptime! {
+        fn depth(n: u32) -> u32 {
+            let frame = std::hint::black_box([0u8; 1024]);
+            match n {
+                0 => frame[0] as u32,
+                n => 1 + depth(n - 1),
+            }
+        }
+        depth(20_000)
+    };
+    assert_eq!(depth, 20_000);
+}
@@ -0,0 +1,694 @@
+#![cfg(test)]
+
+//! Crates compiled by rustc against the comptime built for these tests, as cargo would compile
+//! them, for what can't be checked from inside this crate's own build: invocations that must fail
+//! to compile, and the environment variables that comptime reads while expanding.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Compiles `source` as the library `name`, with the crates named by `externs` from the deps dir
+/// as dependencies and with `envs` set, and returns rustc's stderr if it fails. An extern of the
+/// form `alias=crate` links the crate under another name, as a renamed dependency is.
+fn compile(
+    name: &str,
+    externs: &[&str],
+    envs: &[(&str, &str)],
+    source: &str,
+) -> Result<(), String> {
+    build(name, "lib", externs, envs, source).map(drop)
+}
+
+/// Compiles `source` as the binary `name`, like `compile`, runs it, and returns its stdout.
+fn run(name: &str, externs: &[&str], source: &str) -> String {
+    let (out_dir, _) =
+        build(name, "bin", externs, &[], source).unwrap_or_else(|stderr| panic!("{}", stderr));
+    let bin = out_dir.join(format!("{}{}", name, std::env::consts::EXE_SUFFIX));
+    let output = Command::new(&bin).output().unwrap();
+    assert!(output.status.success(), "`{}` failed", bin.display());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Compiles `source` as the `crate_type` crate `name`, and returns its out dir, where the crate's
+/// dep-info is written too, as `<name>.d`, and rustc's stderr.
+fn build(
+    name: &str,
+    crate_type: &str,
+    externs: &[&str],
+    envs: &[(&str, &str)],
+    source: &str,
+) -> Result<(PathBuf, String), String> {
+    let deps_dir = std::env::current_exe().unwrap();
+    let deps_dir = deps_dir.parent().unwrap();
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("rustc")
+        .join(name);
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let lib_rs = out_dir.join("lib.rs");
+    std::fs::write(&lib_rs, source).unwrap();
+
+    let comptime = newest(
+        deps_dir,
+        &format!("{}comptime-", std::env::consts::DLL_PREFIX),
+        std::env::consts::DLL_SUFFIX,
+    );
+    let mut rustc = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into()));
+    rustc
+        .args(["--edition", "2021", "--crate-type", crate_type])
+        .arg("--emit=dep-info,link")
+        .args(["--crate-name", name])
+        .arg("--out-dir")
+        .arg(&out_dir)
+        .arg("-L")
+        .arg(format!("dependency={}", deps_dir.display()))
+        .arg("--extern")
+        .arg(format!("comptime={}", comptime.display()))
+        .envs(envs.iter().copied());
+    for name in externs {
+        let (name, crate_name) = name.split_once('=').unwrap_or((name, name));
+        let rlib = newest(deps_dir, &format!("lib{}-", crate_name), ".rlib");
+        rustc
+            .arg("--extern")
+            .arg(format!("{}={}", name, rlib.display()));
+    }
+    let output = rustc.arg(&lib_rs).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    match output.status.success() {
+        true => Ok((out_dir, stderr)),
+        false => Err(stderr),
+    }
+}
+
+/// Compiles `source` like `compile`, and returns rustc's stderr. Panics if it compiles.
+fn compile_fail(name: &str, externs: &[&str], source: &str) -> String {
+    match compile(name, externs, &[], source) {
+        Ok(()) => panic!("`{}` compiled", name),
+        Err(stderr) => stderr,
+    }
+}
+
+/// Returns the newest file in `dir` named `<prefix><hash><suffix>`. Cargo keeps the artifacts of
+/// other builds of the same crate (e.g., with other features) too, but it just built or checked
+/// the one that these tests were built with.
+fn newest(dir: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.starts_with(prefix) && name.ends_with(suffix)
+        })
+        .max_by_key(|path| path.metadata().and_then(|m| m.modified()).ok())
+        .unwrap_or_else(|| panic!("no `{}*{}` in `{}`", prefix, suffix, dir.display()))
+}
+
+#[test]
+fn test_assert() {
+    let stderr = compile_fail(
+        "assert",
+        &[],
+        r#"
+        comptime::comptime_assert!(std::mem::size_of::<u64>() == 8);
+        comptime::comptime_assert_eq!("abc".repeat(2), "abcabcabc", "repeat is broken");
+        "#,
+    );
+    assert!(stderr.contains("error: repeat is broken"), "{}", stderr);
+    assert!(stderr.contains(r#"left: "abcabc""#), "{}", stderr);
+    assert!(
+        stderr.contains("aborting due to 1 previous error"),
+        "{}",
+        stderr
+    );
+
+    let stderr = compile_fail(
+        "assert_default_message",
+        &[],
+        "comptime::comptime_assert!((1..=10).sum::<u32>() == 56);",
+    );
+    assert!(
+        stderr.contains("error: comptime assertion failed: (1 ..= 10)"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_missing_crate() {
+    let stderr = compile_fail(
+        "missing_crate",
+        &[],
+        r#"
+        pub const X: u32 = comptime::comptime! {
+            use nonexistent_crate::Thing;
+            1u32
+        };
+        "#,
+    );
+    assert!(
+        stderr.contains(
+            "could not find crate `nonexistent_crate`. Crates used by comptime must be \
+             dependencies of the current crate"
+        ),
+        "{}",
+        stderr
+    );
+    // rustc's own error isn't repeated.
+    assert!(!stderr.contains("E0432"), "{}", stderr);
+}
+
+#[test]
+fn test_driver_template() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("rustc");
+    std::fs::create_dir_all(&dir).unwrap();
+    let template = dir.join("template.rs");
+    std::fs::write(
+        &template,
+        r#"#![allow(unused_variables)]
+        fn main() {
+            let unused = 0;
+            let comptime_output = format!("<{}>", { {{comptime_program}} });
+            {{comptime_print}}
+        }"#,
+    )
+    .unwrap();
+    let template = template.to_str().unwrap();
+    let env = [("COMPTIME_DRIVER_TEMPLATE", template)];
+    let source = r#"const _: () = assert!(comptime::comptime!(6 * 7).len() == "<42>".len());"#;
+    compile("driver_template", &[], &env, source).unwrap();
+
+    let broken = dir.join("broken_template.rs");
+    std::fs::write(&broken, "fn main() { {{comptime_program}} }").unwrap();
+    let env = [("COMPTIME_DRIVER_TEMPLATE", broken.to_str().unwrap())];
+    let stderr = compile("broken_driver_template", &[], &env, source).unwrap_err();
+    assert!(
+        stderr.contains(
+            "invalid comptime driver template: missing the `{{comptime_print}}` placeholder"
+        ),
+        "{}",
+        stderr
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_linker() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("rustc")
+        .join("linker_wrapper");
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("invocations");
+    std::fs::remove_file(&log).ok();
+    let wrapper = dir.join("cc-wrapper");
+    std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> '{}'\nexec cc \"$@\"\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    // A driver that's already linked, from an earlier run, isn't linked again.
+    std::fs::remove_dir_all(Path::new(env!("CARGO_TARGET_TMPDIR")).join("rustc/linker")).ok();
+    let env = [("COMPTIME_LINKER", wrapper.to_str().unwrap())];
+    let source = "const _: () = assert!(comptime::comptime!(6 * 7) == 42);";
+    compile("linker", &[], &env, source).unwrap();
+    let invocations = std::fs::read_to_string(&log).unwrap_or_default();
+    assert!(invocations.contains("comptime_bin_"), "{:?}", invocations);
+
+    let env = [("COMPTIME_LINKER", "/nonexistent/linker")];
+    let stderr = compile("missing_linker", &[], &env, source).unwrap_err();
+    assert!(stderr.contains("/nonexistent/linker"), "{}", stderr);
+}
+
+#[test]
+fn test_crate_metavariable() {
+    let stderr = compile_fail(
+        "crate_metavariable",
+        &[],
+        r#"
+        pub fn answer() -> u32 { 42 }
+        macro_rules! answer {
+            () => { comptime::comptime!($crate::answer()) };
+        }
+        pub const ANSWER: u32 = answer!();
+        "#,
+    );
+    assert!(
+        stderr.contains("unsupported macro metavariable `$crate` in comptime program"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_result_type() {
+    let stderr = compile_fail(
+        "result_type",
+        &[],
+        r#"
+        pub const FOUR: u32 = comptime::comptime!(as u32 { 2 + 2 });
+        pub const NAME: &str = comptime::comptime!(as &str { 4 });
+        "#,
+    );
+    assert!(
+        stderr.contains(
+            "comptime expr result `4i32` is not a string literal, as required by its result type"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("aborting due to 1 previous error"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_range_match_overlap() {
+    let stderr = compile_fail(
+        "range_match_overlap",
+        &[],
+        r#"
+        pub fn is_digit(c: u8) -> bool {
+            comptime::comptime_range_match!(c; {
+                vec![(0x30..=0x39, true), (0x39..=0x40, false)]
+            })
+            .unwrap_or(false)
+        }
+        "#,
+    );
+    assert!(
+        stderr.contains("comptime_range_match! ranges `48..=57` and `57..=64` overlap"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_regex() {
+    let source = r#"
+        pub fn word() -> regex::Regex {
+            regex::Regex::new(comptime::comptime_regex!(r"\w+")).unwrap()
+        }
+        "#;
+    compile("regex", &["regex"], &[], source).unwrap();
+
+    let stderr = compile_fail(
+        "invalid_regex",
+        &["regex"],
+        r#"pub const UNCLOSED: &str = comptime::comptime_regex!(r"(\w+");"#,
+    );
+    assert!(stderr.contains("error: invalid regex: "), "{}", stderr);
+    assert!(stderr.contains("unclosed group"), "{}", stderr);
+
+    let stderr = compile_fail(
+        "regex_without_regex",
+        &[],
+        r#"pub const WORD: &str = comptime::comptime_regex!(r"\w+");"#,
+    );
+    assert!(
+        stderr.contains("comptime_regex! requires the crate to depend on `regex`"),
+        "{}",
+        stderr
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cmd() {
+    let stderr = compile_fail(
+        "cmd",
+        &[],
+        r#"
+        pub const GREETING: &str = comptime::comptime_cmd!("echo", ["hi"]);
+        pub const MISSING: &str = comptime::comptime_cmd!("sh", ["-c", "echo oops >&2; exit 3"]);
+        "#,
+    );
+    assert!(
+        stderr.contains("error: `sh -c echo oops >&2; exit 3` failed with exit status: 3:"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("oops"), "{}", stderr);
+    assert!(
+        stderr.contains("aborting due to 1 previous error"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_phf_match() {
+    // Without `phf`, the map is a `match`.
+    let stdout = run(
+        "phf_match",
+        &[],
+        r#"
+        fn http_status(reason: &str) -> Option<&'static u16> {
+            comptime::comptime_phf!(reason => u16; {
+                vec![("OK", 200u16), ("Not Found", 404), ("I'm a teapot", 418)]
+            })
+        }
+        fn main() {
+            print!("{:?}", ["Not Found", "OK", "Gone"].map(http_status));
+        }
+        "#,
+    );
+    assert_eq!(stdout, "[Some(404), Some(200), None]");
+
+    let stderr = compile_fail(
+        "phf_duplicate_key",
+        &[],
+        r#"
+        pub fn http_status(reason: &str) -> Option<&'static u16> {
+            comptime::comptime_phf!(reason => u16; {
+                vec![("OK", 200u16), ("Not Found", 404), ("OK", 204)]
+            })
+        }
+        "#,
+    );
+    assert!(
+        stderr.contains("comptime_phf! key `OK` is duplicated"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_include_tracking() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("rustc")
+        .join("included");
+    std::fs::create_dir_all(&dir).unwrap();
+    let included = dir.join("schema.sql");
+    std::fs::write(&included, "CREATE TABLE t (id INTEGER);").unwrap();
+    let source = format!(
+        r#"pub const SCHEMA: &str = comptime::comptime!(comptime::IncludeStr({:?}));"#,
+        included
+    );
+    // Cargo rebuilds the crate when a file in its dep-info changes, as it does for any other
+    // `include_str!`.
+    let (out_dir, _) = build("include_tracking", "lib", &[], &[], &source).unwrap();
+    let dep_info = std::fs::read_to_string(out_dir.join("include_tracking.d")).unwrap();
+    assert!(
+        dep_info
+            .lines()
+            .any(|line| line == format!("{}:", included.display())),
+        "{}",
+        dep_info
+    );
+}
+
+#[test]
+fn test_suffix_out_of_range() {
+    let stderr = compile_fail(
+        "suffix_out_of_range",
+        &[],
+        r#"
+pub const WIDE: u64 = comptime::comptime!(@suffix(false) { 100u8 });
+pub const LIMIT: u8 = comptime::comptime!(@suffix(false) { 100 * 3 });
+        "#,
+    );
+    assert!(
+        stderr.contains("error: literal out of range for `u8`"),
+        "{}",
+        stderr
+    );
+    // The error points at the invocation.
+    assert!(stderr.contains("lib.rs:3:23"), "{}", stderr);
+    assert!(
+        stderr.contains("aborting due to 1 previous error"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_lazy_lock() {
+    // Without `once_cell`, it's a `std::sync::LazyLock`.
+    let stdout = run(
+        "lazy_lock",
+        &[],
+        r#"
+        static LENGTHS: std::sync::LazyLock<Vec<usize>> = comptime::comptime_lazy!(Vec<usize>, {
+            let lengths = ["alice", "bob"].map(str::len);
+            quote::quote!(vec![#(#lengths),*])
+        });
+        fn main() {
+            print!("{:?}", *LENGTHS);
+        }
+        "#,
+    );
+    assert_eq!(stdout, "[5, 3]");
+}
+
+#[test]
+fn test_extern_strategies() {
+    let deps_dir = std::env::current_exe().unwrap();
+    let deps_dir = deps_dir.parent().unwrap();
+    let rlib = newest(deps_dir, "libregex-", ".rlib");
+    let dep_info = rlib.with_file_name(format!(
+        "{}.d",
+        rlib.file_stem()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .strip_prefix("lib")
+            .unwrap()
+    ));
+    let source = r#"
+        pub const MATCHED: bool = comptime::comptime!(regex::Regex::new("a+b").unwrap().is_match("caab"));
+        const _: () = assert!(MATCHED);
+        "#;
+    let manifest_dir = ("CARGO_MANIFEST_DIR", env!("CARGO_MANIFEST_DIR"));
+    for strategy in ["args", "scan", "depinfo", "auto"] {
+        // `args` only finds the crate's `--extern`s, and the others also find the rlibs in its out
+        // dir, which cargo shares with its dependencies, along with their dep-info.
+        let name = format!("extern_strategy_{}", strategy);
+        let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join("rustc")
+            .join(&name);
+        std::fs::create_dir_all(&out_dir).unwrap();
+        for file in [&rlib, &dep_info] {
+            std::fs::copy(file, out_dir.join(file.file_name().unwrap())).unwrap();
+        }
+        let env = [("COMPTIME_EXTERN_STRATEGY", strategy), manifest_dir];
+        let externs: &[&str] = if strategy == "args" { &["regex"] } else { &[] };
+        if let Err(stderr) = compile(&name, externs, &env, source) {
+            panic!("`{}` failed:\n{}", strategy, stderr);
+        }
+    }
+
+    let env = [("COMPTIME_EXTERN_STRATEGY", "args"), manifest_dir];
+    let stderr = compile("extern_strategy_args_only", &[], &env, source).unwrap_err();
+    assert!(
+        stderr.contains("could not find crate `regex`"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_span() {
+    let stderr = compile_fail(
+        "span",
+        &[],
+        r#"
+macro_rules! table {
+    ($name:ident) => {
+        pub const $name: [u8; 2] = comptime::comptime!(@span($name) {
+            quote::quote!([1, 300u16])
+        });
+    };
+}
+table!(SMALL);
+"#,
+    );
+    // The error about a token in a group of the result is at the token that the result was given
+    // the span of.
+    assert!(
+        stderr.contains("error[E0308]: mismatched types"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("lib.rs:9:8"), "{}", stderr);
+}
+
+#[test]
+fn test_renamed_quote() {
+    // The parent can only name its `quote` as `q`, but the driver still links it as `quote`.
+    let stdout = run(
+        "renamed_quote",
+        &["q=quote"],
+        r#"
+        const SUM: &str = comptime::comptime!(quote::quote!(1 + 2).to_string());
+        fn main() {
+            println!("{} {}", SUM, q::quote!(3 + 4));
+        }
+        "#,
+    );
+    assert_eq!(stdout, "1 + 2 3 + 4\n");
+}
+
+#[test]
+fn test_debug_all() {
+    let (_, stderr) = build(
+        "debug_all",
+        "lib",
+        &[],
+        &[("COMPTIME_DEBUG_ALL", "1")],
+        r#"
+        pub const X: u32 = comptime::comptime! {
+            eprintln!("computing X");
+            1u32 + 2
+        };
+        "#,
+    )
+    .unwrap();
+    // The program's source is kept, and how it was compiled and run is logged, along with its
+    // stderr and a summary.
+    let kept = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("comptime: keeping `")?.strip_suffix('`'))
+        .unwrap_or_else(|| panic!("{}", stderr));
+    assert!(
+        std::fs::read_to_string(kept)
+            .unwrap()
+            .contains("computing X"),
+        "{}",
+        kept
+    );
+    assert!(stderr.contains("comptime: compiling `"), "{}", stderr);
+    assert!(stderr.contains("comptime: running `"), "{}", stderr);
+    assert!(stderr.contains("computing X"), "{}", stderr);
+    assert!(stderr.contains("comptime: 1 invocation took"), "{}", stderr);
+}
+
+#[test]
+fn test_driver_gc() {
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("rustc")
+        .join("driver_gc");
+    std::fs::remove_dir_all(&out_dir).ok();
+    let drivers = || {
+        let mut drivers: Vec<_> = std::fs::read_dir(&out_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("comptime-driver_gc-") && name.ends_with(".stamp"))
+            .collect();
+        drivers.sort();
+        drivers
+    };
+    let build = |value: u32| {
+        let source = format!("pub const X: u32 = comptime::comptime!({}u32);", value);
+        build("driver_gc", "lib", &[], &[], &source).unwrap();
+    };
+    build(1);
+    let first = drivers();
+    assert_eq!(first.len(), 1);
+    // The edited program's driver is added, and the next build, which doesn't use the first
+    // driver, removes it.
+    build(2);
+    let both = drivers();
+    assert_eq!(both.len(), 2);
+    build(2);
+    let last = drivers();
+    assert_eq!(last.len(), 1);
+    assert_ne!(last, first);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_rustc_shim() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("rustc")
+        .join("shim");
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("invocations.log");
+    std::fs::remove_file(&log).ok();
+    let shim = dir.join("rustc-shim");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    std::fs::write(
+        &shim,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> '{}'\nexec '{}' \"$@\"\n",
+            log.display(),
+            rustc
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&shim, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let shim = shim.to_str().unwrap();
+    // The program's driver is compiled again, rather than reused from an earlier run.
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("rustc")
+        .join("rustc_shim");
+    std::fs::remove_dir_all(out_dir).ok();
+    compile(
+        "rustc_shim",
+        &[],
+        &[("RUSTC", shim)],
+        "pub const WIDTH: u32 = comptime::comptime!(@target_layout { comptime::target::POINTER_WIDTH });",
+    )
+    .unwrap();
+    // Both the query of the target's cfg and the program's compilation go through the shim.
+    let log = std::fs::read_to_string(&log).unwrap();
+    assert!(
+        log.lines().any(|line| line.starts_with("--print cfg")),
+        "{}",
+        log
+    );
+    assert!(log.contains("--crate-name comptime_bin_"), "{}", log);
+}
+
+#[test]
+fn test_cast_truncation_warning() {
+    let source = "pub const B: u8 = comptime::comptime!(@cast(u8) { 300i64 });";
+    let (_, stderr) = build(
+        "cast_truncation",
+        "lib",
+        &[],
+        &[("CARGO_PRIMARY_PACKAGE", "1")],
+        source,
+    )
+    .unwrap();
+    assert!(
+        stderr.contains("lib.rs:1:19: result `(300i64) as u8` is truncated by `as u8`"),
+        "{}",
+        stderr
+    );
+    // Dependencies don't warn.
+    let (_, stderr) = build("cast_truncation_dependency", "lib", &[], &[], source).unwrap();
+    assert!(!stderr.contains("truncated"), "{}", stderr);
+}
+
+#[test]
+fn test_try_error() {
+    let stderr = compile_fail(
+        "try_error",
+        &[],
+        r#"
+        pub const N: u32 = comptime::comptime!(@try {
+            let n: u32 = "12x".parse()?;
+            n
+        });
+        "#,
+    );
+    // The error that `?` returned is reported as the compile error, rather than a panic.
+    assert!(
+        stderr.contains("error: invalid digit found in string"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}